@@ -0,0 +1,17 @@
+#version 140
+
+uniform sampler2D map;
+// Whether the map is drawn, or the solid colour.
+uniform float textured;
+uniform vec4 color;
+
+in vec2 v_tex_coords;
+out vec4 f_color;
+
+void main() {
+    if (textured > 0.5) {
+        f_color = texture(map, v_tex_coords);
+    } else {
+        f_color = color;
+    }
+}
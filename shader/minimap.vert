@@ -0,0 +1,11 @@
+#version 140
+
+// In normalized device coordinates.
+in vec2 position;
+in vec2 tex_coords;
+out vec2 v_tex_coords;
+
+void main() {
+    v_tex_coords = tex_coords;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
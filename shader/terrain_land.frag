@@ -1,9 +1,49 @@
 #version 140
 
+// Direction towards the sun, in world coordinates.
+uniform vec3 sun_direction;
+// Height of the water surface of the region.
+uniform float water_height;
+
+// The detail textures, from low to high elevation.
+uniform sampler2D detail_texture0;
+uniform sampler2D detail_texture1;
+uniform sampler2D detail_texture2;
+uniform sampler2D detail_texture3;
+// 1.0 for each detail texture which is loaded, 0.0 otherwise.
+uniform vec4 detail_loaded;
+// Colours drawn instead of the detail textures which are not loaded.
+uniform vec3 detail_color0;
+uniform vec3 detail_color1;
+uniform vec3 detail_color2;
+uniform vec3 detail_color3;
+
+in vec3 v_position;
 in vec3 v_normal;
-in float v_color;
+in vec4 v_blend;
 out vec4 f_color;
 
+const float ambient = 0.3;
+// Terrain below the water surface fades into this colour with depth.
+const vec3 deep_water_color = vec3(0.02, 0.1, 0.16);
+const float water_extinction = 0.25;
+// Repetitions of the detail textures per meter. Region offsets are
+// multiples of 256 meters, so the textures continue across regions.
+const float detail_scale = 1.0 / 16.0;
+
 void main() {
-    f_color = vec4(v_color, 0.5, v_color, 1.0);
+    vec2 uv = v_position.xy * detail_scale;
+    vec3 layer0 = mix(detail_color0, texture(detail_texture0, uv).rgb, detail_loaded.x);
+    vec3 layer1 = mix(detail_color1, texture(detail_texture1, uv).rgb, detail_loaded.y);
+    vec3 layer2 = mix(detail_color2, texture(detail_texture2, uv).rgb, detail_loaded.z);
+    vec3 layer3 = mix(detail_color3, texture(detail_texture3, uv).rgb, detail_loaded.w);
+    vec3 albedo = layer0 * v_blend.x + layer1 * v_blend.y + layer2 * v_blend.z + layer3 * v_blend.w;
+
+    float diffuse = max(dot(normalize(v_normal), sun_direction), 0.0);
+    vec3 color = albedo * (ambient + (1.0 - ambient) * diffuse);
+
+    // Shallow terrain stays visible through the water, so shorelines show.
+    float depth = max(water_height - v_position.z, 0.0);
+    color = mix(color, deep_water_color, 1.0 - exp(-depth * water_extinction));
+    f_color = vec4(color, 1.0);
 }
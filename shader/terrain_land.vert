@@ -2,17 +2,20 @@
 
 uniform mat4 persp_matrix;
 uniform mat4 view_matrix;
+// Offset of the region from the current region.
+uniform vec3 region_offset;
 
 in vec3 position;
-//in vec3 normal;
+in vec3 normal;
+in vec4 blend;
 out vec3 v_position;
 out vec3 v_normal;
-out float v_color;
+out vec4 v_blend;
 
 void main() {
-    //v_position = position;
-    //v_normal = normal;
-    v_normal = vec3(1.0, 0.0, 0.0);
-    gl_Position = persp_matrix * view_matrix * vec4(position, 1.0);
-    v_color = position.z / 24.8;
+    v_position = position + region_offset;
+    // Normals stay in world coordinates, the lighting is computed there.
+    v_normal = normal;
+    v_blend = blend;
+    gl_Position = persp_matrix * view_matrix * vec4(v_position, 1.0);
 }
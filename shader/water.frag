@@ -0,0 +1,22 @@
+#version 140
+
+// Position of the eye, in the coordinates of the current region.
+uniform vec3 camera_position;
+
+in vec3 v_position;
+out vec4 f_color;
+
+const vec3 shallow_color = vec3(0.15, 0.35, 0.45);
+const vec3 sky_color = vec3(0.55, 0.7, 0.85);
+
+void main() {
+    vec3 to_eye = normalize(camera_position - v_position);
+    // Schlick's approximation: looking down onto the water it is mostly
+    // transparent, towards the horizon it reflects the sky.
+    float cos_theta = abs(to_eye.z);
+    float fresnel = 0.02 + 0.98 * pow(1.0 - cos_theta, 5.0);
+
+    vec3 color = mix(shallow_color, sky_color, fresnel);
+    float alpha = mix(0.45, 1.0, fresnel);
+    f_color = vec4(color, alpha);
+}
@@ -0,0 +1,13 @@
+#version 140
+
+uniform mat4 persp_matrix;
+uniform mat4 view_matrix;
+
+// Already in the coordinates of the current region.
+in vec3 position;
+out vec3 v_position;
+
+void main() {
+    v_position = position;
+    gl_Position = persp_matrix * view_matrix * vec4(position, 1.0);
+}
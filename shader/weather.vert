@@ -0,0 +1,14 @@
+#version 140
+
+uniform mat4 persp_matrix;
+uniform mat4 view_matrix;
+
+// Already in the coordinates of the current region.
+in vec3 position;
+in vec4 color;
+out vec4 v_color;
+
+void main() {
+    v_color = color;
+    gl_Position = persp_matrix * view_matrix * vec4(position, 1.0);
+}
@@ -1,9 +1,187 @@
+use data::region::{DimensionsError, Region, RegionDimensions};
 use data::terrain::TerrainPatch;
 use data::{ids, terrain};
+use failure::Error;
+use rmp_serde;
 pub use simple_disk_cache::CacheError;
 use simple_disk_cache::SimpleCache;
 pub use simple_disk_cache::config::CacheConfig;
+use serde_json;
+use slog::Logger;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 use types::{Uuid, Vector2};
 
+/// Version of the encoding of terrain patches in the cache, to be increased
+/// whenever `TerrainPatch` changes incompatibly.
+pub const TERRAIN_FORMAT_VERSION: u32 = 1;
+
 pub type TerrainCache =
-    SimpleCache<(ids::PersistentRegionId, terrain::PatchPosition), TerrainPatch>;
+    SimpleCache<(ids::PersistentRegionId, terrain::PatchPosition), TerrainCacheEntry>;
+
+/// An entry of the terrain cache.
+///
+/// The patch is encoded separately from the header, so the header can still
+/// be read once the format of `TerrainPatch` changed.
+#[derive(Serialize, Deserialize)]
+pub struct TerrainCacheEntry {
+    pub format_version: u32,
+
+    /// The cache id of the region (see `Region::cache_id`) when the patch
+    /// was received.
+    pub region_cache_id: Uuid,
+
+    /// When the patch was received, in seconds since the Unix epoch.
+    pub timestamp: u64,
+
+    /// The encoded patch, empty if the entry was dropped.
+    data: Vec<u8>,
+}
+
+impl TerrainCacheEntry {
+    pub fn new(patch: &TerrainPatch, region_cache_id: Uuid) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        TerrainCacheEntry {
+            format_version: TERRAIN_FORMAT_VERSION,
+            region_cache_id,
+            timestamp,
+            data: rmp_serde::to_vec(patch).expect("encoding a terrain patch failed"),
+        }
+    }
+
+    /// An entry replacing one which was dropped, as the cache can not remove
+    /// single entries.
+    pub fn dropped() -> Self {
+        TerrainCacheEntry {
+            format_version: TERRAIN_FORMAT_VERSION,
+            region_cache_id: Uuid::nil(),
+            timestamp: 0,
+            data: Vec::new(),
+        }
+    }
+
+    /// An entry whose patch can't be decoded.
+    #[cfg(test)]
+    pub fn corrupt() -> Self {
+        TerrainCacheEntry {
+            // A marker which is never used in MessagePack.
+            data: vec![0xc1],
+            ..Self::dropped()
+        }
+    }
+
+    pub fn is_dropped(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Returns the age of the entry in seconds.
+    pub fn age(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        now.saturating_sub(self.timestamp)
+    }
+
+    pub fn decode(&self) -> Result<TerrainPatch, rmp_serde::decode::Error> {
+        rmp_serde::from_slice(&self.data)
+    }
+}
+
+/// The regions whose terrain was put into the terrain cache.
+///
+/// The cache can not list its keys, so this index is kept next to it. It
+/// holds what is needed to show a region without connecting to its
+/// simulator.
+#[derive(Default, Serialize, Deserialize)]
+pub struct RegionIndex {
+    /// The regions by their UUID.
+    regions: BTreeMap<String, CachedRegion>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CachedRegion {
+    pub side_meters: u32,
+    /// In units of 256 meters.
+    pub grid_location: (u32, u32),
+    pub water_height: f32,
+}
+
+impl CachedRegion {
+    pub fn from_region(region: &Region) -> Self {
+        CachedRegion {
+            side_meters: region.dimensions().side_meters,
+            grid_location: (region.grid_location().x, region.grid_location().y),
+            water_height: region.water_height(),
+        }
+    }
+
+    /// Recreates the region, the terrain composition is not cached.
+    pub fn to_region(&self, uuid: Uuid, id: ids::RegionId) -> Result<Region, DimensionsError> {
+        let dims = RegionDimensions::from_size(self.side_meters, self.side_meters)?;
+        let grid_location = Vector2::new(self.grid_location.0, self.grid_location.1);
+        let mut region = Region::new(uuid, id, dims, grid_location);
+        region.set_water_height(self.water_height);
+        Ok(region)
+    }
+}
+
+impl RegionIndex {
+    /// Reads the index, it is empty if the file does not exist yet.
+    ///
+    /// An index which can't be parsed is logged and replaced by an empty one,
+    /// the terrain cache can still be used without it.
+    pub fn load(path: &Path, logger: &Logger) -> Result<Self, Error> {
+        match File::open(path) {
+            Ok(file) => match serde_json::from_reader(file) {
+                Ok(index) => Ok(index),
+                Err(e) => {
+                    warn!(logger, "Region index {:?} is invalid, starting over: {}", path, e);
+                    Ok(RegionIndex::default())
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(RegionIndex::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the index to a temporary file first, which then replaces the
+    /// old index, so an interrupted save can't leave a truncated index.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temp_path = path.with_extension("tmp");
+        serde_json::to_writer_pretty(File::create(&temp_path)?, self)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    /// Adds or updates a region, returns whether the index changed.
+    pub fn insert(&mut self, uuid: &Uuid, region: CachedRegion) -> bool {
+        let key = uuid.to_string();
+        if self.regions.get(&key) == Some(&region) {
+            return false;
+        }
+        self.regions.insert(key, region);
+        true
+    }
+
+    pub fn get(&self, uuid: &Uuid) -> Option<&CachedRegion> {
+        self.regions.get(&uuid.to_string())
+    }
+
+    /// Returns all regions, skipping entries with invalid UUIDs.
+    pub fn regions(&self) -> Vec<(Uuid, CachedRegion)> {
+        self.regions
+            .iter()
+            .filter_map(|(key, region)| Some((Uuid::parse_str(key).ok()?, region.clone())))
+            .collect()
+    }
+}
@@ -1,9 +1,11 @@
 use alga::linear::AffineTransformation;
 use alga::linear::Similarity;
 use data::ids;
+use data::terrain::Ray;
 use data::{Matrix4, PointLocator, Quaternion, RegionLocator, UnitQuaternion, Uuid, Vector2,
            Vector3};
 use glium::glutin;
+use types::nalgebra::Vector4;
 use std::f32::consts::PI;
 
 /// Anything which can be rendered like an avatar.
@@ -26,15 +28,60 @@ pub struct ClientAvatar {
     head_rotation: UnitQuaternion<f32>,
     aspect_ratio: f32,
 
+    /// Current velocity in m/s.
+    velocity: Vector3<f32>,
+    flying: bool,
+    on_ground: bool,
+
     pressed_left: bool,
     pressed_right: bool,
     pressed_up: bool,
     pressed_down: bool,
+    pressed_jump: bool,
+    pressed_crouch: bool,
+    pressed_fly: bool,
+}
+
+/// Parameters of the locomotion model.
+///
+/// The values are roughly the ones of the avatars in the official viewer.
+mod locomotion {
+    /// Walking speed in m/s.
+    pub const WALK_SPEED: f32 = 3.2;
+    /// Horizontal flying speed in m/s.
+    pub const FLY_SPEED: f32 = 10.;
+    /// Vertical flying speed in m/s.
+    pub const FLY_SPEED_VERTICAL: f32 = 4.;
+    /// Acceleration towards the target velocity in m/s^2.
+    pub const ACCELERATION: f32 = 20.;
+    /// Gravitational acceleration in m/s^2.
+    pub const GRAVITY: f32 = 9.81;
+    /// Initial vertical velocity of a jump in m/s.
+    pub const JUMP_SPEED: f32 = 5.;
+    /// Turning speed in rad/s.
+    pub const TURN_SPEED: f32 = 2.;
+    /// Walking downhill the avatar sticks to the ground if it is less than
+    /// this distance (in m) below it.
+    pub const GROUND_SNAP: f32 = 0.5;
+    /// Height of the camera above the avatar position in m.
+    pub const EYE_HEIGHT: f32 = 1.7;
 }
 
 // TODO
 pub struct OtherAvatar {}
 
+/// The movement controls which are currently active for the client avatar.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Controls {
+    pub forward: bool,
+    pub backward: bool,
+    pub turn_left: bool,
+    pub turn_right: bool,
+    pub up: bool,
+    pub down: bool,
+    pub fly: bool,
+}
+
 lazy_static! {
     static ref WORLD_TO_DISPLAY: Matrix4<f32> = Matrix4::new(
         1.,
@@ -56,9 +103,7 @@ lazy_static! {
     );
 }
 
-// TODO: See opensim_networking::systems::agent_update.
-// pub fn to_update_message(&self, session_id: Uuid) -> AgentUpdate
-// (note: this belongs into the network module and not here)
+// Note: The AgentUpdate messages are created in `networking::agent_update`.
 impl ClientAvatar {
     pub fn new(current_region: Option<ids::RegionId>) -> Self {
         // TODO dummy
@@ -80,10 +125,17 @@ impl ClientAvatar {
             head_rotation: UnitQuaternion::from_axis_angle(&z_axis, 0.),
             aspect_ratio: 1024. / 768.,
 
+            velocity: Vector3::new(0., 0., 0.),
+            flying: false,
+            on_ground: false,
+
             pressed_left: false,
             pressed_right: false,
             pressed_up: false,
             pressed_down: false,
+            pressed_jump: false,
+            pressed_crouch: false,
+            pressed_fly: false,
         }
     }
 
@@ -95,6 +147,25 @@ impl ClientAvatar {
         self.current_region = reg;
     }
 
+    /// Moves the avatar into another region.
+    ///
+    /// `rel_pos` is the position relative to the new region, if it is not
+    /// known yet the position is kept until `set_position` is called.
+    pub fn enter_region(&mut self, region: ids::RegionId, rel_pos: Option<Vector3<f32>>) {
+        self.current_region = Some(region);
+        if let Some(rel_pos) = rel_pos {
+            self.set_position(rel_pos);
+        }
+    }
+
+    /// Sets the position relative to the current region, as reported by the
+    /// simulator.
+    pub fn set_position(&mut self, rel_pos: Vector3<f32>) {
+        self.loc.rel_pos = rel_pos;
+        self.velocity = Vector3::new(0., 0., 0.);
+        self.on_ground = false;
+    }
+
     pub fn handle_key(&mut self, key: glutin::VirtualKeyCode, pressed: bool) -> bool {
         match key {
             glutin::VirtualKeyCode::Left => {
@@ -113,35 +184,162 @@ impl ClientAvatar {
                 self.pressed_down = pressed;
                 true
             }
+            glutin::VirtualKeyCode::Space | glutin::VirtualKeyCode::PageUp => {
+                self.pressed_jump = pressed;
+                true
+            }
+            glutin::VirtualKeyCode::PageDown => {
+                self.pressed_crouch = pressed;
+                true
+            }
+            glutin::VirtualKeyCode::F => {
+                // Only toggle once per key press, ignoring the key repeats.
+                if pressed && !self.pressed_fly {
+                    self.flying = !self.flying;
+                }
+                self.pressed_fly = pressed;
+                true
+            }
             _ => false,
         }
     }
 
+    pub fn flying(&self) -> bool {
+        self.flying
+    }
+
+    pub fn on_ground(&self) -> bool {
+        self.on_ground
+    }
+
+    pub fn velocity(&self) -> &Vector3<f32> {
+        &self.velocity
+    }
+
+    pub fn controls(&self) -> Controls {
+        Controls {
+            forward: self.pressed_up,
+            backward: self.pressed_down,
+            turn_left: self.pressed_left,
+            turn_right: self.pressed_right,
+            up: self.pressed_jump,
+            down: self.pressed_crouch,
+            fly: self.flying,
+        }
+    }
+
+    /// Position of the camera relative to the region.
+    pub fn eye_position(&self) -> Vector3<f32> {
+        self.loc.rel_pos + Vector3::new(0., 0., locomotion::EYE_HEIGHT)
+    }
+
+    /// The horizontal direction the avatar is looking in.
+    pub fn heading(&self) -> Vector2<f32> {
+        // y-axis in the world is the viewing direction.
+        let fwd = self.head_rotation.rotate_vector(&Vector3::y_axis());
+        Vector2::new(fwd.x, fwd.y)
+    }
+
     /// Updates according to local movement input.
-    pub fn update(&mut self) {
+    ///
+    /// `dt` is the length of the time step in seconds, `ground_height`
+    /// returns the terrain height at a region relative position or `None` if
+    /// the terrain there is not known (yet).
+    pub fn update<F>(&mut self, dt: f32, ground_height: F)
+    where
+        F: Fn(f32, f32) -> Option<f32>,
+    {
+        use self::locomotion::*;
+
+        // Turning.
+        let axis = Vector3::z_axis();
+        let turn = if self.pressed_left {
+            TURN_SPEED
+        } else if self.pressed_right {
+            -TURN_SPEED
+        } else {
+            0.
+        };
+        if turn != 0. {
+            self.head_rotation = self.head_rotation
+                .append_rotation(&UnitQuaternion::from_axis_angle(&axis, turn * dt));
+            self.body_rotation = self.head_rotation;
+        }
+
         // y-axis in the world, z-axis in the rendering.
         let default_dir = Vector3::y_axis();
         let fwd = self.head_rotation.rotate_vector(&default_dir);
-        //println!("fwd: {:?}", fwd);
-        if self.pressed_up {
-            self.loc.rel_pos += fwd;
+
+        // Accelerate horizontally towards the velocity requested by the input.
+        let speed = if self.flying { FLY_SPEED } else { WALK_SPEED };
+        let input = if self.pressed_up {
+            1.
         } else if self.pressed_down {
-            self.loc.rel_pos -= fwd;
+            -1.
+        } else {
+            0.
+        };
+        let target = fwd * (input * speed);
+        let horizontal = Vector3::new(self.velocity.x, self.velocity.y, 0.);
+        let horizontal = approach(horizontal, target, ACCELERATION * dt);
+        self.velocity.x = horizontal.x;
+        self.velocity.y = horizontal.y;
+
+        // Vertical movement.
+        if self.flying {
+            let target = if self.pressed_jump {
+                FLY_SPEED_VERTICAL
+            } else if self.pressed_crouch {
+                -FLY_SPEED_VERTICAL
+            } else {
+                0.
+            };
+            let vertical = approach(
+                Vector3::new(0., 0., self.velocity.z),
+                Vector3::new(0., 0., target),
+                ACCELERATION * dt,
+            );
+            self.velocity.z = vertical.z;
+        } else if self.on_ground {
+            self.velocity.z = if self.pressed_jump { JUMP_SPEED } else { 0. };
+        } else {
+            self.velocity.z -= GRAVITY * dt;
         }
 
-        let axis = Vector3::z_axis();
-        if self.pressed_left {
-            self.head_rotation = self.head_rotation
-                .append_rotation(&UnitQuaternion::from_axis_angle(&axis, 0.1));
-        } else if self.pressed_right {
-            self.head_rotation = self.head_rotation
-                .append_rotation(&UnitQuaternion::from_axis_angle(&axis, -0.1));
+        self.loc.rel_pos += self.velocity * dt;
+
+        // Keep the avatar on or above the terrain.
+        let pos = self.loc.rel_pos;
+        match ground_height(pos.x, pos.y) {
+            Some(ground) => {
+                let snap = !self.flying && self.on_ground && self.velocity.z <= 0.
+                    && pos.z - ground < GROUND_SNAP;
+                if pos.z <= ground || snap {
+                    self.loc.rel_pos.z = ground;
+                    self.velocity.z = self.velocity.z.max(0.);
+                    self.on_ground = true;
+
+                    // Flying down onto the ground lands the avatar.
+                    if self.flying && self.pressed_crouch {
+                        self.flying = false;
+                    }
+                } else {
+                    self.on_ground = false;
+                }
+            }
+            None => {
+                // Don't fall through terrain which was not received yet.
+                if !self.flying {
+                    self.velocity.z = 0.;
+                }
+                self.on_ground = false;
+            }
         }
     }
 
     pub fn get_view_matrix(&self) -> Matrix4<f32> {
         // Translate world coordinates to coordinates relative to the eye.
-        let trans = Matrix4::new_translation(&(self.loc.rel_pos * -1.));
+        let trans = Matrix4::new_translation(&(self.eye_position() * -1.));
 
         // Convert (x,y,z) world to (x,z,y) for display coordinates.
         &*WORLD_TO_DISPLAY * trans * self.head_rotation.to_homogeneous()
@@ -156,6 +354,29 @@ impl ClientAvatar {
         // This converts from camera relative coordinates to screen coordinates.
         Matrix4::new_perspective(aspect, fovy, near, far)
     }
+
+    /// Returns the ray from the eye through a point of the screen, given in
+    /// pixels from the upper left corner of a window of `size` pixels, in
+    /// region relative coordinates.
+    ///
+    /// This is `None` if the point can't be unprojected.
+    pub fn screen_ray(&self, point: (f64, f64), size: (u32, u32)) -> Option<Ray> {
+        let ndc_x = 2. * point.0 as f32 / size.0 as f32 - 1.;
+        let ndc_y = 1. - 2. * point.1 as f32 / size.1 as f32;
+        let inverse = (self.get_persp_matrix() * self.get_view_matrix()).try_inverse()?;
+        let unproject = |ndc_z: f32| {
+            let p = inverse * Vector4::new(ndc_x, ndc_y, ndc_z, 1.);
+            Vector3::new(p.x / p.w, p.y / p.w, p.z / p.w)
+        };
+
+        // From the near to the far plane.
+        let near = unproject(-1.);
+        let far = unproject(1.);
+        Some(Ray {
+            origin: near,
+            direction: (far - near).normalize(),
+        })
+    }
 }
 
 impl Avatar for ClientAvatar {
@@ -171,3 +392,85 @@ impl Avatar for ClientAvatar {
         &self.head_rotation
     }
 }
+
+/// Moves `current` towards `target` by at most `max_delta`.
+fn approach(current: Vector3<f32>, target: Vector3<f32>, max_delta: f32) -> Vector3<f32> {
+    let diff = target - current;
+    let dist = diff.norm();
+    if dist <= max_delta {
+        target
+    } else {
+        current + diff * (max_delta / dist)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Projects a region relative point to normalized device coordinates.
+    fn project(avatar: &ClientAvatar, point: Vector3<f32>) -> (Vector3<f32>, f32) {
+        let clip = avatar.get_persp_matrix() * avatar.get_view_matrix()
+            * Vector4::new(point.x, point.y, point.z, 1.);
+        (Vector3::new(clip.x / clip.w, clip.y / clip.w, clip.z / clip.w), clip.w)
+    }
+
+    #[test]
+    fn screen_rays_unproject_screen_points() {
+        let avatar = ClientAvatar::new(None);
+        let size = (800, 600);
+        for &(point, ndc_x, ndc_y) in &[((400., 300.), 0., 0.), ((200., 450.), -0.5, -0.5)] {
+            let ray = avatar.screen_ray(point, size).unwrap();
+            let (ndc, w) = project(&avatar, ray.at(10.));
+            // In front of the eye, and back at the same point of the screen.
+            assert!(w > 0.);
+            assert!((ndc.x - ndc_x).abs() < 1e-3);
+            assert!((ndc.y - ndc_y).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn falling_avatars_land_on_the_terrain() {
+        let mut avatar = ClientAvatar::new(None);
+        for _ in 0..100 {
+            avatar.update(0.02, |_, _| Some(2.));
+        }
+        assert_eq!(avatar.location().rel_pos.z, 2.);
+        assert_eq!(avatar.velocity().z, 0.);
+        assert!(avatar.on_ground());
+
+        // Walking down a gentle slope it sticks to the ground.
+        avatar.update(0.02, |_, _| Some(1.8));
+        assert_eq!(avatar.location().rel_pos.z, 1.8);
+        assert!(avatar.on_ground());
+    }
+
+    #[test]
+    fn jumps_follow_a_ballistic_arc() {
+        use super::locomotion::{GRAVITY, JUMP_SPEED};
+
+        let dt = 0.01;
+        let mut avatar = ClientAvatar::new(None);
+        avatar.set_position(Vector3::new(5., 5., 0.));
+        avatar.update(dt, |_, _| Some(0.));
+        assert!(avatar.on_ground());
+
+        avatar.handle_key(glutin::VirtualKeyCode::Space, true);
+        avatar.update(dt, |_, _| Some(0.));
+        avatar.handle_key(glutin::VirtualKeyCode::Space, false);
+        assert!(!avatar.on_ground());
+
+        let mut apex: f32 = 0.;
+        let mut steps = 1;
+        while !avatar.on_ground() && steps < 1000 {
+            avatar.update(dt, |_, _| Some(0.));
+            apex = apex.max(avatar.location().rel_pos.z);
+            steps += 1;
+        }
+        let expected_apex = JUMP_SPEED * JUMP_SPEED / (2. * GRAVITY);
+        assert!((apex - expected_apex).abs() < 0.05);
+        let expected_time = 2. * JUMP_SPEED / GRAVITY;
+        assert!((steps as f32 * dt - expected_time).abs() < 0.05);
+        assert_eq!(avatar.location().rel_pos.z, 0.);
+    }
+}
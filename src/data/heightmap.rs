@@ -0,0 +1,594 @@
+//! Conversion of the terrain of whole regions from and to heightmap files,
+//! and to meshes, so it can be used outside of the viewer and fixture terrain
+//! can be used without a simulator.
+//!
+//! Heightmaps are matrices indexed by `(x, y)` in meters, like the land
+//! heightmaps of the terrain patches.
+
+use data::ids;
+use data::region::{Region, RegionDimensions};
+use data::terrain::{StorageError, TerrainPatch, TerrainStorage};
+use png::{self, HasParameters};
+use serde_json;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use types::{DMatrix, Uuid, Vector2};
+
+/// Meters per unit of the samples of 16 bit PNG heightmaps, which therefore
+/// cover heights from 0 to 512 meters.
+pub const PNG_HEIGHT_UNIT: f32 = 1. / 128.;
+
+#[derive(Debug, Fail)]
+pub enum ExportError {
+    /// The patches at these positions are neither in memory nor in the disk
+    /// cache.
+    #[fail(display = "Terrain patches are missing: {:?}", _0)]
+    MissingPatches(Vec<(u8, u8)>),
+
+    #[fail(display = "Terrain patch at {:?} has size {}x{}.", _0, _1, _2)]
+    InvalidPatchSize((u8, u8), usize, usize),
+
+    #[fail(display = "Storage error: {}", _0)]
+    Storage(#[cause] StorageError),
+
+    #[fail(display = "IO error: {}", _0)]
+    Io(io::Error),
+
+    #[fail(display = "PNG error: {}", _0)]
+    Png(png::EncodingError),
+}
+
+#[derive(Debug, Fail)]
+pub enum ImportError {
+    #[fail(display = "Unknown heightmap format: {:?}", _0)]
+    UnknownFormat(PathBuf),
+
+    #[fail(display = "Heightmap of {} bytes is not a square of whole samples.", _0)]
+    InvalidLength(usize),
+
+    #[fail(display = "Unsupported PNG heightmap: {:?} {:?}", _0, _1)]
+    UnsupportedPng(png::ColorType, png::BitDepth),
+
+    #[fail(display = "Heightmap is {}x{}, but the region is {}x{}.", _0, _1, _2, _2)]
+    SizeMismatch(usize, usize, usize),
+
+    #[fail(display = "Storage error: {}", _0)]
+    Storage(#[cause] StorageError),
+
+    #[fail(display = "IO error: {}", _0)]
+    Io(io::Error),
+
+    #[fail(display = "PNG error: {}", _0)]
+    Png(png::DecodingError),
+}
+
+impl From<io::Error> for ImportError {
+    fn from(e: io::Error) -> Self {
+        ImportError::Io(e)
+    }
+}
+
+impl From<png::DecodingError> for ImportError {
+    fn from(e: png::DecodingError) -> Self {
+        ImportError::Png(e)
+    }
+}
+
+impl From<io::Error> for ExportError {
+    fn from(e: io::Error) -> Self {
+        ExportError::Io(e)
+    }
+}
+
+impl From<png::EncodingError> for ExportError {
+    fn from(e: png::EncodingError) -> Self {
+        ExportError::Png(e)
+    }
+}
+
+/// Reads a heightmap file, the format is determined by the extension of the
+/// path (`raw`, `r32` or `png`).
+pub fn load(path: &Path) -> Result<DMatrix<f32>, ImportError> {
+    let extension = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    let mut file = File::open(path)?;
+    match extension.as_ref().map(|e| e.as_str()) {
+        Some("raw") => {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            read_raw(&data)
+        }
+        Some("r32") => {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            read_r32(&data)
+        }
+        Some("png") => read_png(file),
+        _ => Err(ImportError::UnknownFormat(path.to_path_buf())),
+    }
+}
+
+/// Returns the side of a square heightmap with `samples` samples.
+fn square_side(samples: usize, bytes: usize) -> Result<usize, ImportError> {
+    let side = (samples as f64).sqrt().round() as usize;
+    if side > 0 && side * side == samples {
+        Ok(side)
+    } else {
+        Err(ImportError::InvalidLength(bytes))
+    }
+}
+
+/// Reads the 13 channel RAW format, see `write_raw`.
+pub fn read_raw(data: &[u8]) -> Result<DMatrix<f32>, ImportError> {
+    if data.len() % 13 != 0 {
+        return Err(ImportError::InvalidLength(data.len()));
+    }
+    let side = square_side(data.len() / 13, data.len())?;
+    Ok(DMatrix::from_fn(side, side, |x, y| {
+        let offset = ((side - 1 - y) * side + x) * 13;
+        data[offset] as f32 * data[offset + 1] as f32 / 128.
+    }))
+}
+
+/// Reads the `.r32` format, see `write_r32`.
+pub fn read_r32(data: &[u8]) -> Result<DMatrix<f32>, ImportError> {
+    if data.len() % 4 != 0 {
+        return Err(ImportError::InvalidLength(data.len()));
+    }
+    let side = square_side(data.len() / 4, data.len())?;
+    Ok(DMatrix::from_fn(side, side, |x, y| {
+        let b = &data[(y * side + x) * 4..];
+        let bits = b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24;
+        f32::from_bits(bits)
+    }))
+}
+
+/// Reads a grayscale PNG, starting with the northern row.
+///
+/// 16 bit samples are in units of `PNG_HEIGHT_UNIT`. 8 bit samples span 0 to
+/// 128 meters, like the PNG heightmaps of OpenSim.
+pub fn read_png<R: Read>(r: R) -> Result<DMatrix<f32>, ImportError> {
+    // The samples are used as stored, by default 16 bit samples would be
+    // stripped to 8 bits.
+    let mut decoder = png::Decoder::new(r);
+    decoder.set(png::Transformations::IDENTITY);
+    let (info, mut reader) = decoder.read_info()?;
+    let mut data = vec![0; info.buffer_size()];
+    reader.next_frame(&mut data)?;
+
+    let (width, height) = (info.width as usize, info.height as usize);
+    if width != height {
+        return Err(ImportError::InvalidLength(data.len()));
+    }
+    let side = width;
+    match (info.color_type, info.bit_depth) {
+        (png::ColorType::Grayscale, png::BitDepth::Sixteen) => {
+            Ok(DMatrix::from_fn(side, side, |x, y| {
+                let offset = ((side - 1 - y) * side + x) * 2;
+                let sample = (data[offset] as u16) << 8 | data[offset + 1] as u16;
+                sample as f32 * PNG_HEIGHT_UNIT
+            }))
+        }
+        (png::ColorType::Grayscale, png::BitDepth::Eight) => {
+            Ok(DMatrix::from_fn(side, side, |x, y| {
+                data[(side - 1 - y) * side + x] as f32 / 255. * 128.
+            }))
+        }
+        (color_type, bit_depth) => Err(ImportError::UnsupportedPng(color_type, bit_depth)),
+    }
+}
+
+/// Slices the heightmap of a whole region into its terrain patches.
+pub fn slice(
+    heights: &DMatrix<f32>,
+    region: &Uuid,
+    dims: &RegionDimensions,
+) -> Result<Vec<TerrainPatch>, ImportError> {
+    let side = dims.side_meters as usize;
+    if heights.shape() != (side, side) {
+        let (width, height) = heights.shape();
+        return Err(ImportError::SizeMismatch(width, height, side));
+    }
+
+    let pps = dims.patches_per_side as usize;
+    let size = dims.patch_size_axis as usize;
+    let mut patches = Vec::with_capacity(pps * pps);
+    for patch_x in 0..pps {
+        for patch_y in 0..pps {
+            let heightmap = heights
+                .slice((patch_x * size, patch_y * size), (size, size))
+                .into_owned();
+            let position = Vector2::new(patch_x as u8, patch_y as u8);
+            patches.push(TerrainPatch::new(region.clone(), size, position, heightmap));
+        }
+    }
+    Ok(patches)
+}
+
+/// Puts a heightmap, e.g. one returned by `load`, as the terrain of a region
+/// into the terrain storage.
+pub fn import_region(
+    storage: &TerrainStorage,
+    region: &Region,
+    heights: &DMatrix<f32>,
+) -> Result<(), ImportError> {
+    for patch in slice(heights, region.uuid(), region.dimensions())? {
+        let position = patch.position().clone();
+        storage
+            .put_patch(region.id().clone(), position, patch)
+            .map_err(ImportError::Storage)?;
+    }
+    Ok(())
+}
+
+/// Assembles the heightmap of a whole region from its patches, which are
+/// taken from memory or the disk cache.
+///
+/// If any patches are not available all of them are reported by
+/// `ExportError::MissingPatches`.
+pub fn assemble(
+    storage: &TerrainStorage,
+    region_id: ids::RegionId,
+    dims: &RegionDimensions,
+) -> Result<DMatrix<f32>, ExportError> {
+    let pps = dims.patches_per_side as usize;
+    let size = dims.patch_size_axis as usize;
+
+    let mut handles = Vec::with_capacity(pps * pps);
+    for patch_x in 0..pps {
+        for patch_y in 0..pps {
+            handles.push((region_id, Vector2::new(patch_x as u8, patch_y as u8)));
+        }
+    }
+
+    let mut heights = DMatrix::zeros(pps * size, pps * size);
+    let mut missing = Vec::new();
+    for (handle, result) in handles.iter().zip(storage.get_patches(&handles)) {
+        let pos = (handle.1.x, handle.1.y);
+        let patch = match result {
+            Ok(patch) => patch,
+            Err(StorageError::NotFound) | Err(StorageError::Corrupt(_)) => {
+                missing.push(pos);
+                continue;
+            }
+            Err(e) => return Err(ExportError::Storage(e)),
+        };
+
+        let heightmap = patch.land_heightmap();
+        if heightmap.shape() != (size, size) {
+            let (rows, cols) = heightmap.shape();
+            return Err(ExportError::InvalidPatchSize(pos, rows, cols));
+        }
+        let offset = (pos.0 as usize * size, pos.1 as usize * size);
+        heights.slice_mut(offset, (size, size)).copy_from(heightmap);
+    }
+
+    if missing.is_empty() {
+        Ok(heights)
+    } else {
+        Err(ExportError::MissingPatches(missing))
+    }
+}
+
+/// Exports the terrain of a region into `dir` in all supported formats,
+/// named after the UUID of the region.
+pub fn export_region(
+    storage: &TerrainStorage,
+    region: &Region,
+    dir: &Path,
+) -> Result<(), ExportError> {
+    let heights = assemble(storage, region.id().clone(), region.dimensions())?;
+    fs::create_dir_all(dir)?;
+    let path = |extension: &str| dir.join(format!("{}.{}", region.uuid(), extension));
+
+    let mut file = BufWriter::new(File::create(path("raw"))?);
+    write_raw(&heights, region.water_height(), &mut file)?;
+    file.flush()?;
+
+    let mut file = BufWriter::new(File::create(path("png"))?);
+    write_png(&heights, &mut file)?;
+    file.flush()?;
+
+    let mut file = BufWriter::new(File::create(path("r32"))?);
+    write_r32(&heights, &mut file)?;
+    file.flush()?;
+
+    let mut file = BufWriter::new(File::create(path("obj"))?);
+    write_obj(&heights, &mut file)?;
+    file.flush()?;
+
+    write_gltf(&heights, &path("gltf"))?;
+    Ok(())
+}
+
+/// Writes the 13 channel RAW format used by OpenSim and the official
+/// viewer, starting with the northern row.
+///
+/// A height is stored as the product of the first two channels divided by
+/// 128, so only heights from 0 to about 508 meters can be represented. The
+/// third channel holds the water height, the others are unused.
+pub fn write_raw<W: Write>(
+    heights: &DMatrix<f32>,
+    water_height: f32,
+    w: &mut W,
+) -> io::Result<()> {
+    let (width, height) = heights.shape();
+    let water = water_height.max(0.).min(255.).round() as u8;
+
+    let mut row = Vec::with_capacity(width * 13);
+    for y in (0..height).rev() {
+        row.clear();
+        for x in 0..width {
+            let (value, multiplier) = raw_encode(heights[(x, y)]);
+            row.extend_from_slice(&[value, multiplier, water]);
+            row.extend_from_slice(&[0; 10]);
+        }
+        w.write_all(&row)?;
+    }
+    Ok(())
+}
+
+/// Finds the values of the first two channels of the RAW format which
+/// represent a height best.
+fn raw_encode(height: f32) -> (u8, u8) {
+    let height = height.max(0.).min(255. * 255. / 128.);
+    let mut best = (0, 1, ::std::f32::INFINITY);
+    for multiplier in 1..256u32 {
+        let value = (height * 128. / multiplier as f32).round().min(255.);
+        let error = (value * multiplier as f32 / 128. - height).abs();
+        if error < best.2 {
+            best = (value as u8, multiplier as u8, error);
+        }
+    }
+    (best.0, best.1)
+}
+
+/// Writes a 16 bit grayscale PNG in units of `PNG_HEIGHT_UNIT`, starting with
+/// the northern row.
+pub fn write_png<W: Write>(heights: &DMatrix<f32>, w: &mut W) -> Result<(), png::EncodingError> {
+    let (width, height) = heights.shape();
+    let mut encoder = png::Encoder::new(w, width as u32, height as u32);
+    encoder
+        .set(png::ColorType::Grayscale)
+        .set(png::BitDepth::Sixteen);
+    let mut writer = encoder.write_header()?;
+
+    // Samples are big endian.
+    let mut data = Vec::with_capacity(width * height * 2);
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let sample = (heights[(x, y)] / PNG_HEIGHT_UNIT).round().max(0.).min(65535.) as u16;
+            data.push((sample >> 8) as u8);
+            data.push(sample as u8);
+        }
+    }
+    writer.write_image_data(&data)
+}
+
+/// Writes the `.r32` format of OpenSim, little endian 32 bit floats starting
+/// with the southern row.
+pub fn write_r32<W: Write>(heights: &DMatrix<f32>, w: &mut W) -> io::Result<()> {
+    let (width, height) = heights.shape();
+    for y in 0..height {
+        for x in 0..width {
+            w.write_all(&le_bytes(heights[(x, y)].to_bits()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Triangulates a heightmap with one vertex per sample.
+///
+/// The vertices use the y axis as up axis, as expected by most modelling
+/// tools, i.e. `(x, height, -y)`. The triangles are counter-clockwise seen
+/// from above.
+fn mesh(heights: &DMatrix<f32>) -> (Vec<[f32; 3]>, Vec<u32>) {
+    let (width, height) = heights.shape();
+    let mut vertices = Vec::with_capacity(width * height);
+    for x in 0..width {
+        for y in 0..height {
+            vertices.push([x as f32, heights[(x, y)], -(y as f32)]);
+        }
+    }
+
+    let index = |x: usize, y: usize| (x * height + y) as u32;
+    let mut indices = Vec::with_capacity((width - 1) * (height - 1) * 6);
+    for x in 0..(width - 1) {
+        for y in 0..(height - 1) {
+            indices.extend_from_slice(&[index(x, y), index(x + 1, y), index(x, y + 1)]);
+            indices.extend_from_slice(&[index(x + 1, y + 1), index(x, y + 1), index(x + 1, y)]);
+        }
+    }
+    (vertices, indices)
+}
+
+/// Writes the triangulated heightmap as Wavefront OBJ.
+pub fn write_obj<W: Write>(heights: &DMatrix<f32>, w: &mut W) -> io::Result<()> {
+    let (vertices, indices) = mesh(heights);
+    for v in vertices.iter() {
+        writeln!(w, "v {} {} {}", v[0], v[1], v[2])?;
+    }
+    // OBJ indices start at 1.
+    for t in indices.chunks(3) {
+        writeln!(w, "f {} {} {}", t[0] + 1, t[1] + 1, t[2] + 1)?;
+    }
+    Ok(())
+}
+
+/// Writes the triangulated heightmap as glTF 2.0, to `path` and a binary
+/// buffer next to it with the extension `bin`.
+pub fn write_gltf(heights: &DMatrix<f32>, path: &Path) -> io::Result<()> {
+    let (vertices, indices) = mesh(heights);
+
+    let mut buffer = Vec::with_capacity(vertices.len() * 12 + indices.len() * 4);
+    let mut min = [::std::f32::INFINITY; 3];
+    let mut max = [::std::f32::NEG_INFINITY; 3];
+    for v in vertices.iter() {
+        for i in 0..3 {
+            min[i] = min[i].min(v[i]);
+            max[i] = max[i].max(v[i]);
+            buffer.extend_from_slice(&le_bytes(v[i].to_bits()));
+        }
+    }
+    let positions_len = buffer.len();
+    for i in indices.iter() {
+        buffer.extend_from_slice(&le_bytes(*i));
+    }
+
+    let bin_path = path.with_extension("bin");
+    let bin_name = bin_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .to_string();
+    File::create(&bin_path)?.write_all(&buffer)?;
+
+    let gltf = json!({
+        "asset": { "version": "2.0", "generator": "opensim-client" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [{ "mesh": 0 }],
+        "meshes": [{
+            "primitives": [{ "attributes": { "POSITION": 0 }, "indices": 1, "mode": 4 }]
+        }],
+        "buffers": [{ "uri": bin_name, "byteLength": buffer.len() }],
+        "bufferViews": [
+            { "buffer": 0, "byteOffset": 0, "byteLength": positions_len, "target": 34962 },
+            {
+                "buffer": 0,
+                "byteOffset": positions_len,
+                "byteLength": buffer.len() - positions_len,
+                "target": 34963
+            }
+        ],
+        "accessors": [
+            {
+                "bufferView": 0,
+                "componentType": 5126,
+                "count": vertices.len(),
+                "type": "VEC3",
+                "min": min,
+                "max": max
+            },
+            {
+                "bufferView": 1,
+                "componentType": 5125,
+                "count": indices.len(),
+                "type": "SCALAR"
+            }
+        ]
+    });
+    let file = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(file, &gltf)?;
+    Ok(())
+}
+
+fn le_bytes(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The terrain of an OpenSim region, 256 by 256 meters.
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/terrain/layer_land.r32");
+
+    fn fixture() -> DMatrix<f32> {
+        load(Path::new(FIXTURE)).unwrap()
+    }
+
+    fn region_dimensions() -> RegionDimensions {
+        RegionDimensions::from_size(256, 256).unwrap()
+    }
+
+    #[test]
+    fn load_fixture() {
+        let heights = fixture();
+        assert_eq!(heights.shape(), (256, 256));
+        assert!(heights.iter().all(|h| *h > 0. && *h < 25.));
+    }
+
+    #[test]
+    fn slice_into_patches() {
+        let heights = fixture();
+        let patches = slice(&heights, &Uuid::nil(), &region_dimensions()).unwrap();
+        assert_eq!(patches.len(), 16 * 16);
+
+        let patch = patches.iter().find(|p| p.position() == &Vector2::new(3, 5)).unwrap();
+        assert_eq!(patch.land_heightmap()[(2, 7)], heights[(3 * 16 + 2, 5 * 16 + 7)]);
+    }
+
+    #[test]
+    fn import_fixture_into_storage() {
+        use data::config::TerrainConfig;
+        use data::terrain::testing::Fixture;
+
+        let fixture_terrain = Fixture::new("heightmap-import", TerrainConfig::default());
+        let (storage, region) = (&fixture_terrain.storage, &fixture_terrain.region);
+        let heights = fixture();
+        import_region(storage, region, &heights).unwrap();
+        let handle = (region.id().clone(), Vector2::new(3, 5));
+        let patch = storage.get_patch(&handle).unwrap();
+        assert_eq!(patch.land_heightmap()[(2, 7)], heights[(3 * 16 + 2, 5 * 16 + 7)]);
+
+        let assembled = assemble(storage, region.id().clone(), region.dimensions()).unwrap();
+        assert_eq!(assembled, heights);
+    }
+
+    #[test]
+    fn slice_rejects_wrong_size() {
+        let heights = DMatrix::zeros(128, 128);
+        match slice(&heights, &Uuid::nil(), &region_dimensions()) {
+            Err(ImportError::SizeMismatch(128, 128, 256)) => {}
+            _ => panic!("expected a size mismatch"),
+        }
+    }
+
+    #[test]
+    fn r32_round_trip() {
+        let heights = fixture();
+        let mut data = Vec::new();
+        write_r32(&heights, &mut data).unwrap();
+        assert_eq!(read_r32(&data).unwrap(), heights);
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let heights = fixture();
+        let mut data = Vec::new();
+        write_raw(&heights, 20., &mut data).unwrap();
+        assert_eq!(data.len(), 256 * 256 * 13);
+
+        let read = read_raw(&data).unwrap();
+        for (a, b) in read.iter().zip(heights.iter()) {
+            // At these heights the multiplier can be small enough for a
+            // precision of a few centimeters.
+            assert!((a - b).abs() < 0.05, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let heights = fixture();
+        let mut data = Vec::new();
+        write_png(&heights, &mut data).unwrap();
+
+        let read = read_png(&data[..]).unwrap();
+        for (a, b) in read.iter().zip(heights.iter()) {
+            assert!((a - b).abs() <= PNG_HEIGHT_UNIT / 2.);
+        }
+    }
+
+    #[test]
+    fn png_keeps_all_16_bits() {
+        // 0x9641 units, the low byte must not be lost.
+        let height = 0x9641 as f32 * PNG_HEIGHT_UNIT;
+        let heights = DMatrix::from_element(2, 2, height);
+        let mut data = Vec::new();
+        write_png(&heights, &mut data).unwrap();
+        assert_eq!(read_png(&data[..]).unwrap(), heights);
+    }
+}
@@ -0,0 +1,349 @@
+//! Top down images of the terrain of regions, hillshaded and tinted by the
+//! water like the tiles of the world map.
+//!
+//! The images are rendered from the terrain storage, so regions which are
+//! only in the disk cache can be mapped offline too. They have one pixel per
+//! meter, and like heightmap images their rows start in the north.
+
+use data::config;
+use data::region::Region;
+use data::terrain::{StorageError, TerrainStorage};
+use png::{self, HasParameters};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use types::{DMatrix, Vector2, Vector3};
+
+/// Colours of the land by height above the water, between which the colour
+/// is interpolated.
+const LAND_COLORS: [(f32, [f32; 3]); 4] = [
+    (0., [0.76, 0.70, 0.50]),
+    (4., [0.35, 0.50, 0.25]),
+    (40., [0.50, 0.45, 0.40]),
+    (100., [0.90, 0.90, 0.92]),
+];
+
+const SHALLOW_WATER_COLOR: [f32; 3] = [0.25, 0.55, 0.65];
+const DEEP_WATER_COLOR: [f32; 3] = [0.05, 0.15, 0.35];
+
+/// Depth in meters at which the water is opaque and of the deep colour.
+const DEEP_WATER: f32 = 16.;
+
+/// Colour of the parts of the region whose terrain is not known.
+const UNKNOWN_COLOR: [f32; 3] = [0.15, 0.15, 0.15];
+
+/// Brightness of the land facing away from the sun.
+const AMBIENT: f32 = 0.35;
+
+/// Exaggeration of the heights for the hillshading, so gentle slopes are
+/// visible too.
+const RELIEF_EXAGGERATION: f32 = 2.;
+
+#[derive(Debug, Fail)]
+pub enum MapError {
+    #[fail(display = "Storage error: {}", _0)]
+    Storage(StorageError),
+
+    #[fail(display = "IO error: {}", _0)]
+    Io(io::Error),
+
+    #[fail(display = "PNG error: {}", _0)]
+    Png(png::EncodingError),
+}
+
+impl From<io::Error> for MapError {
+    fn from(e: io::Error) -> Self {
+        MapError::Io(e)
+    }
+}
+
+impl From<png::EncodingError> for MapError {
+    fn from(e: png::EncodingError) -> Self {
+        MapError::Png(e)
+    }
+}
+
+/// An RGB image with 8 bit sRGB channels, the rows starting in the north.
+#[derive(Clone, Debug)]
+pub struct MapTile {
+    width: usize,
+    height: usize,
+    data: Vec<u8>,
+}
+
+impl MapTile {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The pixels, row by row.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the pixel at `(x, y)` meters from the south west corner.
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let i = ((self.height - 1 - y) * self.width + x) * 3;
+        [self.data[i], self.data[i + 1], self.data[i + 2]]
+    }
+
+    pub fn write_png<W: Write>(&self, w: &mut W) -> Result<(), png::EncodingError> {
+        let mut encoder = png::Encoder::new(w, self.width as u32, self.height as u32);
+        encoder
+            .set(png::ColorType::RGB)
+            .set(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.data)
+    }
+}
+
+fn mix(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [
+        a[0] + (b[0] - a[0]) * t,
+        a[1] + (b[1] - a[1]) * t,
+        a[2] + (b[2] - a[2]) * t,
+    ]
+}
+
+/// Colour of the land at a height above the water.
+fn land_color(elevation: f32) -> [f32; 3] {
+    let mut lower = LAND_COLORS[0];
+    for &upper in LAND_COLORS[1..].iter() {
+        if elevation < upper.0 {
+            let t = ((elevation - lower.0) / (upper.0 - lower.0)).max(0.);
+            return mix(lower.1, upper.1, t);
+        }
+        lower = upper;
+    }
+    lower.1
+}
+
+/// The height at `(x, y)`, or at the closest known point on the line to the
+/// given neighbour if it is outside of the map or not known.
+fn known_height(heights: &DMatrix<f32>, x: usize, y: usize, dx: isize, dy: isize) -> f32 {
+    let (width, height) = heights.shape();
+    let (nx, ny) = (x as isize + dx, y as isize + dy);
+    if nx >= 0 && ny >= 0 && (nx as usize) < width && (ny as usize) < height {
+        let h = heights[(nx as usize, ny as usize)];
+        if h.is_finite() {
+            return h;
+        }
+    }
+    heights[(x, y)]
+}
+
+/// Brightness of the terrain lit by the sun in the north west, 1 for flat
+/// terrain.
+fn hillshade(heights: &DMatrix<f32>, x: usize, y: usize) -> f32 {
+    let dh_dx = (known_height(heights, x, y, 1, 0) - known_height(heights, x, y, -1, 0)) / 2.;
+    let dh_dy = (known_height(heights, x, y, 0, 1) - known_height(heights, x, y, 0, -1)) / 2.;
+    let normal = Vector3::new(
+        -dh_dx * RELIEF_EXAGGERATION,
+        -dh_dy * RELIEF_EXAGGERATION,
+        1.,
+    ).normalize();
+    let sun = Vector3::new(-1., 1., 2f32.sqrt()).normalize();
+    let light = (normal.dot(&sun) / sun.z).max(0.);
+    AMBIENT + (1. - AMBIENT) * light
+}
+
+fn to_srgb_byte(value: f32) -> u8 {
+    (value.max(0.).min(1.) * 255.).round() as u8
+}
+
+/// Renders a heightmap, indexed by `(x, y)` in meters. Unknown heights are
+/// NaN.
+pub fn render(heights: &DMatrix<f32>, water_height: f32) -> MapTile {
+    let (width, height) = heights.shape();
+    let mut data = Vec::with_capacity(width * height * 3);
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let h = heights[(x, y)];
+            let color = if !h.is_finite() {
+                UNKNOWN_COLOR
+            } else {
+                let brightness = hillshade(heights, x, y);
+                let land = land_color(h - water_height);
+                let shaded = [land[0] * brightness, land[1] * brightness, land[2] * brightness];
+                let depth = water_height - h;
+                if depth > 0. {
+                    let t = (depth / DEEP_WATER).min(1.);
+                    let water = mix(SHALLOW_WATER_COLOR, DEEP_WATER_COLOR, t);
+                    mix(shaded, water, 0.5 + 0.5 * t)
+                } else {
+                    shaded
+                }
+            };
+            data.extend(color.iter().map(|&c| to_srgb_byte(c)));
+        }
+    }
+    MapTile {
+        width,
+        height,
+        data,
+    }
+}
+
+/// Collects the heights of a region from the terrain storage, the heights of
+/// patches which are neither in memory nor in the disk cache are NaN.
+/// Previews are left out, the map shows the terrain of the simulator.
+fn gather(storage: &TerrainStorage, region: &Region) -> Result<DMatrix<f32>, StorageError> {
+    let dims = region.dimensions();
+    let pps = dims.patches_per_side as usize;
+    let size = dims.patch_size_axis as usize;
+
+    let mut handles = Vec::with_capacity(pps * pps);
+    for patch_x in 0..pps {
+        for patch_y in 0..pps {
+            handles.push((region.id().clone(), Vector2::new(patch_x as u8, patch_y as u8)));
+        }
+    }
+
+    let mut heights = DMatrix::from_element(pps * size, pps * size, ::std::f32::NAN);
+    for (handle, result) in handles.iter().zip(storage.get_received_patches(&handles)) {
+        let patch = match result {
+            Ok(patch) => patch,
+            Err(StorageError::NotFound) | Err(StorageError::Corrupt(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        let heightmap = patch.land_heightmap();
+        if heightmap.shape() != (size, size) {
+            continue;
+        }
+        let offset = (handle.1.x as usize * size, handle.1.y as usize * size);
+        heights.slice_mut(offset, (size, size)).copy_from(heightmap);
+    }
+    Ok(heights)
+}
+
+/// Renders the map tile of a region.
+pub fn render_region(storage: &TerrainStorage, region: &Region) -> Result<MapTile, MapError> {
+    let heights = gather(storage, region).map_err(MapError::Storage)?;
+    Ok(render(&heights, region.water_height()))
+}
+
+/// Renders the map tile of a region and saves it as PNG, returning the path
+/// of the file.
+pub fn save_region(storage: &TerrainStorage, region: &Region) -> Result<PathBuf, MapError> {
+    let tile = render_region(storage, region)?;
+    let dir = config::Paths {}.map_tiles();
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("{}.png", region.uuid()));
+
+    let mut file = BufWriter::new(File::create(&path)?);
+    tile.write_png(&mut file)?;
+    file.flush()?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data::config::TerrainConfig;
+    use data::terrain::testing::Fixture;
+    use data::terrain::TerrainPatch;
+
+    const WATER: f32 = 20.;
+
+    fn color_bytes(color: [f32; 3]) -> [u8; 3] {
+        [to_srgb_byte(color[0]), to_srgb_byte(color[1]), to_srgb_byte(color[2])]
+    }
+
+    /// The pixel of flat land of the given colour.
+    fn flat_land(color: [f32; 3]) -> [u8; 3] {
+        let flat = DMatrix::from_element(3, 3, 0.);
+        let b = hillshade(&flat, 1, 1);
+        color_bytes([color[0] * b, color[1] * b, color[2] * b])
+    }
+
+    #[test]
+    fn land_color_follows_elevation() {
+        assert_eq!(land_color(-5.), LAND_COLORS[0].1);
+        assert_eq!(land_color(4.), LAND_COLORS[1].1);
+        assert_eq!(land_color(500.), LAND_COLORS[3].1);
+        let between = land_color(2.);
+        assert!(between[1] < LAND_COLORS[0].1[1] && between[1] > LAND_COLORS[1].1[1]);
+    }
+
+    #[test]
+    fn flat_land_has_the_land_color() {
+        let flat = DMatrix::from_element(3, 3, 0.);
+        assert!((hillshade(&flat, 1, 1) - 1.).abs() < 1e-5);
+
+        let tile = render(&DMatrix::from_element(8, 8, WATER + 4.), WATER);
+        assert_eq!((tile.width(), tile.height()), (8, 8));
+        assert_eq!(tile.pixel(3, 3), flat_land(LAND_COLORS[1].1));
+    }
+
+    #[test]
+    fn water_is_tinted_by_depth() {
+        let heights = DMatrix::from_fn(8, 8, |x, _| WATER - 2. * x as f32);
+        let tile = render(&heights, WATER);
+        let shallow = tile.pixel(1, 4);
+        let deep = tile.pixel(7, 4);
+        assert!(shallow[2] > shallow[0]);
+        assert!(deep[2] > deep[0]);
+        assert!(deep[2] < shallow[2]);
+    }
+
+    #[test]
+    fn unknown_terrain_is_grey() {
+        let mut heights = DMatrix::from_element(8, 8, WATER + 4.);
+        heights[(2, 5)] = ::std::f32::NAN;
+        let tile = render(&heights, WATER);
+        assert_eq!(tile.pixel(2, 5), color_bytes(UNKNOWN_COLOR));
+        // The neighbours are still shaded as flat terrain.
+        assert_eq!(tile.pixel(2, 4), flat_land(LAND_COLORS[1].1));
+    }
+
+    #[test]
+    fn slopes_towards_the_sun_are_brighter() {
+        // Rising towards the south east, i.e. facing the sun in the north west.
+        let facing = DMatrix::from_fn(8, 8, |x, y| WATER + 10. + x as f32 - y as f32);
+        let away = DMatrix::from_fn(8, 8, |x, y| WATER + 10. - x as f32 + y as f32);
+        assert!(hillshade(&facing, 4, 4) > 1.);
+        assert!(hillshade(&away, 4, 4) < 1.);
+    }
+
+    #[test]
+    fn rows_start_in_the_north() {
+        let mut heights = DMatrix::from_element(4, 4, WATER + 4.);
+        heights[(0, 3)] = ::std::f32::NAN;
+        let tile = render(&heights, WATER);
+        assert_eq!(&tile.data()[0..3], &color_bytes(UNKNOWN_COLOR)[..]);
+    }
+
+    #[test]
+    fn png_has_the_size_of_the_tile() {
+        let tile = render(&DMatrix::from_element(16, 16, WATER), WATER);
+        let mut png_data = Vec::new();
+        tile.write_png(&mut png_data).unwrap();
+
+        let decoder = png::Decoder::new(&png_data[..]);
+        let (info, _) = decoder.read_info().unwrap();
+        assert_eq!((info.width, info.height), (16, 16));
+        assert_eq!(info.color_type, png::ColorType::RGB);
+    }
+
+    #[test]
+    fn regions_are_rendered_from_received_patches() {
+        let fixture = Fixture::new("map-tile", TerrainConfig::default());
+        let region = fixture.region.clone();
+        let water = region.water_height();
+        fixture.put(&region, 0, 0, |_, _| water + 4.);
+        // Previews don't show up on the map.
+        let land = DMatrix::from_element(16, 16, water - 10.);
+        let preview = TerrainPatch::new(region.uuid().clone(), 16, Vector2::new(0, 0), land);
+        fixture
+            .storage
+            .put_preview((region.id().clone(), Vector2::new(0, 0)), preview);
+
+        let tile = render_region(&fixture.storage, &region).unwrap();
+        assert_eq!(tile.pixel(8, 8), flat_land(LAND_COLORS[1].1));
+        assert_eq!(tile.pixel(100, 100), color_bytes(UNKNOWN_COLOR));
+    }
+}
@@ -9,6 +9,7 @@ use types::{DMatrix, Matrix4, Quaternion, UnitQuaternion, Uuid, Vector2, Vector3
 
 pub mod config {
     use std::path::PathBuf;
+    use std::time::Duration;
 
     pub struct Paths {}
 
@@ -17,23 +18,139 @@ pub mod config {
             // TODO
             "target/cache/terrain".into()
         }
+
+        /// The index of the regions in the terrain cache.
+        pub fn terrain_region_index(&self) -> PathBuf {
+            // TODO
+            "target/cache/terrain_regions.json".into()
+        }
+
+        /// Directory the terrain of regions is exported to.
+        pub fn terrain_export(&self) -> PathBuf {
+            // TODO
+            "target/export".into()
+        }
+
+        /// Directory the map tiles of regions are saved to.
+        pub fn map_tiles(&self) -> PathBuf {
+            // TODO
+            "target/maps".into()
+        }
+    }
+
+    /// Settings of the terrain storage.
+    #[derive(Clone, Debug)]
+    pub struct TerrainConfig {
+        /// Patches further away from the client avatar than this (in meters)
+        /// are not kept in memory.
+        pub draw_distance: f32,
+
+        /// Maximum number of bytes of terrain patches kept in memory, the
+        /// patches furthest away are evicted first.
+        pub memory_budget: usize,
+
+        /// Patches cached on disk longer than this are dropped.
+        pub max_cache_age: Duration,
+    }
+
+    impl Default for TerrainConfig {
+        fn default() -> Self {
+            TerrainConfig {
+                draw_distance: 512.,
+                memory_budget: 64 * 1024 * 1024,
+                max_cache_age: Duration::from_secs(30 * 24 * 60 * 60),
+            }
+        }
     }
 }
 
 /// Managment of the various identifiers, often UUIDs are mapped to usize values
 /// so they can be used in other places to save memory.
 pub mod ids {
+    use parking_lot::RwLock;
+    use std::fmt;
     use types::Uuid;
+    use util::bimap::BiMap;
 
-    // TODO replace by u32, u64 or usize
-    pub type RegionId = Uuid;
+    /// Compact id of a region, only valid during the current session.
+    ///
+    /// Use `PersistentRegionId` for anything stored to disk.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+    pub struct RegionId(u32);
+
+    impl fmt::Display for RegionId {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "#{}", self.0)
+        }
+    }
 
     /// To be used in caches.
     pub type PersistentRegionId = Uuid;
+
+    struct RegionIdsInner {
+        map: BiMap<PersistentRegionId, RegionId>,
+        next_id: u32,
+    }
+
+    /// Assigns the compact region ids to the region UUIDs.
+    ///
+    /// Ids are never reused during a session, so an id held somewhere can't
+    /// refer to a different region later.
+    pub struct RegionIds {
+        inner: RwLock<RegionIdsInner>,
+    }
+
+    impl RegionIds {
+        pub fn new() -> Self {
+            RegionIds {
+                inner: RwLock::new(RegionIdsInner {
+                    map: BiMap::new(),
+                    next_id: 0,
+                }),
+            }
+        }
+
+        /// Returns the id of a region, assigning a new one if the region was
+        /// not seen before.
+        pub fn get_or_insert(&self, uuid: &PersistentRegionId) -> RegionId {
+            if let Some(id) = self.inner.read().map.get_val(uuid) {
+                return *id;
+            }
+
+            let mut inner = self.inner.write();
+            // Another thread could have inserted it in the meantime.
+            if let Some(id) = inner.map.get_val(uuid) {
+                return *id;
+            }
+            let id = RegionId(inner.next_id);
+            inner.next_id += 1;
+            inner.map.insert(uuid.clone(), id);
+            id
+        }
+
+        pub fn get(&self, uuid: &PersistentRegionId) -> Option<RegionId> {
+            self.inner.read().map.get_val(uuid).cloned()
+        }
+
+        pub fn uuid(&self, id: &RegionId) -> Option<PersistentRegionId> {
+            self.inner.read().map.get_key(id).cloned()
+        }
+    }
 }
 
 pub mod avatar;
+pub mod heightmap;
+pub mod map_tile;
+pub mod terraform;
 pub mod terrain;
+pub mod texture;
+pub mod weather;
+
+/// A UUID ending in the decimal digits of `n`, for tests.
+#[cfg(test)]
+pub fn test_uuid(n: u8) -> Uuid {
+    Uuid::parse_str(&format!("00000000-0000-0000-0000-{:012}", n)).unwrap()
+}
 
 /// Contains the various storages for the various entities.
 ///
@@ -42,7 +159,12 @@ pub mod terrain;
 ///       use the full qualified name.
 #[derive(Clone)]
 pub struct Storage {
+    pub region_ids: Arc<ids::RegionIds>,
     pub terrain: Arc<terrain::TerrainStorage>,
+    /// Terraforming strokes to be sent to the simulators.
+    pub strokes: Arc<terraform::StrokeQueue>,
+    pub textures: Arc<texture::TextureStorage>,
+    pub weather: Arc<weather::WeatherStorage>,
     pub region: Arc<region::RegionStorage>,
     pub client_avatar: Arc<RwLock<avatar::ClientAvatar>>,
 }
@@ -52,53 +174,264 @@ pub mod region {
     use failure::Error;
     use std::collections::HashMap;
     use std::sync::{Arc, Mutex};
-    use types::Uuid;
+    use types::{Uuid, Vector2};
     use util::bimap::BiMap;
 
     #[derive(Debug, Fail)]
     pub enum StorageError {
-        #[fail(display = "Region not registered at all: {}", 0)]
+        #[fail(display = "Region not registered at all: {}", _0)]
         NotRegistered(ids::RegionId),
+
+        #[fail(display = "Region is not connected: {}", _0)]
+        NotConnected(ids::RegionId),
+
+        #[fail(display = "Invalid transition from {:?}: {}", _0, _1)]
+        InvalidTransition(Presence, &'static str),
+    }
+
+    #[derive(Debug, Fail)]
+    pub enum DimensionsError {
+        #[fail(display = "Non square regions are not supported: {}x{}", _0, _1)]
+        NotSquare(u32, u32),
+
+        #[fail(display = "Region size is not a multiple of 256 meters: {}", _0)]
+        InvalidSize(u32),
+
+        #[fail(display = "Region size is larger than supported: {}", _0)]
+        TooLarge(u32),
+    }
+
+    /// Where the client avatar is with regard to the regions.
+    ///
+    /// Note: The current region used for rendering is the one of the
+    ///       `ClientAvatar`, which is updated together with its position.
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum Presence {
+        /// Not in any region, i.e. before the first region was connected.
+        Nowhere,
+
+        /// The avatar is in a region.
+        InRegion(ids::RegionId),
+
+        /// The avatar is crossing from one region into a neighbouring one.
+        Crossing {
+            from: ids::RegionId,
+            to: ids::RegionId,
+        },
+
+        /// The avatar is being teleported.
+        Teleporting {
+            from: Option<ids::RegionId>,
+            progress: TeleportProgress,
+        },
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum TeleportProgress {
+        Started,
+        /// A progress message of the simulator, to be displayed to the user.
+        Progress(String),
     }
 
+    struct Inner {
+        regions: HashMap<ids::RegionId, Arc<Connection>>,
+        presence: Presence,
+    }
+
+    impl Inner {
+        fn is_connected(&self, id: &ids::RegionId) -> bool {
+            match self.regions.get(id).map(|conn| &**conn) {
+                Some(&Connection::Connected(_)) => true,
+                _ => false,
+            }
+        }
+    }
+
+    /// Keeps track of the connections to the regions and of the region the
+    /// client avatar is in.
+    ///
+    /// All the transitions happen under one lock, so they are atomic.
     pub struct RegionStorage {
-        regions: Mutex<HashMap<ids::RegionId, Arc<Connection>>>,
+        inner: Mutex<Inner>,
     }
 
     impl RegionStorage {
         pub fn new() -> Self {
             RegionStorage {
-                regions: Mutex::new(HashMap::new()),
+                inner: Mutex::new(Inner {
+                    regions: HashMap::new(),
+                    presence: Presence::Nowhere,
+                }),
             }
         }
 
         /// Warning: Don't store the results, if you want updated values, you
         /// have to call this method again.
         pub fn get(&self, id: &ids::RegionId) -> Result<Arc<Connection>, StorageError> {
-            let regions = self.regions.lock().unwrap();
-            regions
+            let inner = self.inner.lock().unwrap();
+            inner
+                .regions
                 .get(id)
                 .map(Arc::clone)
                 .ok_or_else(|| StorageError::NotRegistered(id.clone()))
         }
 
         pub fn put(&self, id: ids::RegionId, connection: Connection) {
-            let mut regions = self.regions.lock().unwrap();
-            regions.insert(id, Arc::new(connection));
+            let mut inner = self.inner.lock().unwrap();
+            inner.regions.insert(id, Arc::new(connection));
         }
 
-        /*
-        pub fn get_or_create(&self, id: &ids::RegionId) -> Arc<Mutex<Connection>> {
-            let mut regions = self.regions.lock().unwrap();
-            if let Some(region) = regions.get(id) {
-                return Arc::clone(region);
+        /// Returns all regions which are currently connected, i.e. the
+        /// current region and the connected neighbours.
+        pub fn connected(&self) -> Vec<Region> {
+            let inner = self.inner.lock().unwrap();
+            inner
+                .regions
+                .values()
+                .filter_map(|conn| conn.clone_region())
+                .collect()
+        }
+
+        /// Marks the connection to a region as dropped.
+        ///
+        /// If the avatar is in this region, it is afterwards nowhere.
+        pub fn disconnect(&self, id: &ids::RegionId) -> Result<(), StorageError> {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.regions.contains_key(id) {
+                return Err(StorageError::NotRegistered(id.clone()));
             }
+            inner
+                .regions
+                .insert(id.clone(), Arc::new(Connection::Disconnected));
 
-            let region = Arc::new(Mutex::new(Connection::Pending));
-            regions.insert(id.clone(), Arc::clone(&region));
-            region
+            if inner.presence == Presence::InRegion(id.clone()) {
+                inner.presence = Presence::Nowhere;
+            }
+            Ok(())
+        }
+
+        pub fn presence(&self) -> Presence {
+            self.inner.lock().unwrap().presence.clone()
+        }
+
+        /// Starts crossing from the current region into a neighbouring one.
+        pub fn start_crossing(&self, to: ids::RegionId) -> Result<(), StorageError> {
+            let mut inner = self.inner.lock().unwrap();
+            let from = match inner.presence {
+                Presence::InRegion(ref from) => from.clone(),
+                ref p => {
+                    return Err(StorageError::InvalidTransition(p.clone(), "start crossing"));
+                }
+            };
+            inner.presence = Presence::Crossing { from, to };
+            Ok(())
+        }
+
+        /// Starts a teleport out of the current region (if any).
+        pub fn start_teleport(&self) -> Result<(), StorageError> {
+            let mut inner = self.inner.lock().unwrap();
+            let from = match inner.presence {
+                Presence::Nowhere => None,
+                Presence::InRegion(ref from) => Some(from.clone()),
+                ref p => {
+                    return Err(StorageError::InvalidTransition(p.clone(), "start teleport"));
+                }
+            };
+            inner.presence = Presence::Teleporting {
+                from,
+                progress: TeleportProgress::Started,
+            };
+            Ok(())
+        }
+
+        pub fn teleport_progress(&self, message: String) -> Result<(), StorageError> {
+            let mut inner = self.inner.lock().unwrap();
+            match inner.presence {
+                Presence::Teleporting {
+                    ref mut progress, ..
+                } => {
+                    *progress = TeleportProgress::Progress(message);
+                    Ok(())
+                }
+                ref p => Err(StorageError::InvalidTransition(p.clone(), "teleport progress")),
+            }
+        }
+
+        /// Aborts a teleport, the avatar stays in the region it came from if
+        /// that one is still connected.
+        pub fn fail_teleport(&self) -> Result<(), StorageError> {
+            let mut inner = self.inner.lock().unwrap();
+            let from = match inner.presence {
+                Presence::Teleporting { ref from, .. } => from.clone(),
+                ref p => {
+                    return Err(StorageError::InvalidTransition(p.clone(), "fail teleport"));
+                }
+            };
+            let presence = match from {
+                Some(ref from) if inner.is_connected(from) => Presence::InRegion(from.clone()),
+                _ => Presence::Nowhere,
+            };
+            inner.presence = presence;
+            Ok(())
+        }
+
+        /// Finishes the arrival in a connected region, which is either the
+        /// first region, the target of a crossing or the target of a
+        /// teleport.
+        pub fn enter(&self, id: ids::RegionId) -> Result<(), StorageError> {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.is_connected(&id) {
+                return Err(StorageError::NotConnected(id));
+            }
+
+            let valid = match inner.presence {
+                Presence::Nowhere | Presence::Teleporting { .. } => true,
+                Presence::InRegion(ref current) => *current == id,
+                Presence::Crossing { ref to, .. } => *to == id,
+            };
+            if !valid {
+                return Err(StorageError::InvalidTransition(
+                    inner.presence.clone(),
+                    "enter region",
+                ));
+            }
+
+            inner.presence = Presence::InRegion(id);
+            Ok(())
+        }
+    }
+
+    /// Water height of regions the simulator did not tell us about.
+    pub const DEFAULT_WATER_HEIGHT: f32 = 20.;
+
+    /// How the terrain of a region is textured, as announced by the
+    /// simulator in the region handshake.
+    ///
+    /// The corner values are indexed by `x * 2 + y`, i.e. south west, north
+    /// west, south east, north east.
+    #[derive(Clone, Debug)]
+    pub struct TerrainComposition {
+        /// The four detail textures, from low to high elevation.
+        pub detail_textures: [Uuid; 4],
+
+        /// The height at each corner of the region where the blending of the
+        /// detail textures starts.
+        pub start_heights: [f32; 4],
+
+        /// The height range at each corner of the region over which the
+        /// detail textures are blended.
+        pub height_ranges: [f32; 4],
+    }
+
+    impl Default for TerrainComposition {
+        /// The values the simulator uses for new regions.
+        fn default() -> Self {
+            TerrainComposition {
+                detail_textures: [Uuid::nil(); 4],
+                start_heights: [10.; 4],
+                height_ranges: [60.; 4],
+            }
         }
-        */
     }
 
     #[derive(Clone)]
@@ -111,20 +444,114 @@ pub mod region {
 
         /// Dimensions of this region.
         dimensions: RegionDimensions,
+
+        /// The location of the (lower left corner of the) region on the grid,
+        /// in units of 256 meters.
+        grid_location: Vector2<u32>,
+
+        /// Texturing of the terrain.
+        composition: TerrainComposition,
+
+        /// Height of the water surface in meters.
+        water_height: f32,
+
+        /// Changes whenever the content of the region changes, so data
+        /// cached for an older one is outdated. Nil if unknown.
+        cache_id: Uuid,
     }
 
     impl Region {
-        pub fn new(uuid: Uuid, id: ids::RegionId, dimensions: RegionDimensions) -> Self {
+        pub fn new(
+            uuid: Uuid,
+            id: ids::RegionId,
+            dimensions: RegionDimensions,
+            grid_location: Vector2<u32>,
+        ) -> Self {
             Region {
                 uuid,
                 id,
                 dimensions,
+                grid_location,
+                composition: TerrainComposition::default(),
+                water_height: DEFAULT_WATER_HEIGHT,
+                cache_id: Uuid::nil(),
             }
         }
 
+        pub fn set_composition(&mut self, composition: TerrainComposition) {
+            self.composition = composition;
+        }
+
+        pub fn set_water_height(&mut self, water_height: f32) {
+            self.water_height = water_height;
+        }
+
+        pub fn set_cache_id(&mut self, cache_id: Uuid) {
+            self.cache_id = cache_id;
+        }
+
+        pub fn uuid(&self) -> &Uuid {
+            &self.uuid
+        }
+
+        pub fn id(&self) -> &ids::RegionId {
+            &self.id
+        }
+
         pub fn dimensions(&self) -> &RegionDimensions {
             &self.dimensions
         }
+
+        pub fn grid_location(&self) -> &Vector2<u32> {
+            &self.grid_location
+        }
+
+        pub fn composition(&self) -> &TerrainComposition {
+            &self.composition
+        }
+
+        pub fn water_height(&self) -> f32 {
+            self.water_height
+        }
+
+        pub fn cache_id(&self) -> &Uuid {
+            &self.cache_id
+        }
+
+        /// The grid cells (of 256 meters) covered by this region, relative
+        /// to the grid location of `origin`.
+        pub fn grid_cells_from(&self, origin: &Region) -> Vec<Vector2<i64>> {
+            let cells = (self.dimensions.side_meters / 256) as i64;
+            let x0 = self.grid_location[0] as i64 - origin.grid_location[0] as i64;
+            let y0 = self.grid_location[1] as i64 - origin.grid_location[1] as i64;
+
+            let mut result = Vec::new();
+            for x in 0..cells {
+                for y in 0..cells {
+                    result.push(Vector2::new(x0 + x, y0 + y));
+                }
+            }
+            result
+        }
+
+        /// Returns the offset in meters of this region from `origin`, i.e.
+        /// the position of this region in the coordinates of `origin`.
+        pub fn offset_from(&self, origin: &Region) -> Vector2<f32> {
+            let dx = self.grid_location[0] as i64 - origin.grid_location[0] as i64;
+            let dy = self.grid_location[1] as i64 - origin.grid_location[1] as i64;
+            Vector2::new(dx as f32 * 256., dy as f32 * 256.)
+        }
+    }
+
+    /// Returns the grid location (in units of 256 meters) encoded in a region
+    /// handle.
+    ///
+    /// The upper and lower 32 bits of a handle are the global x and y
+    /// coordinates of the region's corner in meters.
+    pub fn grid_location_from_handle(handle: u64) -> Vector2<u32> {
+        let x = (handle >> 32) as u32;
+        let y = (handle & 0xffff_ffff) as u32;
+        Vector2::new(x / 256, y / 256)
     }
 
     #[derive(Clone, Debug, PartialEq, Eq)]
@@ -133,12 +560,42 @@ pub mod region {
         pub side_meters: u32,
 
         /// Number of patches per side.
-        pub patches_per_side: u8,
+        pub patches_per_side: u16,
 
         /// Size of one patch in one axis (16 for normal regions).
         pub patch_size_axis: u8,
     }
 
+    impl RegionDimensions {
+        /// The patch size used by regions of all sizes, including VarRegions.
+        pub const PATCH_SIZE: u8 = 16;
+
+        /// Determines the dimensions of a region with the specified size in
+        /// meters, as reported by the simulator.
+        ///
+        /// Normal regions are 256x256 meters, OpenSim VarRegions can be any
+        /// multiple of that, but patch positions have to fit into `u8`.
+        pub fn from_size(size_x: u32, size_y: u32) -> Result<Self, DimensionsError> {
+            if size_x != size_y {
+                return Err(DimensionsError::NotSquare(size_x, size_y));
+            }
+            if size_x == 0 || size_x % 256 != 0 {
+                return Err(DimensionsError::InvalidSize(size_x));
+            }
+
+            let patches_per_side = size_x / Self::PATCH_SIZE as u32;
+            if patches_per_side > 256 {
+                return Err(DimensionsError::TooLarge(size_x));
+            }
+
+            Ok(RegionDimensions {
+                side_meters: size_x,
+                patches_per_side: patches_per_side as u16,
+                patch_size_axis: Self::PATCH_SIZE,
+            })
+        }
+    }
+
     pub enum Connection {
         /// The connection to the region is not yet established.
         ///
@@ -167,6 +624,97 @@ pub mod region {
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use data::test_uuid;
+
+        /// Connects a region with a UUID ending in `n`.
+        fn connect(storage: &RegionStorage, region_ids: &ids::RegionIds, n: u8) -> ids::RegionId {
+            let uuid = test_uuid(n);
+            let id = region_ids.get_or_insert(&uuid);
+            let dims = RegionDimensions::from_size(256, 256).unwrap();
+            let region = Region::new(uuid, id, dims, Vector2::new(1000 + n as u32, 1000));
+            storage.put(id, Connection::Connected(region));
+            id
+        }
+
+        fn setup() -> (RegionStorage, ids::RegionId, ids::RegionId) {
+            let storage = RegionStorage::new();
+            let region_ids = ids::RegionIds::new();
+            let a = connect(&storage, &region_ids, 1);
+            let b = connect(&storage, &region_ids, 2);
+            (storage, a, b)
+        }
+
+        #[test]
+        fn first_region_has_to_be_connected() {
+            let (storage, a, _) = setup();
+            storage.disconnect(&a).unwrap();
+            assert!(storage.enter(a).is_err());
+            assert_eq!(storage.presence(), Presence::Nowhere);
+        }
+
+        #[test]
+        fn crossing_enters_the_target() {
+            let (storage, a, b) = setup();
+            assert!(storage.start_crossing(b).is_err());
+            storage.enter(a).unwrap();
+            storage.start_crossing(b).unwrap();
+            assert_eq!(storage.presence(), Presence::Crossing { from: a, to: b });
+            // Only the target of the crossing can be entered.
+            assert!(storage.enter(a).is_err());
+            storage.enter(b).unwrap();
+            assert_eq!(storage.presence(), Presence::InRegion(b));
+        }
+
+        #[test]
+        fn teleports_report_progress() {
+            let (storage, a, b) = setup();
+            storage.enter(a).unwrap();
+            assert!(storage.teleport_progress("Waiting".into()).is_err());
+            storage.start_teleport().unwrap();
+            assert!(storage.start_crossing(b).is_err());
+            storage.teleport_progress("Waiting".into()).unwrap();
+            assert_eq!(
+                storage.presence(),
+                Presence::Teleporting {
+                    from: Some(a),
+                    progress: TeleportProgress::Progress("Waiting".into()),
+                }
+            );
+            storage.enter(b).unwrap();
+            assert_eq!(storage.presence(), Presence::InRegion(b));
+        }
+
+        #[test]
+        fn failed_teleports_return_to_connected_origin() {
+            let (storage, a, _) = setup();
+            assert!(storage.fail_teleport().is_err());
+            storage.enter(a).unwrap();
+            storage.start_teleport().unwrap();
+            storage.fail_teleport().unwrap();
+            assert_eq!(storage.presence(), Presence::InRegion(a));
+
+            storage.start_teleport().unwrap();
+            storage.disconnect(&a).unwrap();
+            storage.fail_teleport().unwrap();
+            assert_eq!(storage.presence(), Presence::Nowhere);
+        }
+
+        #[test]
+        fn disconnecting_current_region_leaves_it() {
+            let (storage, a, b) = setup();
+            storage.enter(a).unwrap();
+            storage.disconnect(&b).unwrap();
+            assert_eq!(storage.presence(), Presence::InRegion(a));
+            assert_eq!(storage.connected().len(), 1);
+            storage.disconnect(&a).unwrap();
+            assert_eq!(storage.presence(), Presence::Nowhere);
+            assert!(storage.connected().is_empty());
+        }
+    }
 }
 
 /*
@@ -0,0 +1,315 @@
+//! Terraforming brushes.
+//!
+//! A stroke of a brush is previewed on local copies of the affected terrain
+//! patches, which are put into the terrain storage as previews, and sent to
+//! the simulator of the region as a `ModifyLand` request. The patches the
+//! simulator sends back replace the previews.
+//!
+//! The revert brush returns to the terrain the simulator saved last, which is
+//! not known to the client, so its strokes are only sent and not previewed.
+
+use data::ids;
+use data::region::Region;
+use data::terrain::{PatchPosition, StorageError, TerrainPatch, TerrainStorage};
+use std::f32::consts::PI;
+use std::sync::Mutex;
+use types::{DMatrix, Vector2};
+
+/// Height change in meters per second at the center of the raise, lower and
+/// noise brushes.
+const BRUSH_RATE: f32 = 2.;
+
+/// Fraction per second by which the flatten and smooth brushes move the
+/// heights at their center towards the target.
+const BLEND_RATE: f32 = 4.;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Brush {
+    Flatten,
+    Raise,
+    Lower,
+    Smooth,
+    Noise,
+    Revert,
+}
+
+impl Brush {
+    /// The action of the brush in `ModifyLand` requests.
+    pub fn action(&self) -> u8 {
+        match *self {
+            Brush::Flatten => 0,
+            Brush::Raise => 1,
+            Brush::Lower => 2,
+            Brush::Smooth => 3,
+            Brush::Noise => 4,
+            Brush::Revert => 5,
+        }
+    }
+}
+
+/// One application of a brush.
+#[derive(Clone, Debug)]
+pub struct Stroke {
+    pub brush: Brush,
+    /// Center of the brush, relative to the region.
+    pub center: Vector2<f32>,
+    /// Radius of the brush in meters.
+    pub radius: f32,
+    /// How long the brush is applied, which scales its effect.
+    pub seconds: f32,
+    /// The height flattened to, which is the height at the center when the
+    /// brush was first applied.
+    pub height: f32,
+}
+
+impl Stroke {
+    /// The brush size of the original protocol (small, medium or large),
+    /// for simulators which don't read the radius.
+    pub fn legacy_brush_size(&self) -> u8 {
+        if self.radius <= 1. {
+            0
+        } else if self.radius <= 2. {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Weight of the brush at a point, falling from 1 at the center to 0 at
+    /// the radius.
+    fn weight(&self, x: f32, y: f32) -> f32 {
+        let distance = (Vector2::new(x, y) - self.center).norm();
+        if distance >= self.radius {
+            0.
+        } else {
+            0.5 * (1. + (PI * distance / self.radius).cos())
+        }
+    }
+
+    /// The patches of a region the stroke changes, or reads the heights of
+    /// (for smoothing).
+    pub fn affected_patches(&self, region: &Region) -> Vec<PatchPosition> {
+        let dims = region.dimensions();
+        let size = dims.patch_size_axis as f32;
+        let last = dims.patches_per_side as i64 - 1;
+        let patch = |coordinate: f32| ((coordinate / size).floor() as i64).max(0).min(last);
+
+        let reach = self.radius + 1.;
+        let (x0, x1) = (patch(self.center.x - reach), patch(self.center.x + reach));
+        let (y0, y1) = (patch(self.center.y - reach), patch(self.center.y + reach));
+        let mut positions = Vec::new();
+        for x in x0..x1 + 1 {
+            for y in y0..y1 + 1 {
+                positions.push(Vector2::new(x as u8, y as u8));
+            }
+        }
+        positions
+    }
+}
+
+/// Deterministic value noise in [0, 1] for a grid point.
+fn noise(x: usize, y: usize) -> f32 {
+    let mut n = (x as u32).wrapping_mul(73_856_093) ^ (y as u32).wrapping_mul(19_349_663);
+    n = (n << 13) ^ n;
+    let n = n.wrapping_mul(n.wrapping_mul(n).wrapping_mul(15_731).wrapping_add(789_221))
+        .wrapping_add(1_376_312_589);
+    (n & 0x7fff_ffff) as f32 / 0x7fff_ffff as f32
+}
+
+/// Mean of the known heights around a point, including the point itself.
+fn neighbour_mean(heights: &DMatrix<f32>, x: usize, y: usize) -> f32 {
+    let (rows, cols) = heights.shape();
+    let (mut sum, mut count) = (0., 0);
+    for nx in x.saturating_sub(1)..(x + 2).min(rows) {
+        for ny in y.saturating_sub(1)..(y + 2).min(cols) {
+            let h = heights[(nx, ny)];
+            if h.is_finite() {
+                sum += h;
+                count += 1;
+            }
+        }
+    }
+    sum / count as f32
+}
+
+/// Applies a stroke to a window of the heights of a region, indexed by
+/// `(x, y)` in meters from `origin`. Unknown heights are NaN, and are left
+/// alone, as are all heights by the revert brush.
+pub fn apply(stroke: &Stroke, origin: Vector2<usize>, heights: &mut DMatrix<f32>) {
+    let source = heights.clone();
+    let (rows, cols) = heights.shape();
+    for x in 0..rows {
+        for y in 0..cols {
+            let h = source[(x, y)];
+            let (px, py) = (origin.x + x, origin.y + y);
+            let weight = stroke.weight(px as f32, py as f32);
+            if weight == 0. || !h.is_finite() {
+                continue;
+            }
+
+            let change = weight * stroke.seconds * BRUSH_RATE;
+            let blend = (weight * stroke.seconds * BLEND_RATE).min(1.);
+            heights[(x, y)] = match stroke.brush {
+                Brush::Raise => h + change,
+                Brush::Lower => h - change,
+                Brush::Noise => h + (noise(px, py) * 2. - 1.) * change,
+                Brush::Flatten => h + (stroke.height - h) * blend,
+                Brush::Smooth => h + (neighbour_mean(&source, x, y) - h) * blend,
+                Brush::Revert => h,
+            };
+        }
+    }
+}
+
+/// Previews a stroke on the terrain of a region, patches which were not
+/// received yet are left out. Strokes of the revert brush are not previewed.
+///
+/// Returns the number of patches previewed.
+pub fn preview(
+    storage: &TerrainStorage,
+    region: &Region,
+    stroke: &Stroke,
+) -> Result<usize, StorageError> {
+    if stroke.brush == Brush::Revert {
+        return Ok(0);
+    }
+
+    let size = region.dimensions().patch_size_axis as usize;
+    let positions = stroke.affected_patches(region);
+    let min_x = positions.iter().map(|p| p.x).min().unwrap() as usize;
+    let max_x = positions.iter().map(|p| p.x).max().unwrap() as usize;
+    let min_y = positions.iter().map(|p| p.y).min().unwrap() as usize;
+    let max_y = positions.iter().map(|p| p.y).max().unwrap() as usize;
+    let shape = ((max_x - min_x + 1) * size, (max_y - min_y + 1) * size);
+    let offset = |pos: &PatchPosition| {
+        ((pos.x as usize - min_x) * size, (pos.y as usize - min_y) * size)
+    };
+
+    let mut heights = DMatrix::from_element(shape.0, shape.1, ::std::f32::NAN);
+    let mut present = Vec::new();
+    for pos in positions {
+        let handle = (region.id().clone(), pos);
+        let current = match storage.get_patch(&handle) {
+            Ok(patch) => patch,
+            Err(StorageError::NotFound) | Err(StorageError::Corrupt(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        heights
+            .slice_mut(offset(&pos), (size, size))
+            .copy_from(current.land_heightmap());
+        present.push(pos);
+    }
+
+    apply(stroke, Vector2::new(min_x * size, min_y * size), &mut heights);
+
+    for pos in present.iter() {
+        let land = heights.slice(offset(pos), (size, size)).into_owned();
+        let patch = TerrainPatch::new(region.uuid().clone(), size, *pos, land);
+        storage.put_preview((region.id().clone(), *pos), patch);
+    }
+    Ok(present.len())
+}
+
+/// The strokes which still have to be sent to the simulators.
+pub struct StrokeQueue {
+    strokes: Mutex<Vec<(ids::RegionId, Stroke)>>,
+}
+
+impl StrokeQueue {
+    pub fn new() -> Self {
+        StrokeQueue {
+            strokes: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn push(&self, region_id: ids::RegionId, stroke: Stroke) {
+        self.strokes.lock().unwrap().push((region_id, stroke));
+    }
+
+    /// Removes and returns all queued strokes, oldest first.
+    pub fn take(&self) -> Vec<(ids::RegionId, Stroke)> {
+        ::std::mem::replace(&mut *self.strokes.lock().unwrap(), Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data::config::TerrainConfig;
+    use data::terrain::testing::Fixture;
+
+    fn stroke(brush: Brush) -> Stroke {
+        Stroke {
+            brush,
+            center: Vector2::new(8., 8.),
+            radius: 4.,
+            seconds: 0.25,
+            height: 30.,
+        }
+    }
+
+    fn slope() -> DMatrix<f32> {
+        DMatrix::from_fn(16, 16, |x, _| 20. + x as f32)
+    }
+
+    fn applied(brush: Brush) -> DMatrix<f32> {
+        let mut heights = slope();
+        apply(&stroke(brush), Vector2::new(0, 0), &mut heights);
+        heights
+    }
+
+    #[test]
+    fn raise_and_lower_stay_within_radius() {
+        let raised = applied(Brush::Raise);
+        let lowered = applied(Brush::Lower);
+        let original = slope();
+        assert_eq!(raised[(8, 8)], original[(8, 8)] + 0.25 * BRUSH_RATE);
+        assert_eq!(lowered[(8, 8)], original[(8, 8)] - 0.25 * BRUSH_RATE);
+        assert!(raised[(9, 8)] > original[(9, 8)]);
+        assert_eq!(raised[(12, 8)], original[(12, 8)]);
+        assert_eq!(raised[(0, 0)], original[(0, 0)]);
+    }
+
+    #[test]
+    fn flatten_moves_towards_height() {
+        let flattened = applied(Brush::Flatten);
+        // With a blend of 1 the center reaches the height at once.
+        assert_eq!(flattened[(8, 8)], 30.);
+        assert!(flattened[(6, 8)] > slope()[(6, 8)]);
+        assert!(flattened[(6, 8)] <= 30.);
+    }
+
+    #[test]
+    fn smooth_keeps_planes() {
+        // The mean of the neighbours on a plane is the point itself.
+        assert_eq!(applied(Brush::Smooth), slope());
+    }
+
+    #[test]
+    fn revert_is_not_previewed() {
+        assert_eq!(applied(Brush::Revert), slope());
+
+        let fixture = Fixture::new("terraform-revert", TerrainConfig::default());
+        let region = fixture.region.clone();
+        fixture.put(&region, 0, 0, |_, _| 20.);
+        let previewed = |brush| preview(&fixture.storage, &region, &stroke(brush)).unwrap();
+        assert_eq!(previewed(Brush::Revert), 0);
+        assert_eq!(previewed(Brush::Raise), 1);
+    }
+
+    #[test]
+    fn unknown_heights_are_left_alone() {
+        let mut heights = slope();
+        heights[(8, 8)] = ::std::f32::NAN;
+        apply(&stroke(Brush::Smooth), Vector2::new(0, 0), &mut heights);
+        assert!(heights[(8, 8)].is_nan());
+        assert!(heights[(9, 8)].is_finite());
+    }
+
+    #[test]
+    fn window_origin_is_applied() {
+        let mut heights = slope();
+        apply(&stroke(Brush::Raise), Vector2::new(16, 0), &mut heights);
+        assert_eq!(heights, slope());
+    }
+}
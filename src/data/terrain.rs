@@ -1,12 +1,20 @@
-use cache::TerrainCache;
-use data::avatar::ClientAvatar;
+use cache::{self, CachedRegion, RegionIndex, TerrainCache, TerrainCacheEntry};
+use crossbeam_channel;
+use data::avatar::{Avatar, ClientAvatar};
+use data::region::{Region, RegionStorage};
 use data::{config, ids};
 use failure::Error;
 use parking_lot::RwLock;
+use slog::Logger;
 use std::borrow::Cow;
+use std::cmp;
 use std::collections::HashMap;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use types::{DMatrix, Uuid, Vector2};
+use std::time::{Duration, Instant};
+use types::{DMatrix, Uuid, Vector2, Vector3};
 
 pub type PatchPosition = Vector2<u8>;
 // TODO: Use and check the patch size where appropriate.
@@ -18,53 +26,424 @@ pub enum StorageError {
     #[fail(display = "Patch was not found.")]
     NotFound,
 
-    #[fail(display = "Cache error: {}", 0)]
+    #[fail(display = "Cache error: {:?}", _0)]
     Cache(::simple_disk_cache::CacheError),
+
+    /// The patch of an entry in the disk cache could not be decoded, the
+    /// entry was dropped.
+    #[fail(display = "Corrupt cache entry: {}", _0)]
+    Corrupt(#[cause] ::rmp_serde::decode::Error),
+}
+
+/// Errors of the height queries of `TerrainStorage`.
+#[derive(Debug, Fail)]
+pub enum QueryError {
+    /// The patch covering the queried point is neither in memory nor in the
+    /// disk cache yet.
+    #[fail(display = "Patch {:?} is not loaded.", _0)]
+    NotLoaded(PatchPosition),
+
+    #[fail(display = "Point ({}, {}) is outside of the region.", _0, _1)]
+    OutsideRegion(f32, f32),
+
+    #[fail(display = "Storage error: {}", _0)]
+    Storage(StorageError),
+}
+
+/// A half-line, e.g. from the eye through the mouse cursor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ray {
+    pub origin: Vector3<f32>,
+    /// Unit vector.
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    pub fn at(&self, distance: f32) -> Vector3<f32> {
+        self.origin + self.direction * distance
+    }
 }
 
+/// The point where a ray meets the terrain.
+#[derive(Clone, Debug)]
+pub struct RayHit {
+    pub region_id: ids::RegionId,
+    /// Position relative to the region which was hit.
+    pub rel_pos: Vector3<f32>,
+    /// Position in the coordinates the ray was cast in.
+    pub position: Vector3<f32>,
+    /// Distance from the origin of the ray.
+    pub distance: f32,
+}
+
+/// Maximum number of patches loaded back from the disk cache into memory by
+/// one call of `TerrainStorage::maintain`.
+const MAX_RELOADS_PER_MAINTENANCE: usize = 64;
+
+/// Time after which previews which were not replaced by a patch from the
+/// simulator are discarded, e.g. because it refused to change the terrain.
+const PREVIEW_TIMEOUT_SECS: u64 = 10;
+
 /// The terrain storage manages both the terrain data for patches close
 /// to the client avatar position, and a disk cache for patches further
 /// away.
 pub struct TerrainStorage {
+    config: config::TerrainConfig,
     client_avatar: Arc<RwLock<ClientAvatar>>,
-    // TODO Remove entries once they are too far away from the avatar.
-    //      This could also be implemented in a dedicated method to be
-    //      called from the client update functionality.
-    mem_storage: Mutex<HashMap<PatchHandle, TerrainPatch>>,
+    region_storage: Arc<RegionStorage>,
+    region_ids: Arc<ids::RegionIds>,
+    /// Patches within the draw distance of the client avatar, entries are
+    /// removed by `maintain` once they are too far away.
+    mem_storage: Mutex<HashMap<PatchHandle, Arc<TerrainPatch>>>,
     disk_storage: Mutex<TerrainCache>,
+    /// Locally changed patches, which take the place of the stored ones
+    /// until the simulator sends the changed patch, and when they were put.
+    previews: Mutex<HashMap<PatchHandle, (Instant, Arc<TerrainPatch>)>>,
+    /// The regions present in the disk storage.
+    region_index: Mutex<RegionIndex>,
+    region_index_path: PathBuf,
+
+    /// The generation of each patch put into the storage during this
+    /// session, dropped together with the patch from memory.
+    generations: Mutex<HashMap<PatchHandle, usize>>,
+    /// The last generation assigned.
+    generation_counter: AtomicUsize,
+
+    /// Consumers to be notified about the patches put into the storage.
+    subscribers: Mutex<Vec<crossbeam_channel::Sender<PatchHandle>>>,
+}
+
+/// Result of one `TerrainStorage::maintain` run.
+#[derive(Clone, Debug, Default)]
+pub struct MaintenanceReport {
+    /// Number of patches removed from memory.
+    pub evicted: usize,
+    /// Number of patches loaded from the disk cache into memory.
+    pub reloaded: usize,
+    /// Number of previews discarded as the simulator did not replace them.
+    pub expired_previews: usize,
+}
+
+/// The position of the client avatar and the offsets of the connected
+/// regions relative to its current region.
+struct Viewpoint {
+    position: Vector2<f32>,
+    /// Offset and dimensions of each connected region.
+    regions: HashMap<ids::RegionId, (Vector2<f32>, Region)>,
+}
+
+impl Viewpoint {
+    /// Horizontal distance from the avatar to the closest point of a patch,
+    /// or `None` if the region of the patch is not connected.
+    fn distance(&self, patch_handle: &PatchHandle) -> Option<f32> {
+        let &(ref offset, ref region) = self.regions.get(&patch_handle.0)?;
+        let size = region.dimensions().patch_size_axis as f32;
+        let min = Vector2::new(
+            offset[0] + patch_handle.1[0] as f32 * size,
+            offset[1] + patch_handle.1[1] as f32 * size,
+        );
+
+        let dx = (min[0] - self.position[0])
+            .max(self.position[0] - (min[0] + size))
+            .max(0.);
+        let dy = (min[1] - self.position[1])
+            .max(self.position[1] - (min[1] + size))
+            .max(0.);
+        Some((dx * dx + dy * dy).sqrt())
+    }
+}
+
+/// Approximate number of bytes a patch occupies in memory.
+fn patch_bytes(patch: &TerrainPatch) -> usize {
+    mem::size_of::<TerrainPatch>() + patch.land_heightmap.len() * mem::size_of::<f32>()
+}
+
+/// Orders distances, NaN distances (e.g. of a NaN avatar position) are
+/// treated as equal to all others instead of panicking.
+fn by_distance(a: f32, b: f32) -> cmp::Ordering {
+    a.partial_cmp(&b).unwrap_or(cmp::Ordering::Equal)
 }
 
 impl TerrainStorage {
-    /// Returns true if a patch is withing the relevant distance from client
-    /// avatar to be kept in memory.
-    fn within_range(&self, p_pos: &PatchPosition) -> bool {
-        // TODO implement!!!
-        true
+    /// Returns the current viewpoint, or `None` if the client avatar is not
+    /// in a connected region.
+    fn viewpoint(&self) -> Option<Viewpoint> {
+        let (current_id, rel_pos) = {
+            let avatar = self.client_avatar.read();
+            let current_id = avatar.current_region().clone()?;
+            (current_id, avatar.location().rel_pos)
+        };
+        let current = self.region_storage.get(&current_id).ok()?.clone_region()?;
+
+        let regions = self.region_storage
+            .connected()
+            .into_iter()
+            .map(|region| (region.id().clone(), (region.offset_from(&current), region)))
+            .collect();
+
+        Some(Viewpoint {
+            position: Vector2::new(rel_pos[0], rel_pos[1]),
+            regions,
+        })
+    }
+
+    /// Puts a patch into memory if it is withing the relevant distance from
+    /// the client avatar, evicting patches further away if the memory budget
+    /// would be exceeded otherwise. Returns whether it was put.
+    ///
+    /// As long as the position of the patch can't be determined it is
+    /// treated as closest.
+    fn put_in_memory(&self, patch_handle: PatchHandle, patch: Arc<TerrainPatch>) -> bool {
+        let viewpoint = self.viewpoint();
+        let distance = |handle: &PatchHandle| match viewpoint {
+            Some(ref viewpoint) => viewpoint.distance(handle),
+            None => None,
+        };
+        let own_distance = distance(&patch_handle).unwrap_or(0.);
+        if !(own_distance <= self.config.draw_distance) {
+            return false;
+        }
+
+        let mut evicted = Vec::new();
+        let inserted = {
+            let mut storage = self.mem_storage.lock().unwrap();
+            storage.remove(&patch_handle);
+            let bytes = patch_bytes(&patch);
+            let mut used_bytes: usize = storage.values().map(|p| patch_bytes(p)).sum();
+            if used_bytes + bytes > self.config.memory_budget {
+                // Make room, furthest first.
+                let mut further: Vec<(PatchHandle, f32)> = storage
+                    .keys()
+                    .map(|h| (h.clone(), distance(h).unwrap_or(::std::f32::INFINITY)))
+                    .filter(|&(_, d)| !(d <= own_distance))
+                    .collect();
+                further.sort_by(|a, b| by_distance(b.1, a.1));
+                for (handle, _) in further {
+                    if used_bytes + bytes <= self.config.memory_budget {
+                        break;
+                    }
+                    if let Some(patch) = storage.remove(&handle) {
+                        used_bytes -= patch_bytes(&patch);
+                        evicted.push(handle);
+                    }
+                }
+            }
+            if used_bytes + bytes <= self.config.memory_budget {
+                storage.insert(patch_handle, patch);
+                true
+            } else {
+                false
+            }
+        };
+        self.forget_generations(&evicted);
+        inserted
+    }
+
+    /// Evicts the patches which are out of range or exceed the memory budget
+    /// from memory, and loads the patches which came back into range from the
+    /// disk cache, closest first and in place of patches further away.
+    /// Expired previews are discarded too.
+    ///
+    /// This is to be called regularly while the client avatar moves.
+    pub fn maintain(&self) -> Result<MaintenanceReport, StorageError> {
+        let mut report = MaintenanceReport::default();
+        report.expired_previews = self.expire_previews();
+        let viewpoint = match self.viewpoint() {
+            Some(viewpoint) => viewpoint,
+            None => return Ok(report),
+        };
+        let draw_distance = self.config.draw_distance;
+        let budget = self.config.memory_budget;
+
+        // Evict, keeping the closest patches within the budget.
+        let mut used_bytes = 0;
+        let mut kept = Vec::new();
+        let mut evicted = Vec::new();
+        {
+            let mut storage = self.mem_storage.lock().unwrap();
+            let mut entries: Vec<(PatchHandle, f32, usize)> = storage
+                .iter()
+                .map(|(handle, patch)| {
+                    let distance = viewpoint.distance(handle).unwrap_or(::std::f32::INFINITY);
+                    (handle.clone(), distance, patch_bytes(patch))
+                })
+                .collect();
+            entries.sort_by(|a, b| by_distance(a.1, b.1));
+
+            for (handle, distance, bytes) in entries {
+                if distance <= draw_distance && used_bytes + bytes <= budget {
+                    used_bytes += bytes;
+                    kept.push((handle, distance, bytes));
+                } else {
+                    storage.remove(&handle);
+                    evicted.push(handle);
+                }
+            }
+        }
+
+        // Find the patches in range which are not in memory, closest first.
+        let mut missing = Vec::new();
+        {
+            let storage = self.mem_storage.lock().unwrap();
+            for (region_id, &(_, ref region)) in viewpoint.regions.iter() {
+                let pps = region.dimensions().patches_per_side as usize;
+                for x in 0..pps {
+                    for y in 0..pps {
+                        let handle = (region_id.clone(), Vector2::new(x as u8, y as u8));
+                        if storage.contains_key(&handle) {
+                            continue;
+                        }
+                        match viewpoint.distance(&handle) {
+                            Some(distance) if distance <= draw_distance => {
+                                missing.push((handle, distance))
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+        missing.sort_by(|a, b| by_distance(a.1, b.1));
+
+        // Reload them from the disk cache, as far as the budget allows,
+        // replacing patches in memory which are further away.
+        for (handle, distance) in missing.into_iter().take(MAX_RELOADS_PER_MAINTENANCE) {
+            let patch = match self.get_from_disk(&handle) {
+                Ok(Some(patch)) => patch,
+                Ok(None) | Err(StorageError::Corrupt(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            let bytes = patch_bytes(&patch);
+            let mut storage = self.mem_storage.lock().unwrap();
+            while used_bytes + bytes > budget {
+                match kept.last() {
+                    Some(&(_, furthest, _)) if furthest > distance => {}
+                    _ => break,
+                }
+                let (handle, _, bytes) = kept.pop().unwrap();
+                storage.remove(&handle);
+                evicted.push(handle);
+                used_bytes -= bytes;
+            }
+            if used_bytes + bytes > budget {
+                break;
+            }
+            used_bytes += bytes;
+            storage.insert(handle, Arc::new(patch));
+            report.reloaded += 1;
+        }
+
+        self.forget_generations(&evicted);
+        report.evicted = evicted.len();
+        Ok(report)
     }
 
     pub fn new(
         paths: &config::Paths,
+        config: config::TerrainConfig,
+        client_avatar: Arc<RwLock<ClientAvatar>>,
+        region_storage: Arc<RegionStorage>,
+        region_ids: Arc<ids::RegionIds>,
+        logger: &Logger,
+    ) -> Result<Self, Error> {
+        Self::open(
+            paths.terrain_cache(),
+            paths.terrain_region_index(),
+            config,
+            client_avatar,
+            region_storage,
+            region_ids,
+            logger,
+        )
+    }
+
+    /// Like `new`, but with the disk storage and its region index at the
+    /// specified paths.
+    pub fn open(
+        cache_dir: PathBuf,
+        region_index_path: PathBuf,
+        config: config::TerrainConfig,
         client_avatar: Arc<RwLock<ClientAvatar>>,
+        region_storage: Arc<RegionStorage>,
+        region_ids: Arc<ids::RegionIds>,
+        logger: &Logger,
     ) -> Result<Self, Error> {
         use simple_disk_cache as sdc;
 
         // Setup disk cache.
-        let config = sdc::config::CacheConfig {
+        let cache_config = sdc::config::CacheConfig {
             // 128 MiB (TODO)
             max_bytes: 128 * 1024 * 1024,
             encoding: sdc::config::DataEncoding::Bincode,
             strategy: sdc::config::CacheStrategy::LRU,
             subdirs_per_level: 20,
         };
-        let disk_storage = TerrainCache::initialize(paths.terrain_cache(), config)?;
+        let disk_storage = TerrainCache::initialize(cache_dir, cache_config)?;
+        let region_index = RegionIndex::load(&region_index_path, logger)?;
 
         Ok(TerrainStorage {
+            config,
             client_avatar,
+            region_storage,
+            region_ids,
             mem_storage: Mutex::new(HashMap::new()),
             disk_storage: Mutex::new(disk_storage),
+            previews: Mutex::new(HashMap::new()),
+            region_index: Mutex::new(region_index),
+            region_index_path,
+            generations: Mutex::new(HashMap::new()),
+            generation_counter: AtomicUsize::new(0),
+            subscribers: Mutex::new(Vec::new()),
         })
     }
 
+    /// Records a region in the index of the disk storage, so its terrain can
+    /// be found again without a simulator.
+    pub fn register_region(&self, region: &Region) -> Result<(), Error> {
+        let mut index = self.region_index.lock().unwrap();
+        if index.insert(region.uuid(), CachedRegion::from_region(region)) {
+            index.save(&self.region_index_path)?;
+        }
+        Ok(())
+    }
+
+    /// Drops everything kept in memory for a region which is no longer
+    /// connected, i.e. its patches, previews and generations.
+    pub fn disconnect_region(&self, region_id: &ids::RegionId) {
+        self.mem_storage
+            .lock()
+            .unwrap()
+            .retain(|handle, _| handle.0 != *region_id);
+        self.previews
+            .lock()
+            .unwrap()
+            .retain(|handle, _| handle.0 != *region_id);
+        self.generations
+            .lock()
+            .unwrap()
+            .retain(|handle, _| handle.0 != *region_id);
+    }
+
+    /// Returns the regions whose terrain is (at least partly) in the disk
+    /// storage.
+    pub fn cached_regions(&self) -> Vec<(Uuid, CachedRegion)> {
+        self.region_index.lock().unwrap().regions()
+    }
+
+    /// Returns a channel receiving the handle of every patch which is put
+    /// into the storage from now on.
+    pub fn subscribe(&self) -> crossbeam_channel::Receiver<PatchHandle> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    fn notify(&self, patch_handle: &PatchHandle) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        // Sending only fails if the receiver was dropped.
+        subscribers.retain(|tx| tx.send(patch_handle.clone()).is_ok());
+    }
+
     pub fn put_patch(
         &self,
         region: ids::RegionId,
@@ -73,54 +452,581 @@ impl TerrainStorage {
     ) -> Result<(), StorageError> {
         // Store to disk in any case.
         {
+            let entry = TerrainCacheEntry::new(&patch, self.region_cache_id(&region));
             let mut storage = self.disk_storage.lock().unwrap();
             storage
-                .put(&(region, patch_pos), &patch)
+                .put(&(patch.region.clone(), patch_pos), &entry)
                 .map_err(|e| StorageError::Cache(e))?;
         }
 
         // Store in memory if within relevant distance from avatar.
-        if self.within_range(&patch_pos) {
-            let mut storage = self.mem_storage.lock().unwrap();
-            storage.insert((region, patch_pos), patch);
-        }
+        self.put_in_memory((region, patch_pos), Arc::new(patch));
 
+        // The patch from the simulator replaces any preview.
+        self.previews.lock().unwrap().remove(&(region, patch_pos));
+        self.changed(&(region, patch_pos));
         Ok(())
     }
 
+    /// Assigns a patch a new generation if it is in memory or has a preview,
+    /// otherwise it is back at generation 0, and notifies the subscribers.
+    fn changed(&self, patch_handle: &PatchHandle) {
+        let in_memory = self.previews.lock().unwrap().contains_key(patch_handle)
+            || self.mem_storage.lock().unwrap().contains_key(patch_handle);
+        let mut generations = self.generations.lock().unwrap();
+        if in_memory {
+            let generation = self.generation_counter.fetch_add(1, Ordering::SeqCst) + 1;
+            generations.insert(patch_handle.clone(), generation);
+        } else {
+            generations.remove(patch_handle);
+        }
+        drop(generations);
+        self.notify(patch_handle);
+    }
+
+    /// Puts a locally changed patch, which is returned instead of the stored
+    /// patch until the next `put_patch` of the same patch.
+    pub fn put_preview(&self, patch_handle: PatchHandle, patch: TerrainPatch) {
+        self.previews
+            .lock()
+            .unwrap()
+            .insert(patch_handle.clone(), (Instant::now(), Arc::new(patch)));
+        self.changed(&patch_handle);
+    }
+
+    /// Discards the previews of a region, returning to the stored patches.
+    pub fn discard_previews(&self, region_id: &ids::RegionId) {
+        let discarded: Vec<PatchHandle> = {
+            let mut previews = self.previews.lock().unwrap();
+            let handles = previews
+                .keys()
+                .filter(|handle| handle.0 == *region_id)
+                .cloned()
+                .collect();
+            previews.retain(|handle, _| handle.0 != *region_id);
+            handles
+        };
+        for handle in discarded.iter() {
+            self.changed(handle);
+        }
+    }
+
+    /// Discards the previews older than the timeout, returning their number.
+    fn expire_previews(&self) -> usize {
+        let timeout = Duration::from_secs(PREVIEW_TIMEOUT_SECS);
+        let expired: Vec<PatchHandle> = {
+            let mut previews = self.previews.lock().unwrap();
+            let handles = previews
+                .iter()
+                .filter(|&(_, &(put, _))| put.elapsed() >= timeout)
+                .map(|(handle, _)| handle.clone())
+                .collect();
+            previews.retain(|_, &mut (put, _)| put.elapsed() < timeout);
+            handles
+        };
+        for handle in expired.iter() {
+            self.changed(handle);
+        }
+        expired.len()
+    }
+
+    pub fn config(&self) -> &config::TerrainConfig {
+        &self.config
+    }
+
+    /// Returns the generation of a patch, which changes every time a patch
+    /// is put into the storage, so consumers can tell whether a patch changed
+    /// since they last saw it. It has to be read before the patch, so a
+    /// patch put in between is seen as changed the next time.
+    ///
+    /// Patches which are neither in memory nor previewed (i.e. patches only
+    /// present in the disk cache) have generation 0.
+    pub fn generation(&self, patch_handle: &PatchHandle) -> usize {
+        let generations = self.generations.lock().unwrap();
+        generations.get(patch_handle).cloned().unwrap_or(0)
+    }
+
+    /// Drops the generations of patches evicted from memory, except of the
+    /// previewed ones. This locks the previews, so it must not be called
+    /// while the memory storage is locked.
+    fn forget_generations(&self, patch_handles: &[PatchHandle]) {
+        let previews = self.previews.lock().unwrap();
+        let mut generations = self.generations.lock().unwrap();
+        for handle in patch_handles {
+            if !previews.contains_key(handle) {
+                generations.remove(handle);
+            }
+        }
+    }
+
+    /// Returns a patch, or its preview if there is one.
     pub fn get_patch(
         &self,
         patch_handle: &PatchHandle,
         /* TODO */
         /* patch_size: &PatchSize, */
-    ) -> Result<TerrainPatch, StorageError> {
+    ) -> Result<Arc<TerrainPatch>, StorageError> {
+        if let Some(&(_, ref patch)) = self.previews.lock().unwrap().get(patch_handle) {
+            return Ok(Arc::clone(patch));
+        }
+        self.get_received_patch(patch_handle)
+    }
+
+    /// Returns a patch as last received from the simulator, ignoring any
+    /// preview.
+    pub fn get_received_patch(
+        &self,
+        patch_handle: &PatchHandle,
+    ) -> Result<Arc<TerrainPatch>, StorageError> {
         // Check in memory storage first.
         {
             let storage = self.mem_storage.lock().unwrap();
             if let Some(patch) = storage.get(patch_handle) {
-                // TODO: Avoid this clone. Maybe the mem_storage should
-                // hold Arcs (or better Rcs) to the actual data instead of the patches
-                // directly?
-                return Ok(patch.clone());
+                return Ok(Arc::clone(patch));
             }
         }
 
         // Check disk storage if it was not found in memory.
-        let mut storage = self.disk_storage.lock().unwrap();
-        let res = storage
+        match self.get_from_disk(patch_handle)? {
+            Some(patch) => Ok(Arc::new(patch)),
+            None => Err(StorageError::NotFound),
+        }
+    }
+
+    /// Returns a patch, or its preview if there is one, if it is in memory.
+    /// The disk cache is not looked into.
+    fn get_from_memory(&self, patch_handle: &PatchHandle) -> Option<Arc<TerrainPatch>> {
+        if let Some(&(_, ref patch)) = self.previews.lock().unwrap().get(patch_handle) {
+            return Some(Arc::clone(patch));
+        }
+        self.mem_storage
+            .lock()
+            .unwrap()
             .get(patch_handle)
-            .map_err(|e| StorageError::Cache(e))?;
+            .map(Arc::clone)
+    }
 
-        match res {
-            Some(patch) => Ok(patch),
-            None => Err(StorageError::NotFound),
+    /// Looks up many patches at once, the results are in the order of the
+    /// handles.
+    ///
+    /// This only locks the memory storage once for all patches. Previews are
+    /// returned in place of the stored patches, like by `get_patch`.
+    pub fn get_patches(
+        &self,
+        patch_handles: &[PatchHandle],
+    ) -> Vec<Result<Arc<TerrainPatch>, StorageError>> {
+        self.lookup_patches(patch_handles, true)
+    }
+
+    /// Looks up many patches at once like `get_patches`, but as last
+    /// received from the simulator, ignoring any previews.
+    pub fn get_received_patches(
+        &self,
+        patch_handles: &[PatchHandle],
+    ) -> Vec<Result<Arc<TerrainPatch>, StorageError>> {
+        self.lookup_patches(patch_handles, false)
+    }
+
+    fn lookup_patches(
+        &self,
+        patch_handles: &[PatchHandle],
+        with_previews: bool,
+    ) -> Vec<Result<Arc<TerrainPatch>, StorageError>> {
+        let in_memory: Vec<Option<Arc<TerrainPatch>>> = {
+            let previews = self.previews.lock().unwrap();
+            let storage = self.mem_storage.lock().unwrap();
+            patch_handles
+                .iter()
+                .map(|handle| match previews.get(handle) {
+                    Some(&(_, ref patch)) if with_previews => Some(Arc::clone(patch)),
+                    _ => storage.get(handle).map(Arc::clone),
+                })
+                .collect()
+        };
+
+        patch_handles
+            .iter()
+            .zip(in_memory.into_iter())
+            .map(|(handle, patch)| match patch {
+                Some(patch) => Ok(patch),
+                None => match self.get_from_disk(handle)? {
+                    Some(patch) => Ok(Arc::new(patch)),
+                    None => Err(StorageError::NotFound),
+                },
+            })
+            .collect()
+    }
+
+    /// Returns the cache id of a connected region, or nil if it is unknown.
+    fn region_cache_id(&self, region_id: &ids::RegionId) -> Uuid {
+        self.region_storage
+            .get(region_id)
+            .ok()
+            .and_then(|connection| connection.clone_region())
+            .map(|region| region.cache_id().clone())
+            .unwrap_or_else(Uuid::nil)
+    }
+
+    /// The disk cache is keyed by the persistent region id.
+    ///
+    /// Entries of another format version, older than the configured maximum
+    /// age, or of an outdated region cache id are dropped and treated as
+    /// missing. Entries whose patch can not be decoded are dropped too, but
+    /// reported as `Corrupt`. Errors reading the entry itself are reported
+    /// as `Cache` and leave it alone, as they can be transient.
+    fn get_from_disk(
+        &self,
+        patch_handle: &PatchHandle,
+    ) -> Result<Option<TerrainPatch>, StorageError> {
+        let region_uuid = match self.region_ids.uuid(&patch_handle.0) {
+            Some(uuid) => uuid,
+            None => return Ok(None),
+        };
+        let cache_id = self.region_cache_id(&patch_handle.0);
+        let key = (region_uuid, patch_handle.1);
+
+        let mut storage = self.disk_storage.lock().unwrap();
+        let drop_entry = |storage: &mut TerrainCache| {
+            storage
+                .put(&key, &TerrainCacheEntry::dropped())
+                .map_err(|e| StorageError::Cache(e))
+        };
+
+        // An entry whose header can't be read, e.g. one written before the
+        // header was introduced, is dropped like an outdated one.
+        let entry = match storage.get(&key) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return Ok(None),
+            Err(_) => {
+                drop_entry(&mut *storage)?;
+                return Ok(None);
+            }
+        };
+        if entry.is_dropped() {
+            return Ok(None);
+        }
+
+        let outdated = entry.format_version != cache::TERRAIN_FORMAT_VERSION
+            || entry.age() > self.config.max_cache_age.as_secs()
+            || (!cache_id.is_nil() && !entry.region_cache_id.is_nil()
+                && entry.region_cache_id != cache_id);
+        if outdated {
+            drop_entry(&mut *storage)?;
+            return Ok(None);
+        }
+
+        match entry.decode() {
+            Ok(patch) => Ok(Some(patch)),
+            Err(e) => {
+                drop_entry(&mut *storage)?;
+                Err(StorageError::Corrupt(e))
+            }
+        }
+    }
+
+    /// Returns the terrain height at the region relative position `(x, y)`
+    /// (in meters), bilinearly interpolated between the grid points.
+    pub fn height_at(&self, region: &Region, x: f32, y: f32) -> Result<f32, QueryError> {
+        let (heights, fx, fy) = self.cell_at(region, x, y)?;
+        Ok(interpolate_height(&heights, fx, fy))
+    }
+
+    /// Returns the upward facing unit normal of the terrain at the region
+    /// relative position `(x, y)` (in meters).
+    pub fn normal_at(&self, region: &Region, x: f32, y: f32) -> Result<Vector3<f32>, QueryError> {
+        let (heights, fx, fy) = self.cell_at(region, x, y)?;
+        Ok(interpolate_normal(&heights, fx, fy))
+    }
+
+    /// Casts a ray against the terrain of the connected regions, in the
+    /// coordinates of `current`, up to `max_distance` meters.
+    ///
+    /// The grid cells are walked along the ray, cells of patches which are
+    /// not in memory are passed through. Returns the first point where the
+    /// ray enters the terrain, or `None` if it doesn't within the distance.
+    pub fn cast_ray(
+        &self,
+        current: &Region,
+        ray: &Ray,
+        max_distance: f32,
+    ) -> Result<Option<RayHit>, QueryError> {
+        let mut lookup = HeightLookup::new(current, &self.region_storage, |handle: &PatchHandle| {
+            Ok(self.get_from_memory(handle))
+        });
+
+        traverse_cells(ray, max_distance, |x, y, t0, t1| {
+            let heights = match lookup.cell_heights(x, y).map_err(QueryError::Storage)? {
+                Some(heights) => heights,
+                None => return Ok(None),
+            };
+
+            let cell_origin = Vector3::new(x as f32, y as f32, 0.);
+            let start = ray.at(t0) - cell_origin;
+            let end = ray.at(t1) - cell_origin;
+            let s = match intersect_cell(&heights, &start, &end) {
+                Some(s) => s,
+                None => return Ok(None),
+            };
+            let (region_id, offset) = match lookup.region_at(x, y) {
+                Some((region, _, _)) => (region.id().clone(), region.offset_from(current)),
+                None => return Ok(None),
+            };
+            let distance = t0 + (t1 - t0) * s;
+            let position = ray.at(distance);
+            Ok(Some(RayHit {
+                region_id,
+                rel_pos: position - Vector3::new(offset.x, offset.y, 0.),
+                position,
+                distance,
+            }))
+        })
+    }
+
+    /// Looks up the grid cell containing the point `(x, y)`.
+    ///
+    /// Returns the heights of the corners of the cell (see `CellHeights`)
+    /// and the fractional position of the point inside of the cell.
+    fn cell_at(
+        &self,
+        region: &Region,
+        x: f32,
+        y: f32,
+    ) -> Result<(CellHeights, f32, f32), QueryError> {
+        let dims = region.dimensions();
+        let side = dims.side_meters as f32;
+        if !(x >= 0. && y >= 0. && x < side && y < side) {
+            return Err(QueryError::OutsideRegion(x, y));
+        }
+
+        let x0 = x.floor() as usize;
+        let y0 = y.floor() as usize;
+        let mut lookup = HeightLookup::new(region, &self.region_storage, |handle: &PatchHandle| {
+            match self.get_patch(handle) {
+                Ok(patch) => Ok(Some(patch)),
+                Err(StorageError::NotFound) | Err(StorageError::Corrupt(_)) => Ok(None),
+                Err(e) => Err(e),
+            }
+        });
+        match lookup
+            .cell_heights(x0 as i64, y0 as i64)
+            .map_err(QueryError::Storage)?
+        {
+            Some(heights) => Ok((heights, x - x0 as f32, y - y0 as f32)),
+            None => {
+                let size = dims.patch_size_axis as usize;
+                Err(QueryError::NotLoaded(Vector2::new((x0 / size) as u8, (y0 / size) as u8)))
+            }
         }
     }
 }
 
+/// Looks up the heights of the grid points around a region, in its
+/// coordinates, for the height queries and ray casts.
+///
+/// Each patch is looked up only once, with `get_patch`, which returns `None`
+/// for patches which are not loaded. The neighbouring regions are only looked
+/// up once a point outside of the region is queried.
+struct HeightLookup<'a, F> {
+    current: &'a Region,
+    region_storage: &'a RegionStorage,
+    /// The connected regions covering each grid cell of 256 meters.
+    regions: Option<HashMap<Vector2<i64>, Region>>,
+    get_patch: F,
+    patches: HashMap<PatchHandle, Option<Arc<TerrainPatch>>>,
+}
+
+impl<'a, F> HeightLookup<'a, F>
+where
+    F: FnMut(&PatchHandle) -> Result<Option<Arc<TerrainPatch>>, StorageError>,
+{
+    fn new(current: &'a Region, region_storage: &'a RegionStorage, get_patch: F) -> Self {
+        HeightLookup {
+            current,
+            region_storage,
+            regions: None,
+            get_patch,
+            patches: HashMap::new(),
+        }
+    }
+
+    /// Returns the region containing the grid point `(x, y)`, and the
+    /// position of the point relative to it.
+    fn region_at(&mut self, x: i64, y: i64) -> Option<(&Region, usize, usize)> {
+        let side = self.current.dimensions().side_meters as i64;
+        if x >= 0 && y >= 0 && x < side && y < side {
+            return Some((self.current, x as usize, y as usize));
+        }
+
+        if self.regions.is_none() {
+            let mut regions = HashMap::new();
+            for region in self.region_storage.connected() {
+                for cell in region.grid_cells_from(self.current) {
+                    regions.insert(cell, region.clone());
+                }
+            }
+            self.regions = Some(regions);
+        }
+        let current = self.current;
+        let grid_cell = Vector2::new(
+            (x as f32 / 256.).floor() as i64,
+            (y as f32 / 256.).floor() as i64,
+        );
+        let region = self.regions.as_ref().unwrap().get(&grid_cell)?;
+        let offset = region.offset_from(current);
+        let (rx, ry) = (x - offset.x as i64, y - offset.y as i64);
+        Some((region, rx as usize, ry as usize))
+    }
+
+    /// Returns the patch containing the grid point `(x, y)` if it is loaded,
+    /// and the position of the point in the patch.
+    fn patch_at(
+        &mut self,
+        x: i64,
+        y: i64,
+    ) -> Result<Option<(Arc<TerrainPatch>, usize, usize)>, StorageError> {
+        let (handle, px, py) = match self.region_at(x, y) {
+            Some((region, rx, ry)) => {
+                let size = region.dimensions().patch_size_axis as usize;
+                let patch_pos = Vector2::new((rx / size) as u8, (ry / size) as u8);
+                ((region.id().clone(), patch_pos), rx % size, ry % size)
+            }
+            None => return Ok(None),
+        };
+        if !self.patches.contains_key(&handle) {
+            let patch = (self.get_patch)(&handle)?;
+            self.patches.insert(handle.clone(), patch);
+        }
+        Ok(self.patches[&handle]
+            .as_ref()
+            .map(|patch| (Arc::clone(patch), px, py)))
+    }
+
+    /// Returns the heights of the four corners of the grid cell with the
+    /// lower corner `(x0, y0)`, or `None` if the patch containing that corner
+    /// is not loaded.
+    ///
+    /// The corners at the far edges of a patch are read from the
+    /// neighbouring patch, possibly of a neighbouring region. If that is not
+    /// loaded they take the height of the closest grid point of the cell's
+    /// patch, just like the terrain is rendered.
+    fn cell_heights(&mut self, x0: i64, y0: i64) -> Result<Option<CellHeights>, StorageError> {
+        let (patch, px, py) = match self.patch_at(x0, y0)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let heightmap = patch.land_heightmap();
+        let (rows, cols) = heightmap.shape();
+
+        let mut heights = [0.; 4];
+        for (i, &(dx, dy)) in CELL_CORNERS.iter().enumerate() {
+            heights[i] = match self.patch_at(x0 + dx as i64, y0 + dy as i64)? {
+                Some((corner_patch, cx, cy)) => corner_patch.land_heightmap()[(cx, cy)],
+                None => heightmap[((px + dx).min(rows - 1), (py + dy).min(cols - 1))],
+            };
+        }
+        Ok(Some(heights))
+    }
+}
+
+/// Walks the grid cells of one meter crossed by the horizontal projection of
+/// a ray, up to `max_distance`, calling `visit` with the cell and the
+/// distances at which the ray enters and leaves it, until it returns a
+/// result.
+fn traverse_cells<T, E, F>(ray: &Ray, max_distance: f32, mut visit: F) -> Result<Option<T>, E>
+where
+    F: FnMut(i64, i64, f32, f32) -> Result<Option<T>, E>,
+{
+    // Distance along the ray to the next cell border, and between borders,
+    // for one axis.
+    let axis = |origin: f32, direction: f32| -> (i64, f32, f32) {
+        let cell = origin.floor();
+        if direction > 0. {
+            (1, (cell + 1. - origin) / direction, 1. / direction)
+        } else if direction < 0. {
+            (-1, (cell - origin) / direction, -1. / direction)
+        } else {
+            (0, ::std::f32::INFINITY, ::std::f32::INFINITY)
+        }
+    };
+    let (step_x, mut next_x, delta_x) = axis(ray.origin.x, ray.direction.x);
+    let (step_y, mut next_y, delta_y) = axis(ray.origin.y, ray.direction.y);
+
+    let mut x = ray.origin.x.floor() as i64;
+    let mut y = ray.origin.y.floor() as i64;
+    let mut t = 0.;
+    while t < max_distance {
+        let t_exit = next_x.min(next_y).min(max_distance);
+        if let Some(result) = visit(x, y, t, t_exit)? {
+            return Ok(Some(result));
+        }
+        t = t_exit;
+        if next_x < next_y {
+            x += step_x;
+            next_x += delta_x;
+        } else {
+            y += step_y;
+            next_y += delta_y;
+        }
+    }
+    Ok(None)
+}
+
+/// Intersects the segment from `start` to `end` (relative to the lower corner
+/// of a grid cell, and within it) with the bilinear surface of the cell.
+///
+/// Returns the fraction of the segment at which it first gets below the
+/// surface, which is 0 if it starts below it.
+fn intersect_cell(h: &CellHeights, start: &Vector3<f32>, end: &Vector3<f32>) -> Option<f32> {
+    // The height above the surface along the segment is a quadratic
+    // polynomial q2 * s^2 + q1 * s + q0 of the fraction s.
+    let d = end - start;
+    let (a, b, c) = (h[1] - h[0], h[2] - h[0], h[0] - h[1] - h[2] + h[3]);
+    let q0 = start.z - (h[0] + a * start.x + b * start.y + c * start.x * start.y);
+    let q1 = d.z - (a * d.x + b * d.y + c * (start.x * d.y + start.y * d.x));
+    let q2 = -c * d.x * d.y;
+
+    if q0 <= 0. {
+        return Some(0.);
+    }
+    let in_segment = |s: f32| if s >= 0. && s <= 1. { Some(s) } else { None };
+    if q2.abs() < 1e-6 {
+        if q1 < 0. {
+            return in_segment(-q0 / q1);
+        }
+        return None;
+    }
+
+    let discriminant = q1 * q1 - 4. * q2 * q0;
+    if discriminant < 0. {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let (s0, s1) = ((-q1 - root) / (2. * q2), (-q1 + root) / (2. * q2));
+    in_segment(s0.min(s1)).or_else(|| in_segment(s0.max(s1)))
+}
+
+/// Heights of the corners of a grid cell, in the order of `CELL_CORNERS`.
+type CellHeights = [f32; 4];
+
+/// Offsets of the corners of a grid cell from its lower corner.
+const CELL_CORNERS: [(usize, usize); 4] = [(0, 0), (1, 0), (0, 1), (1, 1)];
+
+fn interpolate_height(h: &CellHeights, fx: f32, fy: f32) -> f32 {
+    let h_y0 = h[0] * (1. - fx) + h[1] * fx;
+    let h_y1 = h[2] * (1. - fx) + h[3] * fx;
+    h_y0 * (1. - fy) + h_y1 * fy
+}
+
+/// The normal of the bilinear surface spanned by the cell.
+fn interpolate_normal(h: &CellHeights, fx: f32, fy: f32) -> Vector3<f32> {
+    let dh_dx = (h[1] - h[0]) * (1. - fy) + (h[3] - h[2]) * fy;
+    let dh_dy = (h[2] - h[0]) * (1. - fx) + (h[3] - h[1]) * fx;
+    Vector3::new(-dh_dx, -dh_dy, 1.).normalize()
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct TerrainPatch {
-    region: ids::RegionId,
+    region: ids::PersistentRegionId,
     size: PatchSize,
     position: PatchPosition,
     land_heightmap: DMatrix<f32>,
@@ -128,7 +1034,7 @@ pub struct TerrainPatch {
 
 impl TerrainPatch {
     pub fn new(
-        region: ids::RegionId,
+        region: ids::PersistentRegionId,
         size: PatchSize,
         position: PatchPosition,
         land_heightmap: DMatrix<f32>,
@@ -141,6 +1047,11 @@ impl TerrainPatch {
         }
     }
 
+    /// Returns the UUID of the region the patch belongs to.
+    pub fn region(&self) -> &ids::PersistentRegionId {
+        &self.region
+    }
+
     /// Returns the size of the patch.
     pub fn size(&self) -> PatchSize {
         self.size
@@ -154,15 +1065,549 @@ impl TerrainPatch {
     pub fn land_heightmap(&self) -> &DMatrix<f32> {
         &self.land_heightmap
     }
+}
 
-    #[deprecated]
-    pub fn dummy() -> Self {
-        let raw_data = include!("./layer_land.png.txt");
-        TerrainPatch {
-            position: Vector2::new(0, 0),
-            region: Uuid::nil(),
-            size: 256,
-            land_heightmap: DMatrix::from_fn(256, 256, |x, y| raw_data[x][y]),
+/// A terrain storage on a fresh disk cache, for tests.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use data::test_uuid;
+    use data::region::{Connection, RegionDimensions};
+    use std::{env, fs, process};
+
+    pub struct Fixture {
+        pub storage: TerrainStorage,
+        pub client_avatar: Arc<RwLock<ClientAvatar>>,
+        pub region_storage: Arc<RegionStorage>,
+        pub region_ids: Arc<ids::RegionIds>,
+        /// The region the client avatar is in, at grid location (1000, 1000).
+        pub region: Region,
+        dir: PathBuf,
+    }
+
+    impl Fixture {
+        /// Creates the storage in a directory named after the test, so
+        /// tests running at the same time don't share their disk cache.
+        pub fn new(name: &str, config: config::TerrainConfig) -> Self {
+            let dir = env::temp_dir().join(format!("opensim-client-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            let region_storage = Arc::new(RegionStorage::new());
+            let region_ids = Arc::new(ids::RegionIds::new());
+            let region = connect(&region_storage, &region_ids, 1, Vector2::new(1000, 1000));
+            let mut avatar = ClientAvatar::new(None);
+            avatar.enter_region(region.id().clone(), Some(Vector3::new(8., 8., 30.)));
+            let client_avatar = Arc::new(RwLock::new(avatar));
+            let storage = TerrainStorage::open(
+                dir.join("terrain"),
+                dir.join("terrain_regions.json"),
+                config,
+                Arc::clone(&client_avatar),
+                Arc::clone(&region_storage),
+                Arc::clone(&region_ids),
+                &Logger::root(::slog::Discard, o!()),
+            ).unwrap();
+
+            Fixture {
+                storage,
+                client_avatar,
+                region_storage,
+                region_ids,
+                region,
+                dir,
+            }
+        }
+
+        /// Connects a region of 256 meters with a UUID ending in `n`.
+        pub fn connect(&self, n: u8, grid_location: Vector2<u32>) -> Region {
+            connect(&self.region_storage, &self.region_ids, n, grid_location)
+        }
+
+        /// Puts a patch of a region into the storage, with the heights given
+        /// by a function of the region relative position.
+        pub fn put<F>(&self, region: &Region, x: u8, y: u8, height: F)
+        where
+            F: Fn(usize, usize) -> f32,
+        {
+            let (x0, y0) = (x as usize * 16, y as usize * 16);
+            let land = DMatrix::from_fn(16, 16, |i, j| height(x0 + i, y0 + j));
+            let pos = Vector2::new(x, y);
+            let patch = TerrainPatch::new(region.uuid().clone(), 16, pos, land);
+            self.storage
+                .put_patch(region.id().clone(), pos, patch)
+                .unwrap();
+        }
+
+        pub fn move_avatar(&self, x: f32, y: f32) {
+            self.client_avatar
+                .write()
+                .set_position(Vector3::new(x, y, 30.));
+        }
+
+        pub fn in_memory(&self, region: &Region, x: u8, y: u8) -> bool {
+            let handle = (region.id().clone(), Vector2::new(x, y));
+            self.storage
+                .mem_storage
+                .lock()
+                .unwrap()
+                .contains_key(&handle)
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn connect(
+        region_storage: &RegionStorage,
+        region_ids: &ids::RegionIds,
+        n: u8,
+        grid_location: Vector2<u32>,
+    ) -> Region {
+        let uuid = test_uuid(n);
+        let id = region_ids.get_or_insert(&uuid);
+        let dims = RegionDimensions::from_size(256, 256).unwrap();
+        let region = Region::new(uuid, id, dims, grid_location);
+        region_storage.put(id, Connection::Connected(region.clone()));
+        region
+    }
+
+    /// The bytes occupied by `n` patches of 16x16 heights.
+    pub fn patches_bytes(n: usize) -> usize {
+        let patch = TerrainPatch::new(Uuid::nil(), 16, Vector2::new(0, 0), DMatrix::zeros(16, 16));
+        n * patch_bytes(&patch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+    use data::region::Connection;
+
+    fn config(draw_distance: f32, patches: usize) -> config::TerrainConfig {
+        config::TerrainConfig {
+            draw_distance,
+            memory_budget: patches_bytes(patches),
+            ..config::TerrainConfig::default()
         }
     }
+
+    #[test]
+    fn viewpoint_distance_is_to_closest_point_of_patch() {
+        let fixture = Fixture::new("viewpoint-distance", config(512., 16));
+        let neighbour = fixture.connect(2, Vector2::new(1001, 1000));
+        let viewpoint = Viewpoint {
+            position: Vector2::new(20., 8.),
+            regions: vec![&fixture.region, &neighbour]
+                .into_iter()
+                .map(|r| (r.id().clone(), (r.offset_from(&fixture.region), r.clone())))
+                .collect(),
+        };
+        let distance = |region: &Region, x, y| {
+            viewpoint.distance(&(region.id().clone(), Vector2::new(x, y)))
+        };
+        assert_eq!(distance(&fixture.region, 1, 0), Some(0.));
+        assert_eq!(distance(&fixture.region, 0, 0), Some(4.));
+        assert_eq!(distance(&fixture.region, 1, 2), Some(24.));
+        assert_eq!(distance(&fixture.region, 3, 3), Some((28f32 * 28. + 40. * 40.).sqrt()));
+        assert_eq!(distance(&neighbour, 0, 0), Some(236.));
+
+        let unknown = fixture.connect(3, Vector2::new(0, 0));
+        assert_eq!(distance(&unknown, 0, 0), None);
+    }
+
+    #[test]
+    fn put_patch_respects_memory_budget() {
+        let fixture = Fixture::new("put-budget", config(512., 2));
+        let region = fixture.region.clone();
+        let flat = |_, _| 20.;
+        fixture.put(&region, 2, 0, flat);
+        fixture.put(&region, 1, 0, flat);
+        // Closer than the others, so the furthest one makes room.
+        fixture.put(&region, 0, 0, flat);
+        assert!(fixture.in_memory(&region, 0, 0));
+        assert!(fixture.in_memory(&region, 1, 0));
+        assert!(!fixture.in_memory(&region, 2, 0));
+        // Further than the others, so it is only put to disk.
+        fixture.put(&region, 3, 0, flat);
+        assert!(!fixture.in_memory(&region, 3, 0));
+        assert!(fixture.storage.get_patch(&(region.id().clone(), Vector2::new(3, 0))).is_ok());
+    }
+
+    #[test]
+    fn put_patch_beyond_draw_distance_stays_on_disk() {
+        let fixture = Fixture::new("put-distance", config(20., 16));
+        let region = fixture.region.clone();
+        fixture.put(&region, 1, 0, |_, _| 20.);
+        fixture.put(&region, 3, 0, |_, _| 20.);
+        assert!(fixture.in_memory(&region, 1, 0));
+        assert!(!fixture.in_memory(&region, 3, 0));
+    }
+
+    #[test]
+    fn maintenance_evicts_furthest_and_reloads_closest() {
+        let fixture = Fixture::new("maintain-order", config(40., 2));
+        let region = fixture.region.clone();
+        for x in 0..3 {
+            fixture.put(&region, x, 0, |_, _| 20.);
+        }
+        assert!(fixture.in_memory(&region, 0, 0) && fixture.in_memory(&region, 1, 0));
+
+        // At the other end the order is reversed: (2, 0) is reloaded in
+        // place of (0, 0), which is furthest away now.
+        fixture.move_avatar(40., 8.);
+        let report = fixture.storage.maintain().unwrap();
+        assert_eq!((report.evicted, report.reloaded), (1, 1));
+        assert!(!fixture.in_memory(&region, 0, 0));
+        assert!(fixture.in_memory(&region, 1, 0));
+        assert!(fixture.in_memory(&region, 2, 0));
+
+        // Out of the draw distance everything is evicted.
+        fixture.move_avatar(200., 200.);
+        let report = fixture.storage.maintain().unwrap();
+        assert_eq!((report.evicted, report.reloaded), (2, 0));
+    }
+
+    #[test]
+    fn generations_are_dropped_with_the_patches() {
+        let fixture = Fixture::new("generations", config(40., 16));
+        let region = fixture.region.clone();
+        let handle = (region.id().clone(), Vector2::new(1, 0));
+        assert_eq!(fixture.storage.generation(&handle), 0);
+        fixture.put(&region, 1, 0, |_, _| 20.);
+        let first = fixture.storage.generation(&handle);
+        fixture.put(&region, 1, 0, |_, _| 21.);
+        let second = fixture.storage.generation(&handle);
+        assert!(first != 0 && second != 0 && second != first);
+
+        // Patches put only to disk have none.
+        let far = (region.id().clone(), Vector2::new(10, 0));
+        fixture.put(&region, 10, 0, |_, _| 20.);
+        assert_eq!(fixture.storage.generation(&far), 0);
+
+        fixture.move_avatar(200., 200.);
+        fixture.storage.maintain().unwrap();
+        assert_eq!(fixture.storage.generation(&handle), 0);
+
+        fixture.move_avatar(8., 8.);
+        fixture.put(&region, 1, 0, |_, _| 22.);
+        fixture.storage.disconnect_region(region.id());
+        assert_eq!(fixture.storage.generation(&handle), 0);
+        assert!(!fixture.in_memory(&region, 1, 0));
+    }
+
+    /// Replaces the disk cache entry of a patch of the fixture region.
+    fn put_entry(fixture: &Fixture, x: u8, y: u8, entry: &TerrainCacheEntry) {
+        let key = (fixture.region.uuid().clone(), Vector2::new(x, y));
+        fixture
+            .storage
+            .disk_storage
+            .lock()
+            .unwrap()
+            .put(&key, entry)
+            .unwrap();
+    }
+
+    /// An entry of the fixture region, as if the patch was received earlier.
+    fn entry(fixture: &Fixture, x: u8, y: u8, region_cache_id: Uuid) -> TerrainCacheEntry {
+        let land = DMatrix::from_element(16, 16, 20.);
+        let patch = TerrainPatch::new(fixture.region.uuid().clone(), 16, Vector2::new(x, y), land);
+        TerrainCacheEntry::new(&patch, region_cache_id)
+    }
+
+    fn read_from_disk(
+        fixture: &Fixture,
+        x: u8,
+        y: u8,
+    ) -> Result<Option<TerrainPatch>, StorageError> {
+        let handle = (fixture.region.id().clone(), Vector2::new(x, y));
+        fixture.storage.get_from_disk(&handle)
+    }
+
+    #[test]
+    fn patches_are_read_back_from_disk_once_disconnected() {
+        let fixture = Fixture::new("disk-round-trip", config(512., 16));
+        let region = fixture.region.clone();
+        fixture.put(&region, 0, 0, |x, y| 20. + x as f32 - y as f32);
+        fixture.storage.disconnect_region(region.id());
+        assert!(!fixture.in_memory(&region, 0, 0));
+
+        let handle = (region.id().clone(), Vector2::new(0, 0));
+        let patch = fixture.storage.get_patch(&handle).unwrap();
+        assert_eq!(patch.land_heightmap()[(3, 1)], 22.);
+        assert_eq!(patch.land_heightmap()[(1, 3)], 18.);
+    }
+
+    #[test]
+    fn disk_entries_are_read_back() {
+        let fixture = Fixture::new("disk-valid", config(512., 16));
+        let e = entry(&fixture, 0, 0, Uuid::nil());
+        put_entry(&fixture, 0, 0, &e);
+        assert!(read_from_disk(&fixture, 0, 0).unwrap().is_some());
+        assert!(read_from_disk(&fixture, 1, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn outdated_disk_entries_are_dropped() {
+        let fixture = Fixture::new("disk-outdated", config(512., 16));
+        let mut old_format = entry(&fixture, 0, 0, Uuid::nil());
+        old_format.format_version = cache::TERRAIN_FORMAT_VERSION + 1;
+        put_entry(&fixture, 0, 0, &old_format);
+
+        let mut expired = entry(&fixture, 1, 0, Uuid::nil());
+        expired.timestamp -= fixture.storage.config().max_cache_age.as_secs() + 60;
+        put_entry(&fixture, 1, 0, &expired);
+
+        for x in 0..2 {
+            assert!(read_from_disk(&fixture, x, 0).unwrap().is_none());
+        }
+        // They were replaced, so they stay dropped.
+        let key = (fixture.region.uuid().clone(), Vector2::new(0, 0));
+        let stored = fixture.storage.disk_storage.lock().unwrap().get(&key).unwrap();
+        assert!(stored.unwrap().is_dropped());
+    }
+
+    #[test]
+    fn entries_of_other_region_cache_ids_are_dropped() {
+        let mut fixture = Fixture::new("disk-cache-id", config(512., 16));
+        let old_id = Uuid::parse_str("00000000-0000-0000-0000-0000000000aa").unwrap();
+        let new_id = Uuid::parse_str("00000000-0000-0000-0000-0000000000bb").unwrap();
+        let e = entry(&fixture, 0, 0, old_id);
+        put_entry(&fixture, 0, 0, &e);
+        let e = entry(&fixture, 1, 0, Uuid::nil());
+        put_entry(&fixture, 1, 0, &e);
+
+        // The region changed since the entry was stored.
+        fixture.region.set_cache_id(new_id);
+        let region = fixture.region.clone();
+        fixture
+            .region_storage
+            .put(region.id().clone(), Connection::Connected(region));
+        assert!(read_from_disk(&fixture, 0, 0).unwrap().is_none());
+        // Entries of unknown cache ids are kept.
+        assert!(read_from_disk(&fixture, 1, 0).unwrap().is_some());
+    }
+
+    #[test]
+    fn corrupt_disk_entries_are_reported_and_dropped() {
+        let fixture = Fixture::new("disk-corrupt", config(512., 16));
+        put_entry(&fixture, 0, 0, &TerrainCacheEntry::corrupt());
+        match read_from_disk(&fixture, 0, 0) {
+            Err(StorageError::Corrupt(_)) => {}
+            _ => panic!("expected a corrupt entry"),
+        }
+        assert!(read_from_disk(&fixture, 0, 0).unwrap().is_none());
+        let handle = (fixture.region.id().clone(), Vector2::new(0, 0));
+        match fixture.storage.get_patch(&handle) {
+            Err(StorageError::NotFound) => {}
+            _ => panic!("expected no patch"),
+        }
+    }
+
+    #[test]
+    fn maintenance_survives_nan_positions() {
+        assert_eq!(by_distance(1., 3.), cmp::Ordering::Less);
+        assert_eq!(by_distance(::std::f32::NAN, 1.), cmp::Ordering::Equal);
+        assert_eq!(by_distance(1., ::std::f32::NAN), cmp::Ordering::Equal);
+
+        let fixture = Fixture::new("maintain-nan", config(512., 16));
+        let region = fixture.region.clone();
+        fixture.put(&region, 0, 0, |_, _| 20.);
+        fixture.move_avatar(::std::f32::NAN, ::std::f32::NAN);
+        fixture.put(&region, 1, 0, |_, _| 20.);
+        assert!(fixture.storage.maintain().is_ok());
+    }
+
+    /// Puts a preview of a flat patch of the fixture region.
+    fn put_preview(fixture: &Fixture, x: u8, y: u8, height: f32) {
+        let pos = Vector2::new(x, y);
+        let land = DMatrix::from_element(16, 16, height);
+        let patch = TerrainPatch::new(fixture.region.uuid().clone(), 16, pos, land);
+        fixture
+            .storage
+            .put_preview((fixture.region.id().clone(), pos), patch);
+    }
+
+    fn height(patch: Result<Arc<TerrainPatch>, StorageError>) -> f32 {
+        patch.unwrap().land_heightmap()[(0, 0)]
+    }
+
+    #[test]
+    fn previews_take_the_place_of_patches() {
+        let fixture = Fixture::new("previews", config(512., 16));
+        let region = fixture.region.clone();
+        let handles: Vec<PatchHandle> = (0..2)
+            .map(|x| (region.id().clone(), Vector2::new(x, 0)))
+            .collect();
+        fixture.put(&region, 0, 0, |_, _| 20.);
+        fixture.put(&region, 1, 0, |_, _| 20.);
+        put_preview(&fixture, 0, 0, 25.);
+
+        assert_eq!(height(fixture.storage.get_patch(&handles[0])), 25.);
+        assert_eq!(height(fixture.storage.get_received_patch(&handles[0])), 20.);
+        let heights: Vec<f32> = fixture
+            .storage
+            .get_patches(&handles)
+            .into_iter()
+            .map(height)
+            .collect();
+        assert_eq!(heights, vec![25., 20.]);
+
+        // The patch from the simulator replaces the preview.
+        fixture.put(&region, 0, 0, |_, _| 21.);
+        assert_eq!(height(fixture.storage.get_patch(&handles[0])), 21.);
+    }
+
+    #[test]
+    fn previews_are_discarded() {
+        let fixture = Fixture::new("previews-discarded", config(512., 16));
+        let region = fixture.region.clone();
+        let handle = (region.id().clone(), Vector2::new(0, 0));
+        fixture.put(&region, 0, 0, |_, _| 20.);
+
+        put_preview(&fixture, 0, 0, 25.);
+        let previewed = fixture.storage.generation(&handle);
+        fixture.storage.discard_previews(region.id());
+        assert_eq!(height(fixture.storage.get_patch(&handle)), 20.);
+        assert!(fixture.storage.generation(&handle) != previewed);
+
+        // Fresh previews survive the maintenance, expired ones don't.
+        put_preview(&fixture, 0, 0, 25.);
+        assert_eq!(fixture.storage.maintain().unwrap().expired_previews, 0);
+        assert_eq!(height(fixture.storage.get_patch(&handle)), 25.);
+        {
+            let mut previews = fixture.storage.previews.lock().unwrap();
+            let preview = previews.get_mut(&handle).unwrap();
+            preview.0 -= Duration::from_secs(PREVIEW_TIMEOUT_SECS + 1);
+        }
+        assert_eq!(fixture.storage.maintain().unwrap().expired_previews, 1);
+        assert_eq!(height(fixture.storage.get_patch(&handle)), 20.);
+    }
+
+    fn ray(origin: (f32, f32, f32), direction: (f32, f32, f32)) -> Ray {
+        Ray {
+            origin: Vector3::new(origin.0, origin.1, origin.2),
+            direction: Vector3::new(direction.0, direction.1, direction.2).normalize(),
+        }
+    }
+
+    fn visited(ray: &Ray, max_distance: f32) -> Vec<(i64, i64)> {
+        let mut cells = Vec::new();
+        let result: Result<Option<()>, ()> = traverse_cells(ray, max_distance, |x, y, _, _| {
+            cells.push((x, y));
+            Ok(None)
+        });
+        assert!(result.unwrap().is_none());
+        cells
+    }
+
+    #[test]
+    fn traversal_follows_the_ray() {
+        let cells = visited(&ray((0.5, 0.5, 10.), (1., 0., 0.)), 3.);
+        assert_eq!(cells, vec![(0, 0), (1, 0), (2, 0), (3, 0)]);
+
+        let cells = visited(&ray((0.5, 0.25, 10.), (-1., -1., 0.)), 2.);
+        assert_eq!(cells, vec![(0, 0), (0, -1), (-1, -1), (-1, -2)]);
+    }
+
+    #[test]
+    fn traversal_looking_down_visits_one_cell() {
+        assert_eq!(visited(&ray((3.5, 7.5, 10.), (0., 0., -1.)), 100.), vec![(3, 7)]);
+    }
+
+    #[test]
+    fn traversal_reports_distances() {
+        let ray = ray((0.5, 0.5, 0.), (1., 0., 0.));
+        let mut distances = Vec::new();
+        let _: Result<Option<()>, ()> = traverse_cells(&ray, 2., |_, _, t0, t1| {
+            distances.push((t0, t1));
+            Ok(None)
+        });
+        assert_eq!(distances, vec![(0., 0.5), (0.5, 1.5), (1.5, 2.)]);
+    }
+
+    #[test]
+    fn segment_hits_flat_cell() {
+        let flat = [10.; 4];
+        let start = Vector3::new(0., 0.5, 12.);
+        let end = Vector3::new(1., 0.5, 8.);
+        assert_eq!(intersect_cell(&flat, &start, &end), Some(0.5));
+        // Above the surface all the way.
+        let end = Vector3::new(1., 0.5, 11.);
+        assert_eq!(intersect_cell(&flat, &start, &end), None);
+        // Starting below the surface.
+        let start = Vector3::new(0., 0.5, 9.);
+        assert_eq!(intersect_cell(&flat, &start, &end), Some(0.));
+    }
+
+    #[test]
+    fn segment_hits_curved_cell() {
+        // A saddle, the surface is at height 1 + (x - 0.5) * (y - 0.5) * 4.
+        let saddle = [2., 0., 0., 2.];
+        let start = Vector3::new(0., 0., 3.);
+        let end = Vector3::new(1., 1., 1.5);
+        let s = intersect_cell(&saddle, &start, &end).unwrap();
+        let point = start + (end - start) * s;
+        let surface = interpolate_height(&saddle, point.x, point.y);
+        assert!((point.z - surface).abs() < 1e-4);
+    }
+
+    #[test]
+    fn ray_crosses_patch_borders() {
+        let fixture = Fixture::new("ray-patches", config(512., 16));
+        let region = fixture.region.clone();
+        fixture.put(&region, 0, 0, |_, _| 20.);
+        fixture.put(&region, 1, 0, |_, _| 30.);
+
+        // Descending half a meter per meter, it meets the patch of 30
+        // meters 2 meters after its border.
+        let r = ray((8., 8., 35.), (1., 0., -0.5));
+        let hit = fixture.storage.cast_ray(&region, &r, 100.).unwrap().unwrap();
+        assert_eq!(hit.region_id, *region.id());
+        assert!((hit.rel_pos.x - 18.).abs() < 1e-3);
+        assert!((hit.rel_pos.z - 30.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn ray_crosses_region_borders() {
+        let fixture = Fixture::new("ray-regions", config(512., 16));
+        let region = fixture.region.clone();
+        let neighbour = fixture.connect(2, Vector2::new(1001, 1000));
+        fixture.put(&region, 15, 0, |_, _| 20.);
+        fixture.put(&neighbour, 0, 0, |_, _| 30.);
+
+        // The last cell of the region rises to the neighbour's edge.
+        let height = fixture.storage.height_at(&region, 255.5, 8.).unwrap();
+        assert!((height - 25.).abs() < 1e-3);
+
+        let r = ray((250., 8., 35.), (1., 0., -0.5));
+        let hit = fixture.storage.cast_ray(&region, &r, 100.).unwrap().unwrap();
+        assert_eq!(hit.region_id, *neighbour.id());
+        assert!((hit.position.x - 260.).abs() < 1e-3);
+        assert!((hit.rel_pos.x - 4.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn ray_passes_unloaded_patches() {
+        let fixture = Fixture::new("ray-unloaded", config(512., 16));
+        let region = fixture.region.clone();
+        fixture.put(&region, 0, 0, |_, _| 20.);
+        fixture.put(&region, 2, 0, |_, _| 23.);
+        // (1, 0) is only in the disk cache, it would be hit at once.
+        let land = DMatrix::from_element(16, 16, 40.);
+        let patch = TerrainPatch::new(region.uuid().clone(), 16, Vector2::new(1, 0), land);
+        put_entry(&fixture, 1, 0, &TerrainCacheEntry::new(&patch, Uuid::nil()));
+        assert!(!fixture.in_memory(&region, 1, 0));
+
+        // The ray is below 23 meters when it gets to (2, 0).
+        let r = ray((8., 8., 25.), (1., 0., -0.1));
+        let hit = fixture.storage.cast_ray(&region, &r, 100.).unwrap().unwrap();
+        assert!((hit.rel_pos.x - 32.).abs() < 1e-3);
+        assert!(fixture
+            .storage
+            .get_patch(&(region.id().clone(), Vector2::new(1, 0)))
+            .is_ok());
+    }
 }
@@ -1,22 +1,145 @@
-use data::Uuid;
-use std::sync::Arc;
+//! Textures fetched from the simulators, e.g. the detail textures of the
+//! terrain.
+//!
+//! Consumers ask the storage for a texture, which requests it if it is not
+//! there yet. The region manager fetches the requested textures and puts
+//! them into the storage, where the consumers find them the next time.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use types::Uuid;
+
+/// Interval after which a texture whose fetch failed is requested again.
+const RETRY_INTERVAL_MS: u64 = 10_000;
 
 // TODO:
-// - In memory cache for textures, limiting the amount of memory used for textures kept in cache.
+// - Limit the amount of memory used for textures kept in the storage.
 // - Or should textures not be kept in main memory at all, and just the GPU memory should be
 // managed.
 
-pub struct Texture {
-    /// The texture id on the sim (or grid?).
-    id: Uuid,
+/// A decoded texture.
+#[derive(Clone, Debug)]
+pub struct TextureImage {
+    pub width: u32,
+    pub height: u32,
+    /// 8 bit RGBA pixels, row by row from the top.
+    pub rgba: Vec<u8>,
+}
 
-    handle: Arc<TextureHandle>,
+enum TextureState {
+    /// Requested, but not fetched yet.
+    Requested,
+    Loaded(Arc<TextureImage>),
+    /// Fetching it failed at the instant, it is requested again after the
+    /// retry interval.
+    Failed(Instant),
 }
 
-impl Texture {
+pub struct TextureStorage {
+    textures: Mutex<HashMap<Uuid, TextureState>>,
+    /// The textures requested since the region manager last took them.
+    requests: Mutex<Vec<Uuid>>,
+}
+
+impl TextureStorage {
+    pub fn new() -> Self {
+        TextureStorage {
+            textures: Mutex::new(HashMap::new()),
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns a texture if it was fetched already, otherwise it is
+    /// requested (once, or again some time after fetching it failed). The
+    /// nil UUID stands for no texture at all.
+    pub fn get_or_request(&self, id: &Uuid) -> Option<Arc<TextureImage>> {
+        if id.is_nil() {
+            return None;
+        }
+        let mut textures = self.textures.lock().unwrap();
+        let retry = Duration::from_millis(RETRY_INTERVAL_MS);
+        match textures.get(id) {
+            Some(&TextureState::Loaded(ref image)) => return Some(Arc::clone(image)),
+            Some(&TextureState::Failed(at)) if at.elapsed() >= retry => {}
+            Some(_) => return None,
+            None => {}
+        }
+        textures.insert(id.clone(), TextureState::Requested);
+        self.requests.lock().unwrap().push(id.clone());
+        None
+    }
+
+    /// Removes and returns the textures to be fetched, oldest first.
+    pub fn take_requests(&self) -> Vec<Uuid> {
+        ::std::mem::replace(&mut *self.requests.lock().unwrap(), Vec::new())
+    }
 
+    /// Puts a requested texture back, e.g. as it can't be fetched until a
+    /// simulator is connected.
+    pub fn defer(&self, ids: Vec<Uuid>) {
+        self.requests.lock().unwrap().extend(ids);
+    }
+
+    pub fn put(&self, id: Uuid, image: TextureImage) {
+        let mut textures = self.textures.lock().unwrap();
+        textures.insert(id, TextureState::Loaded(Arc::new(image)));
+    }
+
+    /// Marks a texture which could not be fetched, it is requested again
+    /// after the retry interval.
+    pub fn fail(&self, id: Uuid) {
+        let mut textures = self.textures.lock().unwrap();
+        textures.insert(id, TextureState::Failed(Instant::now()));
+    }
 }
 
-pub struct TextureHandle {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data::test_uuid as uuid;
+
+    fn image() -> TextureImage {
+        TextureImage {
+            width: 1,
+            height: 1,
+            rgba: vec![255, 0, 0, 255],
+        }
+    }
+
+    #[test]
+    fn textures_are_requested_once() {
+        let storage = TextureStorage::new();
+        assert!(storage.get_or_request(&uuid(1)).is_none());
+        assert!(storage.get_or_request(&uuid(1)).is_none());
+        assert!(storage.get_or_request(&Uuid::nil()).is_none());
+        assert_eq!(storage.take_requests(), vec![uuid(1)]);
+        assert!(storage.take_requests().is_empty());
+
+        storage.put(uuid(1), image());
+        assert_eq!(storage.get_or_request(&uuid(1)).unwrap().rgba, image().rgba);
+        assert!(storage.take_requests().is_empty());
+    }
+
+    #[test]
+    fn failed_textures_are_requested_again_later() {
+        let storage = TextureStorage::new();
+        storage.get_or_request(&uuid(2));
+        let requests = storage.take_requests();
+        storage.defer(requests);
+        assert_eq!(storage.take_requests(), vec![uuid(2)]);
+        storage.fail(uuid(2));
+        assert!(storage.get_or_request(&uuid(2)).is_none());
+        assert!(storage.take_requests().is_empty());
 
+        // Once the retry interval passed.
+        let failed_at = Instant::now() - Duration::from_millis(RETRY_INTERVAL_MS);
+        storage
+            .textures
+            .lock()
+            .unwrap()
+            .insert(uuid(2), TextureState::Failed(failed_at));
+        assert!(storage.get_or_request(&uuid(2)).is_none());
+        assert_eq!(storage.take_requests(), vec![uuid(2)]);
+    }
 }
@@ -0,0 +1,195 @@
+//! Wind and cloud layers of the regions.
+//!
+//! Simulators send these through `LayerData` messages like the land, but at a
+//! much lower resolution: each layer is a single grid of samples covering the
+//! whole region. They are only kept in memory, as they change all the time.
+
+use data::ids;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use types::{DMatrix, Vector2};
+
+/// Index of the grid cell covering a coordinate, for grids of `cells` cells
+/// covering `side` meters.
+fn cell_index(coordinate: f32, side: f32, cells: usize) -> usize {
+    let index = (coordinate / side * cells as f32).floor();
+    index.max(0.).min(cells as f32 - 1.) as usize
+}
+
+/// Center of a grid cell, in meters from the region origin.
+fn cell_center(x: usize, y: usize, side: f32, shape: (usize, usize)) -> Vector2<f32> {
+    Vector2::new(
+        (x as f32 + 0.5) * side / shape.0 as f32,
+        (y as f32 + 0.5) * side / shape.1 as f32,
+    )
+}
+
+/// Velocity of the wind over a region, indexed by `(x, y)` like the land
+/// heightmaps.
+#[derive(Clone, Debug)]
+pub struct WindField {
+    /// Velocity towards the east, in meters per second.
+    pub east: DMatrix<f32>,
+    /// Velocity towards the north, in meters per second.
+    pub north: DMatrix<f32>,
+}
+
+impl WindField {
+    /// Returns the velocity at a point of a region with sides of `side`
+    /// meters, points outside of the region get the velocity of the closest
+    /// sample.
+    pub fn velocity_at(&self, point: Vector2<f32>, side: f32) -> Vector2<f32> {
+        let (rows, cols) = self.east.shape();
+        let x = cell_index(point.x, side, rows);
+        let y = cell_index(point.y, side, cols);
+        Vector2::new(self.east[(x, y)], self.north[(x, y)])
+    }
+
+    /// Returns the position and velocity of every sample.
+    pub fn samples(&self, side: f32) -> Vec<(Vector2<f32>, Vector2<f32>)> {
+        let shape = self.east.shape();
+        let mut samples = Vec::with_capacity(shape.0 * shape.1);
+        for x in 0..shape.0 {
+            for y in 0..shape.1 {
+                let velocity = Vector2::new(self.east[(x, y)], self.north[(x, y)]);
+                samples.push((cell_center(x, y, side, shape), velocity));
+            }
+        }
+        samples
+    }
+}
+
+/// Density of the clouds over a region, from 0 (clear sky) upwards.
+#[derive(Clone, Debug)]
+pub struct CloudLayer {
+    pub density: DMatrix<f32>,
+}
+
+impl CloudLayer {
+    /// Returns the density at a point of a region with sides of `side`
+    /// meters.
+    pub fn density_at(&self, point: Vector2<f32>, side: f32) -> f32 {
+        let (rows, cols) = self.density.shape();
+        self.density[(cell_index(point.x, side, rows), cell_index(point.y, side, cols))]
+    }
+
+    /// Returns the minimum and maximum corner and the density of every cell.
+    pub fn cells(&self, side: f32) -> Vec<(Vector2<f32>, Vector2<f32>, f32)> {
+        let shape = self.density.shape();
+        let size = Vector2::new(side / shape.0 as f32, side / shape.1 as f32);
+        let mut cells = Vec::with_capacity(shape.0 * shape.1);
+        for x in 0..shape.0 {
+            for y in 0..shape.1 {
+                let min = Vector2::new(x as f32 * size.x, y as f32 * size.y);
+                cells.push((min, min + size, self.density[(x, y)]));
+            }
+        }
+        cells
+    }
+}
+
+/// The most recent wind and cloud layers received for a region.
+#[derive(Clone, Debug, Default)]
+pub struct RegionWeather {
+    wind: Option<WindField>,
+    clouds: Option<CloudLayer>,
+    updated: Option<Instant>,
+}
+
+impl RegionWeather {
+    pub fn wind(&self) -> Option<&WindField> {
+        self.wind.as_ref()
+    }
+
+    pub fn clouds(&self) -> Option<&CloudLayer> {
+        self.clouds.as_ref()
+    }
+
+    /// When a layer of the region was last received.
+    pub fn updated(&self) -> Option<Instant> {
+        self.updated
+    }
+}
+
+/// Storage of the wind and cloud layers of the connected regions.
+pub struct WeatherStorage {
+    regions: Mutex<HashMap<ids::RegionId, RegionWeather>>,
+}
+
+impl WeatherStorage {
+    pub fn new() -> Self {
+        WeatherStorage {
+            regions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn put_wind(&self, region_id: ids::RegionId, wind: WindField) {
+        let mut regions = self.regions.lock().unwrap();
+        let weather = regions
+            .entry(region_id)
+            .or_insert_with(RegionWeather::default);
+        weather.wind = Some(wind);
+        weather.updated = Some(Instant::now());
+    }
+
+    pub fn put_clouds(&self, region_id: ids::RegionId, clouds: CloudLayer) {
+        let mut regions = self.regions.lock().unwrap();
+        let weather = regions
+            .entry(region_id)
+            .or_insert_with(RegionWeather::default);
+        weather.clouds = Some(clouds);
+        weather.updated = Some(Instant::now());
+    }
+
+    /// Returns the layers of a region, if any were received.
+    pub fn get(&self, region_id: &ids::RegionId) -> Option<RegionWeather> {
+        self.regions.lock().unwrap().get(region_id).cloned()
+    }
+
+    /// Forgets the layers of a region, once it is no longer connected.
+    pub fn remove(&self, region_id: &ids::RegionId) {
+        self.regions.lock().unwrap().remove(region_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wind() -> WindField {
+        WindField {
+            east: DMatrix::from_fn(16, 16, |x, _| x as f32),
+            north: DMatrix::from_fn(16, 16, |_, y| -(y as f32)),
+        }
+    }
+
+    #[test]
+    fn wind_is_sampled_from_the_covering_cell() {
+        let wind = wind();
+        assert_eq!(wind.velocity_at(Vector2::new(0., 0.), 256.), Vector2::new(0., 0.));
+        assert_eq!(wind.velocity_at(Vector2::new(40., 250.), 256.), Vector2::new(2., -15.));
+        // Points outside of the region use the closest sample.
+        assert_eq!(wind.velocity_at(Vector2::new(300., -5.), 256.), Vector2::new(15., 0.));
+    }
+
+    #[test]
+    fn samples_are_at_cell_centers() {
+        let samples = wind().samples(512.);
+        assert_eq!(samples.len(), 256);
+        assert_eq!(samples[0], (Vector2::new(16., 16.), Vector2::new(0., 0.)));
+        assert_eq!(samples[17], (Vector2::new(48., 48.), Vector2::new(1., -1.)));
+    }
+
+    #[test]
+    fn cloud_cells_cover_the_region() {
+        let clouds = CloudLayer {
+            density: DMatrix::from_element(16, 16, 0.5),
+        };
+        let cells = clouds.cells(256.);
+        assert_eq!(cells.len(), 256);
+        assert_eq!(cells[0].0, Vector2::new(0., 0.));
+        assert_eq!(cells[255].1, Vector2::new(256., 256.));
+        assert_eq!(clouds.density_at(Vector2::new(100., 100.), 256.), 0.5);
+    }
+}
@@ -22,9 +22,12 @@ extern crate multiqueue;
 extern crate opensim_networking;
 extern crate opensim_types as types;
 extern crate parking_lot;
+extern crate png;
+extern crate rmp_serde;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
 extern crate simple_disk_cache;
 #[macro_use]
@@ -43,15 +46,178 @@ pub mod networking;
 pub mod render;
 pub mod util;
 
+use opensim_networking::logging::{Log, LogLevel};
+
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let log = Log::new_dir("target/log", LogLevel::Debug).unwrap();
+
+    match args.get(0).map(|arg| arg.as_str()) {
+        Some("--offline") => match (args.get(1).map(|arg| arg.as_str()), args.get(2)) {
+            (Some("--heightmap"), Some(path)) => run_heightmap(log, path),
+            (Some(region), _) => run_offline(setup_storage(&log), log, region),
+            (None, _) => list_cached_regions(&setup_storage(&log)),
+        },
+        Some("--maps") => save_map_tiles(&setup_storage(&log)),
+        _ => run_online(setup_storage(&log), log),
+    }
+}
+
+/// Sets up the storage managers.
+fn setup_storage(log: &Log) -> data::Storage {
+    let paths = data::config::Paths {};
+    setup_storage_in(paths.terrain_cache(), paths.terrain_region_index(), log)
+}
+
+/// Like `setup_storage`, but with the terrain cache and its region index at
+/// the specified paths.
+fn setup_storage_in(
+    terrain_cache: std::path::PathBuf,
+    terrain_region_index: std::path::PathBuf,
+    log: &Log,
+) -> data::Storage {
+    use parking_lot::RwLock;
+    use std::sync::Arc;
+
+    let client_avatar = Arc::new(RwLock::new(data::avatar::ClientAvatar::new(None)));
+    let region_ids = Arc::new(data::ids::RegionIds::new());
+    let region_storage = Arc::new(data::region::RegionStorage::new());
+    data::Storage {
+        terrain: Arc::new(
+            data::terrain::TerrainStorage::open(
+                terrain_cache,
+                terrain_region_index,
+                data::config::TerrainConfig::default(),
+                Arc::clone(&client_avatar),
+                Arc::clone(&region_storage),
+                Arc::clone(&region_ids),
+                log.slog_logger(),
+            ).expect("setup terrain storage failed"),
+        ),
+        strokes: Arc::new(data::terraform::StrokeQueue::new()),
+        textures: Arc::new(data::texture::TextureStorage::new()),
+        weather: Arc::new(data::weather::WeatherStorage::new()),
+        region: region_storage,
+        region_ids,
+        client_avatar,
+    }
+}
+
+/// Prints the regions which can be viewed in offline mode.
+fn list_cached_regions(storage: &data::Storage) {
+    let regions = storage.terrain.cached_regions();
+    if regions.is_empty() {
+        println!("No regions in the terrain cache.");
+    }
+    for (uuid, region) in regions {
+        println!(
+            "{} ({}m, grid location {}, {})",
+            uuid, region.side_meters, region.grid_location.0, region.grid_location.1
+        );
+    }
+}
+
+/// Saves the map tiles of all regions in the terrain cache.
+fn save_map_tiles(storage: &data::Storage) {
+    for (uuid, cached) in storage.terrain.cached_regions() {
+        let region_id = storage.region_ids.get_or_insert(&uuid);
+        let region = match cached.to_region(uuid.clone(), region_id) {
+            Ok(region) => region,
+            Err(e) => {
+                println!("Region {} is invalid: {}", uuid, e);
+                continue;
+            }
+        };
+        match data::map_tile::save_region(&storage.terrain, &region) {
+            Ok(path) => println!("Saved map tile of region {} to {:?}.", uuid, path),
+            Err(e) => println!("Saving map tile of region {} failed: {}", uuid, e),
+        }
+    }
+}
+
+/// Shows the cached terrain of a region, without connecting to a simulator.
+fn run_offline(storage: data::Storage, log: Log, region: &str) {
+    use types::Uuid;
+
+    let uuid = Uuid::parse_str(region).expect("invalid region UUID");
+    let cached = storage
+        .terrain
+        .cached_regions()
+        .into_iter()
+        .find(|&(ref cached_uuid, _)| *cached_uuid == uuid)
+        .map(|(_, cached)| cached)
+        .expect("region is not in the terrain cache");
+
+    let region_id = storage.region_ids.get_or_insert(&uuid);
+    let region = cached
+        .to_region(uuid, region_id.clone())
+        .expect("invalid cached region");
+    enter_offline_region(&storage, region);
+
+    render::render_world(storage, log.slog_logger().clone());
+}
+
+/// Shows the terrain of a heightmap file, without connecting to a simulator.
+fn run_heightmap(log: Log, path: &str) {
+    use data::region::{Region, RegionDimensions};
+    use std::path::Path;
+    use std::{env, fs, process};
+    use types::{Uuid, Vector2};
+
+    let heights = data::heightmap::load(Path::new(path)).expect("loading the heightmap failed");
+    let dims = RegionDimensions::from_size(heights.nrows() as u32, heights.ncols() as u32)
+        .expect("invalid heightmap size");
+
+    // The terrain is not the one of any region on a grid, so it is kept out
+    // of the terrain cache, in a scratch directory removed afterwards.
+    let scratch = env::temp_dir().join(format!("opensim-client-heightmap-{}", process::id()));
+    let storage = setup_storage_in(
+        scratch.join("terrain"),
+        scratch.join("terrain_regions.json"),
+        &log,
+    );
+
+    let uuid = Uuid::nil();
+    let region_id = storage.region_ids.get_or_insert(&uuid);
+    let region = Region::new(uuid, region_id, dims, Vector2::new(1000, 1000));
+    enter_offline_region(&storage, region.clone());
+    data::heightmap::import_region(&storage.terrain, &region, &heights)
+        .expect("importing the heightmap failed");
+
+    render::render_world(storage, log.slog_logger().clone());
+    let _ = fs::remove_dir_all(&scratch);
+}
+
+/// Puts a region in the place of a connected one, with the client avatar
+/// starting above its center.
+fn enter_offline_region(storage: &data::Storage, region: data::region::Region) {
+    use data::region::Connection;
+    use types::Vector3;
+
+    let region_id = region.id().clone();
+    let center = region.dimensions().side_meters as f32 / 2.;
+    storage
+        .region
+        .put(region_id.clone(), Connection::Connected(region));
+    storage
+        .region
+        .enter(region_id.clone())
+        .expect("entering the region failed");
+    storage
+        .client_avatar
+        .write()
+        .enter_region(region_id, Some(Vector3::new(center, center, 100.)));
+}
+
+/// Logs in and connects to the simulator.
+fn run_online(storage: data::Storage, log: Log) {
     use futures::Future;
     use networking::RegionManager;
     use opensim_networking::circuit::message_handlers::Handlers;
-    use opensim_networking::logging::{Log, LogLevel};
     use opensim_networking::login::{hash_password, LoginRequest};
-    use opensim_networking::simulator::Simulator;
-    use parking_lot::RwLock;
-    use std::sync::{mpsc, Arc, Mutex};
+    use opensim_networking::simulator::{ConnectInfo, Simulator};
+    use std::net::SocketAddr;
+    use std::sync::{mpsc, Mutex};
     use std::thread;
     use tokio_core::reactor::Core;
     use typed_rwlock;
@@ -69,22 +235,7 @@ fn main() {
         .perform(cfg.sim.loginuri.as_str())
         .expect("Login failure.");
 
-    // Setup logging.
-    let log = Log::new_dir("target/log", LogLevel::Debug).unwrap();
-    let connect_info = login_response.into();
-    let handlers = Handlers::default();
-
-    // Setup storage managers.
-    let paths = data::config::Paths {};
-    let client_avatar = Arc::new(RwLock::new(data::avatar::ClientAvatar::new(None)));
-    let storage = data::Storage {
-        terrain: Arc::new(
-            data::terrain::TerrainStorage::new(&paths, Arc::clone(&client_avatar))
-                .expect("setup terrain storage failed"),
-        ),
-        region: Arc::new(data::region::RegionStorage::new()),
-        client_avatar,
-    };
+    let connect_info: ConnectInfo = login_response.into();
 
     // Connect to the simulator.
     //
@@ -93,51 +244,39 @@ fn main() {
     // the stack bigger.
     let builder = thread::Builder::new().stack_size(16 * 1024 * 1024);
     let storage_ = storage.clone();
+    let log_ = log.clone();
     builder
         .spawn(move || {
-            let mut region_manager = Box::new(RegionManager::start(log.clone(), &storage_));
+            let mut region_manager = Box::new(RegionManager::start(log_.clone(), &storage_));
+            region_manager.setup_agent(&connect_info);
+            let address = SocketAddr::new(connect_info.sim_ip, connect_info.sim_port);
+            let handlers = region_manager.handlers(address);
             let mut reactor = Core::new().unwrap();
             let handle = reactor.handle();
 
             println!("connecting sim");
             let sim = reactor
-                .run(Simulator::connect(connect_info, handlers, handle, log))
+                .run(Simulator::connect(connect_info, handlers, handle.clone(), log_))
                 .unwrap();
             println!("connecting sim finished");
 
-            // Notify region storage of the connected region.
-            let region_uuid = sim.region_info().region_id.clone();
-            let region_dims = data::region::RegionDimensions {
-                // TODO
-                patches_per_side: 16,
-                // TODO
-                patch_size_axis: 16,
-                // TODO (VarRegions extension?)
-                side_meters: 256,
-            };
-            // TODO: grid_location
-            // TODO: patches_size
-            let region =
-                data::region::Region::new(region_uuid.clone(), region_uuid.clone(), region_dims);
-            storage_.region.put(
-                region_uuid.clone(),
-                data::region::Connection::Connected(region),
-            );
+            // Setup the region manager so terrain data is downloaded etc.,
+            // this also registers the region in the region storage.
+            let region_id = region_manager
+                .setup_sim(sim, address)
+                .expect("setting up the simulator failed");
 
             // Notify the client storage about the current region.
-            storage_
-                .client_avatar
-                .write()
-                .set_current_region(Some(region_uuid));
-
-            // Setup the region manager so terrain data is downloaded etc.
-            region_manager.setup_sim(sim);
+            region_manager
+                .enter_region(&region_id)
+                .expect("entering the region failed");
 
             loop {
-                reactor.turn(None);
+                let timeout = region_manager.update(&handle);
+                reactor.turn(Some(timeout));
             }
         })
         .unwrap();
 
-    render::render_world(storage);
+    render::render_world(storage, log.slog_logger().clone());
 }
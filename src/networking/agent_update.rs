@@ -0,0 +1,243 @@
+//! Keeps the simulator informed about the state of the client avatar, by
+//! sending `AgentUpdate` messages.
+//!
+//! The state of the avatar is sampled at a fixed rate, but a message is only
+//! sent if it differs from the last state sent, or as keep-alive once the
+//! last one is a while ago.
+
+use data::avatar::{Avatar, ClientAvatar, Controls};
+use futures::Future;
+use opensim_networking::messages::all::{AgentUpdate, AgentUpdate_AgentData};
+use opensim_networking::simulator::Simulator;
+use parking_lot::RwLock;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::Handle;
+use types::{UnitQuaternion, Uuid, Vector3};
+
+/// The official viewer sends at most 10 updates per second.
+const UPDATE_INTERVAL_MS: u64 = 100;
+
+/// An unchanged state is sent again after this interval, so the simulator
+/// doesn't take the agent for gone.
+const KEEP_ALIVE_INTERVAL_MS: u64 = 1000;
+
+/// Position changes smaller than this (in meters) are not sent.
+const POSITION_EPSILON: f32 = 0.05;
+
+/// Rotation changes smaller than this (in radians) are not sent.
+const ROTATION_EPSILON: f32 = 0.01;
+
+/// Distance of the far clip plane sent to the simulator, it determines
+/// which objects the simulator will tell us about.
+const DRAW_DISTANCE: f32 = 512.;
+
+/// Bit values of the control flags field, as understood by the simulator.
+mod control_flags {
+    pub const AT_POS: u32 = 1 << 0;
+    pub const AT_NEG: u32 = 1 << 1;
+    pub const UP_POS: u32 = 1 << 4;
+    pub const UP_NEG: u32 = 1 << 5;
+    pub const FLY: u32 = 1 << 13;
+    pub const TURN_LEFT: u32 = 1 << 25;
+    pub const TURN_RIGHT: u32 = 1 << 26;
+}
+
+fn to_control_flags(controls: &Controls) -> u32 {
+    use self::control_flags::*;
+
+    let mut flags = 0;
+    if controls.forward {
+        flags |= AT_POS;
+    }
+    if controls.backward {
+        flags |= AT_NEG;
+    }
+    if controls.up {
+        flags |= UP_POS;
+    }
+    if controls.down {
+        flags |= UP_NEG;
+    }
+    if controls.fly {
+        flags |= FLY;
+    }
+    if controls.turn_left {
+        flags |= TURN_LEFT;
+    }
+    if controls.turn_right {
+        flags |= TURN_RIGHT;
+    }
+    flags
+}
+
+/// The state of the client avatar which is reported to the simulator.
+#[derive(Clone, Debug)]
+struct AgentState {
+    camera_center: Vector3<f32>,
+    body_rotation: UnitQuaternion<f32>,
+    head_rotation: UnitQuaternion<f32>,
+    control_flags: u32,
+}
+
+impl AgentState {
+    fn sample(avatar: &ClientAvatar) -> Self {
+        AgentState {
+            camera_center: avatar.eye_position(),
+            body_rotation: UnitQuaternion::from_quaternion(*avatar.body_rotation()),
+            head_rotation: UnitQuaternion::from_quaternion(*avatar.head_rotation()),
+            control_flags: to_control_flags(&avatar.controls()),
+        }
+    }
+
+    /// Whether the difference to the other state is relevant to the simulator.
+    fn differs_from(&self, other: &AgentState) -> bool {
+        self.control_flags != other.control_flags
+            || (self.camera_center - other.camera_center).norm() > POSITION_EPSILON
+            || self.body_rotation.angle_to(&other.body_rotation) > ROTATION_EPSILON
+            || self.head_rotation.angle_to(&other.head_rotation) > ROTATION_EPSILON
+    }
+
+    fn to_message(&self, agent_id: &Uuid, session_id: &Uuid) -> AgentUpdate {
+        // y-axis in the world is the viewing direction.
+        let at_axis = self.head_rotation.rotate_vector(&Vector3::y_axis());
+        let up_axis = Vector3::z();
+        let left_axis = up_axis.cross(&at_axis);
+
+        AgentUpdate {
+            agent_data: AgentUpdate_AgentData {
+                agent_id: agent_id.clone(),
+                session_id: session_id.clone(),
+                body_rotation: self.body_rotation,
+                head_rotation: self.head_rotation,
+                state: 0,
+                camera_center: self.camera_center,
+                camera_at_axis: at_axis,
+                camera_left_axis: left_axis,
+                camera_up_axis: up_axis,
+                far: DRAW_DISTANCE,
+                control_flags: self.control_flags,
+                flags: 0,
+            },
+        }
+    }
+}
+
+/// Samples the client avatar and sends `AgentUpdate` messages if needed.
+pub struct AgentUpdater {
+    agent_id: Uuid,
+    session_id: Uuid,
+    client_avatar: Arc<RwLock<ClientAvatar>>,
+
+    /// The state last sent, and when.
+    last_sent: Option<(AgentState, Instant)>,
+    next_sample: Instant,
+}
+
+impl AgentUpdater {
+    pub fn new(
+        agent_id: Uuid,
+        session_id: Uuid,
+        client_avatar: Arc<RwLock<ClientAvatar>>,
+    ) -> Self {
+        AgentUpdater {
+            agent_id,
+            session_id,
+            client_avatar,
+            last_sent: None,
+            next_sample: Instant::now(),
+        }
+    }
+
+    /// Forget about the last sent state, so the next sample is sent in any
+    /// case. (Used when the simulator changes.)
+    pub fn reset(&mut self) {
+        self.last_sent = None;
+    }
+
+    /// Samples the client avatar if the update interval has elapsed, and
+    /// sends an update message to the simulator if its state changed.
+    ///
+    /// Returns the time until this should be called again.
+    pub fn update(&mut self, sim: &Simulator, handle: &Handle) -> Duration {
+        let now = Instant::now();
+        if now < self.next_sample {
+            return self.next_sample - now;
+        }
+        let interval = Duration::from_millis(UPDATE_INTERVAL_MS);
+        self.next_sample = now + interval;
+
+        let state = AgentState::sample(&*self.client_avatar.read());
+        if needs_sending(&state, self.last_sent.as_ref(), now) {
+            let msg = state.to_message(&self.agent_id, &self.session_id);
+            // Updates are sent unreliably, a lost one is superseded by the
+            // next one anyway.
+            handle.spawn(sim.send_message(msg, false).map(|_| ()).map_err(|_| ()));
+            self.last_sent = Some((state, now));
+        }
+
+        interval
+    }
+}
+
+/// Whether a sampled state has to be sent, given the state sent last and
+/// when it was sent.
+fn needs_sending(
+    state: &AgentState,
+    last_sent: Option<&(AgentState, Instant)>,
+    now: Instant,
+) -> bool {
+    match last_sent {
+        Some(&(ref last, sent_at)) => {
+            state.differs_from(last)
+                || now - sent_at >= Duration::from_millis(KEEP_ALIVE_INTERVAL_MS)
+        }
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> AgentState {
+        AgentState {
+            camera_center: Vector3::new(128., 128., 25.),
+            body_rotation: UnitQuaternion::identity(),
+            head_rotation: UnitQuaternion::identity(),
+            control_flags: 0,
+        }
+    }
+
+    #[test]
+    fn unchanged_states_are_not_resent() {
+        let sent_at = Instant::now();
+        let last = (state(), sent_at);
+        let now = sent_at + Duration::from_millis(UPDATE_INTERVAL_MS);
+        assert!(needs_sending(&state(), None, now));
+        assert!(!needs_sending(&state(), Some(&last), now));
+
+        let mut moved = state();
+        moved.camera_center.x += POSITION_EPSILON / 2.;
+        assert!(!needs_sending(&moved, Some(&last), now));
+        moved.camera_center.x += POSITION_EPSILON;
+        assert!(needs_sending(&moved, Some(&last), now));
+
+        let mut walking = state();
+        walking.control_flags = to_control_flags(&Controls {
+            forward: true,
+            ..Controls::default()
+        });
+        assert!(needs_sending(&walking, Some(&last), now));
+    }
+
+    #[test]
+    fn keep_alive_fires_after_the_interval() {
+        let sent_at = Instant::now();
+        let last = (state(), sent_at);
+        let keep_alive = Duration::from_millis(KEEP_ALIVE_INTERVAL_MS);
+        let before = sent_at + keep_alive - Duration::from_millis(UPDATE_INTERVAL_MS);
+        assert!(!needs_sending(&state(), Some(&last), before));
+        assert!(needs_sending(&state(), Some(&last), sent_at + keep_alive));
+    }
+}
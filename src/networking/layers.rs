@@ -0,0 +1,218 @@
+//! Decoding of the layers simulators send through `LayerData` messages, i.e.
+//! the land, the wind and the clouds.
+//!
+//! The message handlers hold only one handler per message type, so all the
+//! layers are received by the same handler and told apart here.
+
+use data::weather::{CloudLayer, WindField};
+use opensim_networking::layer_data::Patch;
+use opensim_networking::messages::all::LayerData;
+use types::DMatrix;
+
+/// Layer types of `LayerData` messages, the extended variants are sent by
+/// regions larger than 256 meters.
+mod layer_types {
+    pub const LAND: u8 = 0x4c;
+    pub const LAND_EXTENDED: u8 = 0x4d;
+    pub const WIND: u8 = 0x37;
+    pub const CLOUD: u8 = 0x38;
+    pub const WIND_EXTENDED: u8 = 0x39;
+    pub const CLOUD_EXTENDED: u8 = 0x3a;
+}
+
+#[derive(Debug, Fail)]
+pub enum LayerError {
+    #[fail(display = "Decoding layer failed: {}", _0)]
+    Decode(String),
+
+    /// Layers which are not used, e.g. water.
+    #[fail(display = "Layer of type {:#x} is not supported.", _0)]
+    UnknownType(u8),
+
+    #[fail(display = "Layer consists of {} patches, expected {}.", _0, _1)]
+    PatchCount(usize, usize),
+
+    #[fail(display = "Layer patches differ in size.")]
+    SizeMismatch,
+
+    #[fail(display = "Layer contains values which are not finite.")]
+    NotFinite,
+}
+
+/// A decoded patch of a layer.
+#[derive(Clone, Debug)]
+pub struct LayerPatch {
+    /// Position of the patch in the region, in patches.
+    pub position: (u32, u32),
+    pub data: DMatrix<f32>,
+}
+
+pub enum Layer {
+    /// Land patches, these are validated by the receiver (see
+    /// `patch_validation`).
+    Land(Vec<LayerPatch>),
+    Wind(WindField),
+    Clouds(CloudLayer),
+}
+
+/// Decodes the layer carried by a message.
+pub fn decode(msg: &LayerData) -> Result<Layer, LayerError> {
+    use self::layer_types::*;
+
+    let layer_type = msg.layer_id.type_;
+    match layer_type {
+        LAND | LAND_EXTENDED | WIND | CLOUD | WIND_EXTENDED | CLOUD_EXTENDED => {}
+        other => return Err(LayerError::UnknownType(other)),
+    }
+
+    let patches =
+        Patch::extract_message(msg).map_err(|e| LayerError::Decode(format!("{:?}", e)))?;
+    let patches = patches
+        .iter()
+        .map(|patch| {
+            let p = patch.patch_position();
+            LayerPatch {
+                position: (p.0 as u32, p.1 as u32),
+                data: patch.to_data(),
+            }
+        })
+        .collect();
+    from_patches(layer_type, patches)
+}
+
+fn check_finite(data: &DMatrix<f32>) -> Result<(), LayerError> {
+    if data.iter().all(|v| v.is_finite()) {
+        Ok(())
+    } else {
+        Err(LayerError::NotFinite)
+    }
+}
+
+/// Assembles a layer of the type from its decoded patches.
+///
+/// The wind is sent as two patches, holding the velocities towards the east
+/// and the north. The clouds are sent as a single patch of densities.
+fn from_patches(layer_type: u8, patches: Vec<LayerPatch>) -> Result<Layer, LayerError> {
+    use self::layer_types::*;
+
+    if let LAND | LAND_EXTENDED = layer_type {
+        return Ok(Layer::Land(patches));
+    }
+
+    let mut data: Vec<DMatrix<f32>> = patches.into_iter().map(|p| p.data).collect();
+    for matrix in &data {
+        check_finite(matrix)?;
+    }
+    match layer_type {
+        WIND | WIND_EXTENDED => {
+            if data.len() != 2 {
+                return Err(LayerError::PatchCount(data.len(), 2));
+            }
+            let north = data.pop().unwrap();
+            let east = data.pop().unwrap();
+            if east.shape() != north.shape() {
+                return Err(LayerError::SizeMismatch);
+            }
+            Ok(Layer::Wind(WindField { east, north }))
+        }
+        CLOUD | CLOUD_EXTENDED => {
+            if data.len() != 1 {
+                return Err(LayerError::PatchCount(data.len(), 1));
+            }
+            Ok(Layer::Clouds(CloudLayer {
+                density: data.pop().unwrap(),
+            }))
+        }
+        other => Err(LayerError::UnknownType(other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::layer_types::*;
+    use super::*;
+
+    fn patches(count: usize, value: f32) -> Vec<LayerPatch> {
+        (0..count)
+            .map(|i| LayerPatch {
+                position: (i as u32, 0),
+                data: DMatrix::from_element(16, 16, value),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn land_patches_are_passed_on() {
+        for &layer_type in [LAND, LAND_EXTENDED].iter() {
+            match from_patches(layer_type, patches(3, 20.)) {
+                Ok(Layer::Land(land)) => {
+                    assert_eq!(land.len(), 3);
+                    assert_eq!(land[2].position, (2, 0));
+                }
+                _ => panic!("expected land"),
+            }
+        }
+    }
+
+    #[test]
+    fn wind_consists_of_two_patches() {
+        for &layer_type in [WIND, WIND_EXTENDED].iter() {
+            let mut layer = patches(2, 3.);
+            layer[1].data[(0, 0)] = -1.;
+            match from_patches(layer_type, layer) {
+                Ok(Layer::Wind(wind)) => {
+                    assert_eq!(wind.east[(0, 0)], 3.);
+                    assert_eq!(wind.north[(0, 0)], -1.);
+                }
+                _ => panic!("expected wind"),
+            }
+        }
+        match from_patches(WIND, patches(1, 3.)) {
+            Err(LayerError::PatchCount(1, 2)) => {}
+            _ => panic!("expected a patch count error"),
+        }
+
+        let mut layer = patches(2, 3.);
+        layer[1].data = DMatrix::zeros(8, 8);
+        match from_patches(WIND_EXTENDED, layer) {
+            Err(LayerError::SizeMismatch) => {}
+            _ => panic!("expected a size mismatch"),
+        }
+    }
+
+    #[test]
+    fn clouds_consist_of_one_patch() {
+        for &layer_type in [CLOUD, CLOUD_EXTENDED].iter() {
+            match from_patches(layer_type, patches(1, 0.5)) {
+                Ok(Layer::Clouds(clouds)) => assert_eq!(clouds.density[(3, 3)], 0.5),
+                _ => panic!("expected clouds"),
+            }
+        }
+        match from_patches(CLOUD, patches(2, 0.5)) {
+            Err(LayerError::PatchCount(2, 1)) => {}
+            _ => panic!("expected a patch count error"),
+        }
+    }
+
+    #[test]
+    fn weather_must_be_finite() {
+        let mut layer = patches(2, 3.);
+        layer[0].data[(4, 4)] = ::std::f32::NAN;
+        match from_patches(WIND, layer) {
+            Err(LayerError::NotFinite) => {}
+            _ => panic!("expected non finite values to be rejected"),
+        }
+        match from_patches(CLOUD_EXTENDED, patches(1, ::std::f32::INFINITY)) {
+            Err(LayerError::NotFinite) => {}
+            _ => panic!("expected non finite values to be rejected"),
+        }
+    }
+
+    #[test]
+    fn unknown_layers_are_rejected() {
+        match from_patches(0x57, patches(1, 20.)) {
+            Err(LayerError::UnknownType(0x57)) => {}
+            _ => panic!("expected an unknown type"),
+        }
+    }
+}
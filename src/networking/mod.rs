@@ -7,85 +7,737 @@
 
 use chashmap::CHashMap;
 use crossbeam_channel;
+use data::avatar::ClientAvatar;
+use data::region::{self, Connection, Presence, Region, RegionDimensions, RegionStorage,
+                   TerrainComposition};
+use data::terraform::StrokeQueue;
 use data::terrain::{self, PatchHandle, TerrainPatch, TerrainStorage};
+use data::texture::{TextureImage, TextureStorage};
+use data::weather::WeatherStorage;
 use data::{ids, Storage};
+use failure::Error;
 use futures::{future, task, Async, Future, Poll};
+use opensim_networking::circuit::message_handlers::Handlers;
 use opensim_networking::logging::Log;
-use opensim_networking::services;
-use opensim_networking::simulator::Simulator;
+use opensim_networking::messages::all::LayerData;
+use opensim_networking::messages::{MessageInstance, MessageType};
+use opensim_networking::simulator::{ConnectInfo, Simulator};
+use parking_lot::RwLock;
 use simple_disk_cache::config::{CacheStrategy, DataEncoding};
 use slog::{Drain, Logger};
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::Handle;
 use types::Uuid;
-use types::{DMatrix, Vector2};
+use types::{DMatrix, Vector2, Vector3};
+
+pub mod agent_update;
+pub mod layers;
+pub mod patch_validation;
+pub mod terraform;
+
+/// Interval in which the memory of the terrain storage is maintained.
+const TERRAIN_MAINTENANCE_INTERVAL_MS: u64 = 1000;
+
+use self::agent_update::AgentUpdater;
+use self::layers::{Layer, LayerError, LayerPatch};
+use self::patch_validation::PatchStatistics;
+
+/// Messages of the simulators relevant to the region manager.
+enum SimEvent {
+    /// A neighbouring simulator we should connect to as a child agent.
+    EnableSimulator {
+        region_handle: u64,
+        address: SocketAddr,
+    },
+    /// The avatar crossed into a neighbouring region.
+    CrossedRegion {
+        region_handle: u64,
+        address: SocketAddr,
+        position: Vector3<f32>,
+    },
+    TeleportStart,
+    TeleportProgress(String),
+    TeleportFinish {
+        region_handle: u64,
+        address: SocketAddr,
+    },
+    TeleportFailed(String),
+    /// The simulator reports the position of the avatar after arriving.
+    MovementComplete { position: Vector3<f32> },
+    /// Connecting to the simulator of a region failed.
+    ConnectFailed {
+        region_handle: u64,
+        address: SocketAddr,
+    },
+    /// A land, wind or cloud layer, from the simulator at the address.
+    LayerData {
+        address: SocketAddr,
+        msg: LayerData,
+    },
+}
+
+/// The arrival in a region, which is possibly still being connected.
+struct Arrival {
+    region_handle: u64,
+    /// The position in the new region, if it was reported.
+    position: Option<Vector3<f32>>,
+    /// Teleports drop the connections to all previous regions.
+    teleport: bool,
+}
 
 /// Manages the interaction between Viewer and Region.
+///
+/// Besides the simulator of the current region, child agent connections to
+/// the neighbouring simulators announced by it are maintained, so their
+/// terrain can be displayed too.
 pub struct RegionManager {
-    simulators: HashMap<Uuid, Simulator>,
+    simulators: HashMap<ids::RegionId, Simulator>,
     log: Log,
 
-    terrain_receivers: Arc<Mutex<services::terrain::Receivers>>,
+    /// The connection info of the main agent, child agent connections reuse
+    /// its circuit code and session.
+    connect_info: Option<ConnectInfo>,
+    client_avatar: Arc<RwLock<ClientAvatar>>,
+    agent_updater: Option<AgentUpdater>,
+    region_storage: Arc<RegionStorage>,
+    region_ids: Arc<ids::RegionIds>,
+
+    /// Handles of the regions we are connected (`Some`) or connecting to.
+    region_handles: HashMap<u64, Option<ids::RegionId>>,
+    /// The regions of the connected simulators, by address.
+    sim_addresses: HashMap<SocketAddr, ids::RegionId>,
+    /// The region the avatar is arriving in, once it is connected.
+    pending_arrival: Option<Arrival>,
+    /// Layers received from simulators which are not set up yet, by address.
+    pending_layers: HashMap<SocketAddr, Vec<LayerData>>,
+    events_tx: crossbeam_channel::Sender<SimEvent>,
+    events_rx: crossbeam_channel::Receiver<SimEvent>,
+    neighbours_tx: crossbeam_channel::Sender<(Simulator, SocketAddr)>,
+    neighbours_rx: crossbeam_channel::Receiver<(Simulator, SocketAddr)>,
+
     terrain_storage: Arc<TerrainStorage>,
+    /// Land patches on their way into the terrain storage.
+    patches_tx: crossbeam_channel::Sender<(ids::RegionId, TerrainPatch)>,
+    /// Statistics of the received terrain patches, by region.
+    patch_statistics: Arc<Mutex<HashMap<ids::RegionId, PatchStatistics>>>,
+    weather_storage: Arc<WeatherStorage>,
+    strokes: Arc<StrokeQueue>,
+    texture_storage: Arc<TextureStorage>,
 }
 
 impl RegionManager {
     pub fn start(log: Log, storage: &Storage) -> Self {
-        let terrain_receivers = Arc::new(Mutex::new(services::terrain::Receivers::new()));
-
-        let terrain_receivers_ = Arc::clone(&terrain_receivers);
-        let terrain_storage_ = Arc::clone(&storage.terrain);
+        let (patches_tx, patches_rx) = crossbeam_channel::unbounded();
+        let terrain_storage = Arc::clone(&storage.terrain);
+        let patch_statistics = Arc::new(Mutex::new(HashMap::new()));
+        let thread_statistics = Arc::clone(&patch_statistics);
 
+        // Writing to the terrain storage involves the disk cache, so it is
+        // done in a dedicated thread instead of the reactor thread. The
+        // memory of the terrain storage is maintained here too.
+        let thread_log = log.clone();
         thread::spawn(move || {
-            let terrain_receivers = Arc::clone(&terrain_receivers_);
-            let terrain_storage = Arc::clone(&terrain_storage_);
-
-            // TODO !!! Make better
+            let interval = Duration::from_millis(TERRAIN_MAINTENANCE_INTERVAL_MS);
+            let mut last_maintenance = Instant::now();
             loop {
-                {
-                    let mut recv = terrain_receivers.lock().unwrap();
-                    recv.receive_patches(|region_id, patch| {
-                        let p = patch.patch_position();
-                        let patch_pos = Vector2::new(p.0 as u8, p.1 as u8);
-                        let data_matrix = patch.to_data();
-                        // TODO fail gracefully
-                        assert_eq!(data_matrix.nrows(), data_matrix.ncols());
-                        terrain_storage
-                            .put_patch(
-                                region_id.clone(),
-                                patch_pos,
-                                TerrainPatch::new(
-                                    region_id.clone(),
-                                    data_matrix.nrows(),
-                                    patch_pos,
-                                    data_matrix,
-                                ),
-                            )
-                            .unwrap();
-                    });
+                // Blocks until the next patch arrives or maintenance is due,
+                // and ends once the region manager was dropped.
+                match patches_rx.recv_timeout(interval) {
+                    Ok((region_id, patch)) => {
+                        let patch_pos = patch.position().clone();
+                        if let Err(e) = terrain_storage.put_patch(region_id, patch_pos, patch) {
+                            warn!(
+                                thread_log.slog_logger(),
+                                "Storing terrain patch {:?} of region {} failed: {}",
+                                (patch_pos.x, patch_pos.y),
+                                region_id,
+                                e
+                            );
+                            let mut statistics = thread_statistics.lock().unwrap();
+                            statistics
+                                .entry(region_id)
+                                .or_insert_with(PatchStatistics::default)
+                                .storage_failures += 1;
+                        }
+                    }
+                    Err(crossbeam_channel::RecvTimeoutError::Timeout) => {}
+                    Err(crossbeam_channel::RecvTimeoutError::Disconnected) => break,
+                }
+
+                if last_maintenance.elapsed() >= interval {
+                    last_maintenance = Instant::now();
+                    if let Err(e) = terrain_storage.maintain() {
+                        warn!(thread_log.slog_logger(), "Terrain maintenance failed: {}", e);
+                    }
                 }
-                thread::sleep(::std::time::Duration::from_millis(50));
             }
         });
 
+        let (events_tx, events_rx) = crossbeam_channel::unbounded();
+        let (neighbours_tx, neighbours_rx) = crossbeam_channel::unbounded();
+
         RegionManager {
             simulators: HashMap::new(),
             log,
+            connect_info: None,
+            client_avatar: Arc::clone(&storage.client_avatar),
+            agent_updater: None,
+            region_storage: Arc::clone(&storage.region),
+            region_ids: Arc::clone(&storage.region_ids),
+            region_handles: HashMap::new(),
+            sim_addresses: HashMap::new(),
+            pending_arrival: None,
+            pending_layers: HashMap::new(),
+            events_tx,
+            events_rx,
+            neighbours_tx,
+            neighbours_rx,
             terrain_storage: Arc::clone(&storage.terrain),
-            terrain_receivers,
+            patches_tx,
+            patch_statistics,
+            weather_storage: Arc::clone(&storage.weather),
+            strokes: Arc::clone(&storage.strokes),
+            texture_storage: Arc::clone(&storage.textures),
+        }
+    }
+
+    /// Returns the message handlers to be used for connecting to the
+    /// simulator at the address, these forward the messages about
+    /// neighbouring simulators, region crossings, teleports and the layers
+    /// to the region manager.
+    pub fn handlers(&self, address: SocketAddr) -> Handlers {
+        let events_tx = self.events_tx.clone();
+        let mut handlers = Handlers::default();
+        let types = [
+            MessageType::EnableSimulator,
+            MessageType::CrossedRegion,
+            MessageType::TeleportStart,
+            MessageType::TeleportProgress,
+            MessageType::TeleportFinish,
+            MessageType::TeleportFailed,
+            MessageType::AgentMovementComplete,
+        ];
+        for msg_type in types.iter() {
+            let events_tx = events_tx.clone();
+            handlers.register_type(
+                msg_type.clone(),
+                Box::new(move |msg, _| {
+                    if let Some(event) = to_sim_event(msg) {
+                        let _ = events_tx.send(event);
+                    }
+                    Ok(())
+                }),
+            );
+        }
+
+        // Only one handler is kept per message type, so the land layers are
+        // received here too, instead of through the terrain service.
+        handlers.register_type(
+            MessageType::LayerData,
+            Box::new(move |msg, _| {
+                if let MessageInstance::LayerData(msg) = msg {
+                    let _ = events_tx.send(SimEvent::LayerData { address, msg });
+                }
+                Ok(())
+            }),
+        );
+        handlers
+    }
+
+    /// Sets up a newly connected simulator, and registers its region in the
+    /// region storage.
+    ///
+    /// Returns the id of the region.
+    pub fn setup_sim(
+        &mut self,
+        sim: Simulator,
+        address: SocketAddr,
+    ) -> Result<ids::RegionId, Error> {
+        let (region, region_handle) = {
+            let region_info = sim.region_info();
+            let dims =
+                RegionDimensions::from_size(region_info.region_size_x, region_info.region_size_y)?;
+            let grid_location = region::grid_location_from_handle(region_info.region_handle);
+            let mut region = Region::new(
+                region_info.region_id.clone(),
+                self.region_ids.get_or_insert(&region_info.region_id),
+                dims,
+                grid_location,
+            );
+            region.set_composition(TerrainComposition {
+                detail_textures: [
+                    region_info.terrain_detail0.clone(),
+                    region_info.terrain_detail1.clone(),
+                    region_info.terrain_detail2.clone(),
+                    region_info.terrain_detail3.clone(),
+                ],
+                start_heights: [
+                    region_info.terrain_start_height00,
+                    region_info.terrain_start_height01,
+                    region_info.terrain_start_height10,
+                    region_info.terrain_start_height11,
+                ],
+                height_ranges: [
+                    region_info.terrain_height_range00,
+                    region_info.terrain_height_range01,
+                    region_info.terrain_height_range10,
+                    region_info.terrain_height_range11,
+                ],
+            });
+            region.set_water_height(region_info.water_height);
+            region.set_cache_id(region_info.cache_id.clone());
+            (region, region_info.region_handle)
+        };
+        let region_id = region.id().clone();
+
+        if let Err(e) = self.terrain_storage.register_region(&region) {
+            warn!(self.log.slog_logger(), "Indexing the terrain cache failed: {}", e);
+        }
+        self.simulators.insert(region_id.clone(), sim);
+        self.sim_addresses.insert(address, region_id.clone());
+        self.region_storage
+            .put(region_id.clone(), Connection::Connected(region));
+        self.region_handles
+            .insert(region_handle, Some(region_id.clone()));
+
+        // The layers which arrived while the simulator was being set up.
+        for msg in self.pending_layers.remove(&address).unwrap_or_default() {
+            self.receive_layer(address, msg);
+        }
+
+        // Finish the arrival in the region if we were waiting for it.
+        let arrived = match self.pending_arrival {
+            Some(ref arrival) => arrival.region_handle == region_handle,
+            None => false,
+        };
+        if arrived {
+            let arrival = self.pending_arrival.take().unwrap();
+            self.complete_arrival(&region_id, arrival)?;
+        }
+
+        Ok(region_id)
+    }
+
+    /// Makes a connected region the current region of the client avatar.
+    pub fn enter_region(&mut self, region_id: &ids::RegionId) -> Result<(), Error> {
+        self.enter(region_id, None)
+    }
+
+    fn enter(
+        &mut self,
+        region_id: &ids::RegionId,
+        position: Option<Vector3<f32>>,
+    ) -> Result<(), Error> {
+        self.region_storage.enter(region_id.clone())?;
+        // Region and position of the avatar have to change at once.
+        self.client_avatar
+            .write()
+            .enter_region(region_id.clone(), position);
+        if let Some(ref mut updater) = self.agent_updater {
+            updater.reset();
+        }
+        Ok(())
+    }
+
+    fn complete_arrival(
+        &mut self,
+        region_id: &ids::RegionId,
+        arrival: Arrival,
+    ) -> Result<(), Error> {
+        if arrival.teleport {
+            let previous: Vec<ids::RegionId> = self.simulators
+                .keys()
+                .filter(|id| *id != region_id)
+                .cloned()
+                .collect();
+            for id in previous {
+                self.drop_sim(&id);
+            }
+        } else if self.region_storage.presence() != Presence::InRegion(region_id.clone()) {
+            self.region_storage.start_crossing(region_id.clone())?;
+        }
+
+        self.enter(region_id, arrival.position)
+    }
+
+    /// Drops the circuit to a simulator and marks its region as disconnected.
+    fn drop_sim(&mut self, region_id: &ids::RegionId) {
+        self.simulators.remove(region_id);
+        self.region_handles
+            .retain(|_, id| id.as_ref() != Some(region_id));
+        self.sim_addresses.retain(|_, id| id != region_id);
+        self.weather_storage.remove(region_id);
+        self.terrain_storage.disconnect_region(region_id);
+        let _ = self.region_storage.disconnect(region_id);
+    }
+
+    /// Sets up the agent whose state is sent to the simulators.
+    pub fn setup_agent(&mut self, connect_info: &ConnectInfo) {
+        self.agent_updater = Some(AgentUpdater::new(
+            connect_info.agent_id.clone(),
+            connect_info.session_id.clone(),
+            Arc::clone(&self.client_avatar),
+        ));
+        self.connect_info = Some(connect_info.clone());
+    }
+
+    /// Starts connecting to a simulator, once connected it is set up by
+    /// `update`.
+    fn connect(&mut self, region_handle: u64, address: SocketAddr, handle: &Handle) {
+        if self.region_handles.contains_key(&region_handle) {
+            return;
+        }
+        let mut connect_info = match self.connect_info {
+            Some(ref info) => info.clone(),
+            None => return,
+        };
+        connect_info.sim_ip = address.ip();
+        connect_info.sim_port = address.port();
+        self.region_handles.insert(region_handle, None);
+
+        let neighbours_tx = self.neighbours_tx.clone();
+        let events_tx = self.events_tx.clone();
+        let logger = self.log.slog_logger().clone();
+        let connect = Simulator::connect(
+            connect_info,
+            self.handlers(address),
+            handle.clone(),
+            self.log.clone(),
+        );
+        handle.spawn(
+            connect
+                .map(move |sim| {
+                    let _ = neighbours_tx.send((sim, address));
+                })
+                .map_err(move |e| {
+                    warn!(logger, "Connecting to simulator failed: {}", e);
+                    let _ = events_tx.send(SimEvent::ConnectFailed {
+                        region_handle,
+                        address,
+                    });
+                }),
+        );
+    }
+
+    /// Forgets a region whose simulator could not be connected, so it is
+    /// tried again when announced the next time, and aborts the arrival in
+    /// it.
+    fn connect_failed(&mut self, region_handle: u64, address: SocketAddr) -> Result<(), Error> {
+        self.pending_layers.remove(&address);
+        if self.region_handles.get(&region_handle) == Some(&None) {
+            self.region_handles.remove(&region_handle);
+        }
+
+        let arriving = match self.pending_arrival {
+            Some(ref arrival) => arrival.region_handle == region_handle,
+            None => false,
+        };
+        if arriving {
+            let arrival = self.pending_arrival.take().unwrap();
+            // Crossings only change the presence once the region is
+            // connected, teleports are already under way.
+            if arrival.teleport {
+                self.region_storage.fail_teleport()?;
+            }
         }
+        Ok(())
+    }
+
+    /// Arrives in a region, connecting to it first if needed.
+    fn arrive(&mut self, arrival: Arrival, address: SocketAddr, handle: &Handle) {
+        let region_id = self.region_handles
+            .get(&arrival.region_handle)
+            .and_then(|id| id.clone());
+
+        match region_id {
+            Some(region_id) => {
+                if let Err(e) = self.complete_arrival(&region_id, arrival) {
+                    warn!(self.log.slog_logger(), "Arriving in region failed: {}", e);
+                }
+            }
+            None => {
+                let region_handle = arrival.region_handle;
+                self.pending_arrival = Some(arrival);
+                self.connect(region_handle, address, handle);
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: SimEvent, handle: &Handle) -> Result<(), Error> {
+        match event {
+            SimEvent::EnableSimulator {
+                region_handle,
+                address,
+            } => self.connect(region_handle, address, handle),
+            SimEvent::CrossedRegion {
+                region_handle,
+                address,
+                position,
+            } => {
+                let arrival = Arrival {
+                    region_handle,
+                    position: Some(position),
+                    teleport: false,
+                };
+                self.arrive(arrival, address, handle);
+            }
+            SimEvent::TeleportStart => self.region_storage.start_teleport()?,
+            SimEvent::TeleportProgress(message) => {
+                self.region_storage.teleport_progress(message)?
+            }
+            SimEvent::TeleportFinish {
+                region_handle,
+                address,
+            } => {
+                let arrival = Arrival {
+                    region_handle,
+                    position: None,
+                    teleport: true,
+                };
+                self.arrive(arrival, address, handle);
+            }
+            SimEvent::TeleportFailed(reason) => {
+                warn!(self.log.slog_logger(), "Teleport failed: {}", reason);
+                self.pending_arrival = None;
+                self.region_storage.fail_teleport()?;
+            }
+            SimEvent::MovementComplete { position } => {
+                self.client_avatar.write().set_position(position);
+            }
+            SimEvent::ConnectFailed {
+                region_handle,
+                address,
+            } => self.connect_failed(region_handle, address)?,
+            SimEvent::LayerData { address, msg } => self.receive_layer(address, msg),
+        }
+        Ok(())
+    }
+
+    /// Returns the statistics of the terrain patches received so far.
+    pub fn patch_statistics(&self) -> HashMap<ids::RegionId, PatchStatistics> {
+        self.patch_statistics.lock().unwrap().clone()
+    }
+
+    /// Passes a received layer on to the terrain or weather storage. Layers
+    /// of simulators which are not set up yet are kept until they are.
+    fn receive_layer(&mut self, address: SocketAddr, msg: LayerData) {
+        let region_id = match self.sim_addresses.get(&address) {
+            Some(region_id) => region_id.clone(),
+            None => {
+                self.pending_layers
+                    .entry(address)
+                    .or_insert_with(Vec::new)
+                    .push(msg);
+                return;
+            }
+        };
+        match layers::decode(&msg) {
+            Ok(Layer::Land(patches)) => self.receive_land(region_id, patches),
+            Ok(Layer::Wind(wind)) => self.weather_storage.put_wind(region_id, wind),
+            Ok(Layer::Clouds(clouds)) => self.weather_storage.put_clouds(region_id, clouds),
+            // Water and the other layers are not used.
+            Err(LayerError::UnknownType(_)) => {}
+            Err(e) => warn!(
+                self.log.slog_logger(),
+                "Dropped layer of region {}: {}", region_id, e
+            ),
+        }
+    }
+
+    /// Validates received land patches and passes them on to the terrain
+    /// storage, malformed patches are logged and dropped.
+    fn receive_land(&self, region_id: ids::RegionId, patches: Vec<LayerPatch>) {
+        let logger = self.log.slog_logger();
+        let region = self.region_storage
+            .get(&region_id)
+            .ok()
+            .and_then(|connection| connection.clone_region());
+        let region = match region {
+            Some(region) => region,
+            None => {
+                warn!(logger, "Terrain patches for unconnected region {}.", region_id);
+                return;
+            }
+        };
+        let dims = region.dimensions().clone();
+
+        let mut all_statistics = self.patch_statistics.lock().unwrap();
+        let statistics = all_statistics
+            .entry(region_id)
+            .or_insert_with(PatchStatistics::default);
+        for patch in patches {
+            let position = patch.position;
+            let mut data_matrix = patch.data;
+            match patch_validation::validate(&dims, position, &mut data_matrix) {
+                Ok(clamped) => {
+                    if clamped > 0 {
+                        warn!(
+                            logger,
+                            "Clamped {} heights of terrain patch {:?} of region {}.",
+                            clamped,
+                            position,
+                            region_id
+                        );
+                    }
+                    statistics.accepted += 1;
+                    statistics.clamped_heights += clamped;
+                }
+                Err(e) => {
+                    warn!(
+                        logger,
+                        "Dropped terrain patch {:?} of region {}: {}", position, region_id, e
+                    );
+                    statistics.rejected += 1;
+                    continue;
+                }
+            }
+
+            let patch_pos = Vector2::new(position.0 as u8, position.1 as u8);
+            let patch = TerrainPatch::new(
+                region.uuid().clone(),
+                dims.patch_size_axis as usize,
+                patch_pos,
+                data_matrix,
+            );
+            let _ = self.patches_tx.send((region_id, patch));
+        }
+    }
+
+    /// Sends the queued terraforming strokes to the simulators of their
+    /// regions. The previews of strokes which can't be sent are discarded.
+    fn send_strokes(&mut self, handle: &Handle) {
+        for (region_id, stroke) in self.strokes.take() {
+            let (sim, connect_info) = match (self.simulators.get(&region_id), &self.connect_info) {
+                (Some(sim), &Some(ref connect_info)) => (sim, connect_info),
+                _ => {
+                    warn!(
+                        self.log.slog_logger(),
+                        "Terraforming region {} failed: not connected.", region_id
+                    );
+                    self.terrain_storage.discard_previews(&region_id);
+                    continue;
+                }
+            };
+            let msg =
+                terraform::to_message(&stroke, &connect_info.agent_id, &connect_info.session_id);
+            let logger = self.log.slog_logger().clone();
+            let terrain_storage = Arc::clone(&self.terrain_storage);
+            handle.spawn(sim.send_message(msg, true).map(|_| ()).map_err(move |e| {
+                warn!(logger, "Sending terraforming request failed: {}", e);
+                terrain_storage.discard_previews(&region_id);
+            }));
+        }
+    }
+
+    /// Fetches the requested textures from the simulator of the current
+    /// region, they stay requested until there is one.
+    fn fetch_textures(&mut self, handle: &Handle) {
+        let requests = self.texture_storage.take_requests();
+        if requests.is_empty() {
+            return;
+        }
+        let current_region = self.client_avatar.read().current_region().clone();
+        let sim = match current_region.and_then(|id| self.simulators.get(&id)) {
+            Some(sim) => sim,
+            None => {
+                self.texture_storage.defer(requests);
+                return;
+            }
+        };
+
+        for id in requests {
+            let logger = self.log.slog_logger().clone();
+            let texture_storage = Arc::clone(&self.texture_storage);
+            let fetch = sim.services().textures.get_texture(&id);
+            handle.spawn(fetch.then(move |result| -> Result<(), ()> {
+                match result {
+                    Ok(texture) => {
+                        let image = texture.image.to_rgba();
+                        let (width, height) = image.dimensions();
+                        let rgba = image.into_raw();
+                        texture_storage.put(id, TextureImage { width, height, rgba });
+                    }
+                    Err(e) => {
+                        warn!(logger, "Fetching texture {} failed: {}", id, e);
+                        texture_storage.fail(id);
+                    }
+                }
+                Ok(())
+            }));
+        }
+    }
+
+    /// Performs the tasks of the region manager which are due, i.e. handling
+    /// received terrain, connections, region crossings and teleports, sending
+    /// terraforming requests, fetching textures, and sending the state of the client avatar to
+    /// the simulator of the current region.
+    ///
+    /// This should be called whenever the reactor was woken up.
+    ///
+    /// Returns the time until this should be called again at the latest.
+    pub fn update(&mut self, handle: &Handle) -> Duration {
+        while let Ok(event) = self.events_rx.try_recv() {
+            if let Err(e) = self.handle_event(event, handle) {
+                warn!(self.log.slog_logger(), "Handling simulator event failed: {}", e);
+            }
+        }
+        while let Ok((sim, address)) = self.neighbours_rx.try_recv() {
+            if let Err(e) = self.setup_sim(sim, address) {
+                warn!(self.log.slog_logger(), "Setup of simulator failed: {}", e);
+            }
+        }
+
+        self.send_strokes(handle);
+        self.fetch_textures(handle);
+
+        let current_region = self.client_avatar.read().current_region().clone();
+        let sim = match current_region {
+            Some(ref region_id) => self.simulators.get(region_id),
+            None => None,
+        };
+
+        match (self.agent_updater.as_mut(), sim) {
+            (Some(updater), Some(sim)) => updater.update(sim, handle),
+            _ => Duration::from_millis(100),
+        }
+    }
+}
+
+/// Converts the messages registered in `RegionManager::handlers`.
+fn to_sim_event(msg: MessageInstance) -> Option<SimEvent> {
+    fn address(ip: ::std::net::Ipv4Addr, port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(ip), port)
     }
 
-    pub fn setup_sim(&mut self, sim: Simulator) {
-        let region_id = sim.region_info().region_id.clone();
-        // TODO: handle potential errors
-        self.terrain_receivers
-            .lock()
-            .unwrap()
-            .register(region_id, &sim.services().terrain)
-            .unwrap();
-        self.simulators.insert(region_id, sim);
+    match msg {
+        MessageInstance::EnableSimulator(msg) => Some(SimEvent::EnableSimulator {
+            region_handle: msg.simulator_info.handle,
+            address: address(msg.simulator_info.ip, msg.simulator_info.port),
+        }),
+        MessageInstance::CrossedRegion(msg) => Some(SimEvent::CrossedRegion {
+            region_handle: msg.region_data.region_handle,
+            address: address(msg.region_data.sim_ip, msg.region_data.sim_port),
+            position: msg.info.position,
+        }),
+        MessageInstance::TeleportStart(_) => Some(SimEvent::TeleportStart),
+        MessageInstance::TeleportProgress(msg) => Some(SimEvent::TeleportProgress(
+            String::from_utf8_lossy(&msg.info.message).into_owned(),
+        )),
+        MessageInstance::TeleportFinish(msg) => Some(SimEvent::TeleportFinish {
+            region_handle: msg.info.region_handle,
+            address: address(msg.info.sim_ip, msg.info.sim_port),
+        }),
+        MessageInstance::TeleportFailed(msg) => Some(SimEvent::TeleportFailed(
+            String::from_utf8_lossy(&msg.info.reason).into_owned(),
+        )),
+        MessageInstance::AgentMovementComplete(msg) => Some(SimEvent::MovementComplete {
+            position: msg.data.position,
+        }),
+        _ => None,
     }
 }
@@ -0,0 +1,123 @@
+//! Validation of the terrain patches received from simulators, so that a
+//! malformed `LayerData` message only costs the affected patch.
+
+use data::region::RegionDimensions;
+use types::DMatrix;
+
+/// Heights below this (in meters) are clamped.
+const MIN_HEIGHT: f32 = -256.;
+
+/// Heights above this (in meters) are clamped.
+const MAX_HEIGHT: f32 = 4096.;
+
+#[derive(Debug, Fail)]
+pub enum PatchError {
+    #[fail(display = "Patch is {}x{}, expected {}x{}.", _0, _1, _2, _2)]
+    InvalidSize(usize, usize, usize),
+
+    #[fail(display = "Patch position ({}, {}) is outside of the region.", _0, _1)]
+    OutOfRange(u32, u32),
+
+    #[fail(display = "Patch contains {} heights which are not finite.", _0)]
+    NotFinite(usize),
+}
+
+/// Counts of the terrain patches received for one region.
+#[derive(Clone, Debug, Default)]
+pub struct PatchStatistics {
+    pub accepted: usize,
+    pub rejected: usize,
+    /// Heights of accepted patches which were clamped.
+    pub clamped_heights: usize,
+    /// Accepted patches which could not be put into the terrain storage.
+    pub storage_failures: usize,
+}
+
+/// Checks a received patch against the dimensions of its region.
+///
+/// Patches with heights which are not finite are rejected, finite heights
+/// outside of a plausible range are clamped. Returns the number of clamped
+/// heights.
+pub fn validate(
+    dims: &RegionDimensions,
+    position: (u32, u32),
+    heights: &mut DMatrix<f32>,
+) -> Result<usize, PatchError> {
+    let size = dims.patch_size_axis as usize;
+    if heights.shape() != (size, size) {
+        let (rows, cols) = heights.shape();
+        return Err(PatchError::InvalidSize(rows, cols, size));
+    }
+
+    let pps = dims.patches_per_side as u32;
+    if position.0 >= pps || position.1 >= pps {
+        return Err(PatchError::OutOfRange(position.0, position.1));
+    }
+
+    let not_finite = heights.iter().filter(|h| !h.is_finite()).count();
+    if not_finite > 0 {
+        return Err(PatchError::NotFinite(not_finite));
+    }
+
+    let mut clamped = 0;
+    for h in heights.iter_mut() {
+        if *h < MIN_HEIGHT || *h > MAX_HEIGHT {
+            *h = h.max(MIN_HEIGHT).min(MAX_HEIGHT);
+            clamped += 1;
+        }
+    }
+    Ok(clamped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dims() -> RegionDimensions {
+        RegionDimensions::from_size(256, 256).unwrap()
+    }
+
+    #[test]
+    fn valid_patch_is_accepted() {
+        let mut heights = DMatrix::from_element(16, 16, 21.5);
+        assert_eq!(validate(&dims(), (15, 0), &mut heights).unwrap(), 0);
+    }
+
+    #[test]
+    fn wrong_size_is_rejected() {
+        let mut heights = DMatrix::from_element(16, 32, 0.);
+        match validate(&dims(), (0, 0), &mut heights) {
+            Err(PatchError::InvalidSize(16, 32, 16)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn position_outside_of_region_is_rejected() {
+        let mut heights = DMatrix::from_element(16, 16, 0.);
+        match validate(&dims(), (3, 16), &mut heights) {
+            Err(PatchError::OutOfRange(3, 16)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn nan_is_rejected() {
+        let mut heights = DMatrix::from_element(16, 16, 0.);
+        heights[(4, 5)] = ::std::f32::NAN;
+        match validate(&dims(), (0, 0), &mut heights) {
+            Err(PatchError::NotFinite(1)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn absurd_heights_are_clamped() {
+        let mut heights = DMatrix::from_element(16, 16, 0.);
+        heights[(0, 0)] = 1e9;
+        heights[(1, 0)] = -1e9;
+        assert_eq!(validate(&dims(), (0, 0), &mut heights).unwrap(), 2);
+        assert_eq!(heights[(0, 0)], MAX_HEIGHT);
+        assert_eq!(heights[(1, 0)], MIN_HEIGHT);
+    }
+}
@@ -0,0 +1,73 @@
+//! Sends the terraforming strokes of the client to the simulators, as
+//! `ModifyLand` requests.
+
+use data::terraform::Stroke;
+use opensim_networking::messages::all::{ModifyLand, ModifyLand_AgentData,
+                                        ModifyLand_ModifyBlock,
+                                        ModifyLand_ModifyBlockExtended, ModifyLand_ParcelData};
+use types::Uuid;
+
+/// Local id of the parcel data block which addresses a point of the region,
+/// instead of a parcel.
+const NO_PARCEL: i32 = -1;
+
+pub fn to_message(stroke: &Stroke, agent_id: &Uuid, session_id: &Uuid) -> ModifyLand {
+    ModifyLand {
+        agent_data: ModifyLand_AgentData {
+            agent_id: agent_id.clone(),
+            session_id: session_id.clone(),
+        },
+        modify_block: ModifyLand_ModifyBlock {
+            action: stroke.brush.action(),
+            brush_size: stroke.legacy_brush_size(),
+            seconds: stroke.seconds,
+            height: stroke.height,
+        },
+        // A single point, the simulator applies the brush around it.
+        parcel_data: vec![ModifyLand_ParcelData {
+            local_id: NO_PARCEL,
+            west: stroke.center.x,
+            south: stroke.center.y,
+            east: stroke.center.x,
+            north: stroke.center.y,
+        }],
+        modify_block_extended: vec![ModifyLand_ModifyBlockExtended {
+            brush_size: stroke.radius,
+        }],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data::terraform::Brush;
+    use types::Vector2;
+
+    #[test]
+    fn stroke_is_sent_as_point() {
+        let stroke = Stroke {
+            brush: Brush::Lower,
+            center: Vector2::new(100., 50.),
+            radius: 1.5,
+            seconds: 0.25,
+            height: 30.,
+        };
+        let agent_id = Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap();
+        let session_id = Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap();
+        let msg = to_message(&stroke, &agent_id, &session_id);
+
+        assert_eq!(msg.agent_data.agent_id, agent_id);
+        assert_eq!(msg.agent_data.session_id, session_id);
+        assert_eq!(msg.modify_block.action, 2);
+        assert_eq!(msg.modify_block.brush_size, 1);
+        assert_eq!(msg.modify_block.seconds, 0.25);
+        assert_eq!(msg.modify_block.height, 30.);
+        assert_eq!(msg.parcel_data.len(), 1);
+        let parcel = &msg.parcel_data[0];
+        assert_eq!(parcel.local_id, NO_PARCEL);
+        assert_eq!((parcel.west, parcel.east), (100., 100.));
+        assert_eq!((parcel.south, parcel.north), (50., 50.));
+        assert_eq!(msg.modify_block_extended.len(), 1);
+        assert_eq!(msg.modify_block_extended[0].brush_size, 1.5);
+    }
+}
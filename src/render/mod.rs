@@ -2,47 +2,368 @@
 //!
 //! Targets OpenGL 3.1 and GLSL 1.40 for now.
 
-use data::avatar::ClientAvatar;
-use data::{self, Storage};
+use data::avatar::{Avatar, ClientAvatar};
+use data::region::Region;
+use data::terraform::{Brush, Stroke};
+use data::terrain::{RayHit, TerrainStorage};
+use data::texture::TextureStorage;
+use data::{self, ids, Storage};
 use glium::index::PrimitiveType;
 use glium::{self, glutin, Surface};
 use parking_lot::RwLock;
+use slog::Logger;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 use typed_rwlock::{RwLockReader, RwLockWriter};
-use types::Vector3;
+use types::{Vector2, Vector3};
+
+pub mod culling {
+    use types::nalgebra::Vector4;
+    use types::{Matrix4, Vector3};
+
+    /// Axis aligned bounding box.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct BoundingBox {
+        pub min: Vector3<f32>,
+        pub max: Vector3<f32>,
+    }
+
+    impl BoundingBox {
+        pub fn translated(&self, offset: &Vector3<f32>) -> BoundingBox {
+            BoundingBox {
+                min: self.min + offset,
+                max: self.max + offset,
+            }
+        }
+
+        /// Returns the distance from a point to the closest point of the box,
+        /// which is 0 for points inside of it.
+        pub fn distance_to(&self, point: &Vector3<f32>) -> f32 {
+            let axis = |p: f32, min: f32, max: f32| (min - p).max(p - max).max(0.);
+            Vector3::new(
+                axis(point.x, self.min.x, self.max.x),
+                axis(point.y, self.min.y, self.max.y),
+                axis(point.z, self.min.z, self.max.z),
+            ).norm()
+        }
+    }
+
+    /// The volume visible through the camera, bounded by six planes.
+    pub struct Frustum {
+        /// The planes `(a, b, c, d)` with `a*x + b*y + c*z + d >= 0` for
+        /// points inside.
+        planes: [Vector4<f32>; 6],
+    }
+
+    impl Frustum {
+        /// Extracts the planes from the combined projection and view matrix,
+        /// i.e. `persp_matrix * view_matrix`, with the OpenGL convention of
+        /// clip space.
+        pub fn from_matrix(m: &Matrix4<f32>) -> Self {
+            let row = |i: usize| Vector4::new(m[(i, 0)], m[(i, 1)], m[(i, 2)], m[(i, 3)]);
+            let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+            Frustum {
+                planes: [w + x, w - x, w + y, w - y, w + z, w - z],
+            }
+        }
+
+        /// Whether the box is (possibly) visible.
+        ///
+        /// Boxes are only rejected if they are completely outside of one of
+        /// the planes, so some boxes near the corners of the frustum are
+        /// reported as visible although they are not.
+        pub fn intersects(&self, bounds: &BoundingBox) -> bool {
+            self.planes.iter().all(|p| {
+                // The corner furthest inside of the plane.
+                let x = if p.x >= 0. { bounds.max.x } else { bounds.min.x };
+                let y = if p.y >= 0. { bounds.max.y } else { bounds.min.y };
+                let z = if p.z >= 0. { bounds.max.z } else { bounds.min.z };
+                p.x * x + p.y * y + p.z * z + p.w >= 0.
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::f32::consts::PI;
+
+        fn unit_box(x: f32, y: f32, z: f32) -> BoundingBox {
+            BoundingBox {
+                min: Vector3::new(x - 0.5, y - 0.5, z - 0.5),
+                max: Vector3::new(x + 0.5, y + 0.5, z + 0.5),
+            }
+        }
+
+        /// Looking down the negative z axis, with a field of view of 90°
+        /// and a far plane at 100.
+        fn frustum() -> Frustum {
+            Frustum::from_matrix(&Matrix4::new_perspective(1., PI / 2., 0.1, 100.))
+        }
+
+        #[test]
+        fn boxes_in_view_are_visible() {
+            assert!(frustum().intersects(&unit_box(0., 0., -10.)));
+            assert!(frustum().intersects(&unit_box(9., -9., -10.)));
+            // Partly inside.
+            assert!(frustum().intersects(&unit_box(10.2, 0., -10.)));
+            assert!(frustum().intersects(&unit_box(0., 0., -100.)));
+        }
+
+        #[test]
+        fn boxes_out_of_view_are_culled() {
+            // Behind the camera.
+            assert!(!frustum().intersects(&unit_box(0., 0., 10.)));
+            // Beside the field of view.
+            assert!(!frustum().intersects(&unit_box(12., 0., -10.)));
+            assert!(!frustum().intersects(&unit_box(0., -12., -10.)));
+            // Beyond the far plane.
+            assert!(!frustum().intersects(&unit_box(0., 0., -101.)));
+        }
+
+        #[test]
+        fn view_transformation_is_applied() {
+            // Camera moved to x = 50, the box at the origin is out of view.
+            let view = Matrix4::new_translation(&Vector3::new(-50., 0., 0.));
+            let persp = Matrix4::new_perspective(1., PI / 2., 0.1, 100.);
+            let frustum = Frustum::from_matrix(&(persp * view));
+            assert!(!frustum.intersects(&unit_box(0., 0., -10.)));
+            assert!(frustum.intersects(&unit_box(50., 0., -10.)));
+        }
+
+        #[test]
+        fn distance_to_box() {
+            let b = unit_box(0., 0., 0.);
+            assert_eq!(b.distance_to(&Vector3::new(0.2, -0.3, 0.1)), 0.);
+            assert_eq!(b.distance_to(&Vector3::new(3.5, 0., 0.)), 3.);
+            assert_eq!(b.distance_to(&Vector3::new(3.5, 4.5, 0.)), 5.);
+            let moved = b.translated(&Vector3::new(10., 0., 0.));
+            assert_eq!(moved.distance_to(&Vector3::new(3.5, 0., 0.)), 6.);
+        }
+    }
+}
 
 pub mod terrain_land {
-    use data::region::RegionDimensions;
+    use super::culling::BoundingBox;
+    use data::region::{Region, TerrainComposition};
     use data::terrain::{self, TerrainStorage};
     use data::{self, ids};
     use failure::Error;
+    use std::ops::Range;
     use std::sync::Arc;
-    use types::{nalgebra, DMatrix, Vector2};
+    use types::{nalgebra, DMatrix, Vector2, Vector3};
 
     #[derive(Copy, Clone)]
     pub struct Vertex {
         position: [f32; 3],
-        //color: [f32; 3],
+        /// Unit normal of the terrain surface, in world coordinates.
+        normal: [f32; 3],
+        /// Weights of the four detail textures, summing up to 1.
+        blend: [f32; 4],
     }
 
-    implement_vertex!(Vertex, position);
+    implement_vertex!(Vertex, position, normal, blend);
+
+    /// Solid colours of the four detail layers, which are drawn instead of
+    /// the detail textures until these are loaded.
+    pub const DETAIL_COLORS: [[f32; 3]; 4] = [
+        [0.45, 0.36, 0.25],
+        [0.32, 0.46, 0.18],
+        [0.47, 0.45, 0.42],
+        [0.92, 0.92, 0.95],
+    ];
+
+    /// Pseudo random value in `[-1, 1]` for an integer lattice point.
+    fn lattice_value(x: i32, y: i32) -> f32 {
+        let mut n = (x as u32).wrapping_mul(374_761_393) ^ (y as u32).wrapping_mul(668_265_263);
+        n = (n ^ (n >> 13)).wrapping_mul(1_274_126_177);
+        n = n ^ (n >> 16);
+        (n & 0xffff) as f32 / 32767.5 - 1.
+    }
+
+    /// Smoothly interpolated value noise in `[-1, 1]`, with a feature size
+    /// of one unit.
+    fn noise(x: f32, y: f32) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (sx, sy) = (fx * fx * (3. - 2. * fx), fy * fy * (3. - 2. * fy));
+        let (x0, y0) = (x0 as i32, y0 as i32);
+
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let bottom = lerp(lattice_value(x0, y0), lattice_value(x0 + 1, y0), sx);
+        let top = lerp(lattice_value(x0, y0 + 1), lattice_value(x0 + 1, y0 + 1), sx);
+        lerp(bottom, top, sy)
+    }
+
+    /// Sum of noise octaves up to `freq`, with decreasing amplitude.
+    fn turbulence(x: f32, y: f32, freq: f32) -> f32 {
+        let mut t = 0.;
+        let mut f = 1.;
+        while f <= freq {
+            t += noise(x * f, y * f) / f;
+            f *= 2.;
+        }
+        t
+    }
+
+    /// Bilinear interpolation of per corner values, indexed like in
+    /// `TerrainComposition`, at a relative position in the region.
+    fn interpolate_corners(corners: &[f32; 4], rel: Vector2<f32>) -> f32 {
+        let south = corners[0] * (1. - rel.x) + corners[2] * rel.x;
+        let north = corners[1] * (1. - rel.x) + corners[3] * rel.x;
+        south * (1. - rel.y) + north * rel.y
+    }
+
+    /// Computes the weights of the four detail textures at a point of the
+    /// terrain.
+    ///
+    /// As in the official viewer the elevation is perturbed by noise before
+    /// it is mapped onto the layers, so the layer borders do not follow the
+    /// contour lines. The low frequency noise is scaled up on steep terrain
+    /// by `slope_squared` (the squared horizontal part of the normal).
+    ///
+    /// `global` is the position of the point on the grid in meters, which
+    /// keeps the noise continuous across regions, and `rel` its position
+    /// relative to the region, in `[0, 1]` on both axes.
+    pub fn blend_weights(
+        composition: &TerrainComposition,
+        height: f32,
+        global: Vector2<f32>,
+        rel: Vector2<f32>,
+        slope_squared: f32,
+    ) -> [f32; 4] {
+        let start = interpolate_corners(&composition.start_heights, rel);
+        let range = interpolate_corners(&composition.height_ranges, rel).max(0.01);
+
+        let (nx, ny) = (global.x * 0.2, global.y * 0.2);
+        let twiddle = noise(nx, ny) * 6.5 + turbulence(nx, ny, 2.) * slope_squared;
+        let scaled = ((height + twiddle - start) * 4. / range).max(0.).min(3.);
+
+        let lower = (scaled.floor() as usize).min(2);
+        let frac = scaled - lower as f32;
+        let mut weights = [0.; 4];
+        weights[lower] = 1. - frac;
+        weights[lower + 1] = frac;
+        weights
+    }
+
+    /// Computes the normal of a heightmap grid point with unit spacing from
+    /// the heights of its neighbours, using central differences.
+    ///
+    /// `dist_x` and `dist_y` are the distances between the neighbours on
+    /// each axis, which is less than 2 at the edges of the grid, where the
+    /// point itself has to be used instead of its missing neighbour. If it is
+    /// zero the slope along that axis is taken to be flat.
+    pub fn grid_normal(
+        h_left: f32,
+        h_right: f32,
+        h_down: f32,
+        h_up: f32,
+        dist_x: f32,
+        dist_y: f32,
+    ) -> [f32; 3] {
+        let dh_dx = if dist_x > 0. {
+            (h_right - h_left) / dist_x
+        } else {
+            0.
+        };
+        let dh_dy = if dist_y > 0. {
+            (h_up - h_down) / dist_y
+        } else {
+            0.
+        };
+        let len = (dh_dx * dh_dx + dh_dy * dh_dy + 1.).sqrt();
+        [-dh_dx / len, -dh_dy / len, 1. / len]
+    }
+
+    /// Distance in meters up to which patches are drawn at full resolution.
+    const LOD_FULL_DISTANCE: f32 = 48.;
+
+    /// Returns the distance between the drawn grid points of a patch at a
+    /// horizontal distance from the eye.
+    ///
+    /// Beyond `LOD_FULL_DISTANCE` the step doubles each time the distance
+    /// doubles, up to the size of the patch (which is a power of two).
+    pub fn lod_step(distance: f32, patch_size: usize) -> usize {
+        let mut step = 1;
+        let mut limit = LOD_FULL_DISTANCE;
+        while distance > limit && step < patch_size {
+            step *= 2;
+            limit *= 2.;
+        }
+        step
+    }
+
+    /// The triangles of one loaded patch.
+    pub struct PatchIndices {
+        /// Bounds of the patch, relative to the region.
+        pub bounds: BoundingBox,
+        /// The range of the patch's triangles in the indices.
+        pub range: Range<usize>,
+    }
 
     /// Render state for land layer terrain data of one region.
+    ///
+    /// The land of the whole region is represented by one grid of vertices,
+    /// one vertex per meter, with an additional row and column at the far
+    /// edges of the region. This way the cells between two adjacent patches
+    /// are triangulated too, and the triangles are described by indices into
+    /// this grid.
+    ///
+    /// Depending on their distance from the eye, patches are triangulated
+    /// with only every second, fourth, ... grid point (geomipmapping).
     pub struct RenderState {
         region_id: ids::RegionId,
+        composition: TerrainComposition,
+        /// Position of the region's origin on the grid, in meters.
+        origin: Vector2<f32>,
+        side_meters: f32,
+
+        /// Number of vertices per side of the grid.
+        grid_side: usize,
+        /// Number of patches per side of the region.
+        patches_per_side: usize,
+        /// Number of cells per side of one patch.
+        patch_size: usize,
+
         vertices: Vec<Vertex>,
-        /// Current write offset.
-        vertices_offset: usize,
+        /// Whether the height of a vertex is known, either from its own
+        /// patch or by extending the edge of a neighbouring patch.
+        vertices_valid: Vec<bool>,
+        indices: Vec<u32>,
+
+        /// Which patches were already added to the vertex grid.
+        ///
+        /// Indexed by `patch_x * patches_per_side + patch_y`.
+        patches_loaded: Vec<bool>,
+        /// The generations of the patches added to the vertex grid, indexed
+        /// like `patches_loaded`.
+        patches_generation: Vec<usize>,
 
-        /// Patches which yet have to be added the vertices vector.
+        /// Patches which yet have to be (re)added to the vertices vector.
+        ///
+        /// Initially these are all patches, afterwards only the patches
+        /// reported as changed by the terrain storage.
         patches_pending: Vec<data::terrain::PatchPosition>,
+
+        /// Position of the eye relative to the region, for which the levels
+        /// of detail were chosen.
+        eye: Vector2<f32>,
+        /// The step (see `lod_step`) each patch is triangulated with,
+        /// indexed like `patches_loaded`.
+        patches_step: Vec<usize>,
+        /// The triangles of the loaded patches, as currently in `indices`.
+        patch_indices: Vec<PatchIndices>,
     }
 
     impl RenderState {
-        pub fn new(region_id: ids::RegionId, reg_dims: &RegionDimensions) -> Self {
+        pub fn new(region: &Region) -> Self {
+            let reg_dims = region.dimensions();
             let pps = reg_dims.patches_per_side as usize;
+            let patch_size = reg_dims.patch_size_axis as usize;
+            let grid_side = pps * patch_size + 1;
 
             let mut patches_pending = Vec::new();
             for patch_x in 0..pps {
@@ -51,24 +372,54 @@ pub mod terrain_land {
                 }
             }
 
-            let mut vertices = Vec::new();
-            let v_per_patch = 6 * (reg_dims.patch_size_axis as usize - 1).pow(2);
-            let v_tot = pps * pps * v_per_patch;
-            for _ in 0..v_tot {
-                vertices.push(Vertex {
-                    position: [0., 0., 0.],
-                });
+            let mut vertices = Vec::with_capacity(grid_side * grid_side);
+            for x in 0..grid_side {
+                for y in 0..grid_side {
+                    vertices.push(Vertex {
+                        position: [x as f32, y as f32, 0.],
+                        normal: [0., 0., 1.],
+                        blend: [1., 0., 0., 0.],
+                    });
+                }
             }
 
+            let grid_location = region.grid_location();
             RenderState {
-                region_id,
+                region_id: region.id().clone(),
+                composition: region.composition().clone(),
+                origin: Vector2::new(
+                    grid_location.x as f32 * 256.,
+                    grid_location.y as f32 * 256.,
+                ),
+                side_meters: reg_dims.side_meters as f32,
+                grid_side,
+                patches_per_side: pps,
+                patch_size,
+                vertices_valid: vec![false; vertices.len()],
                 vertices,
-                vertices_offset: 0,
+                indices: Vec::new(),
+                patches_loaded: vec![false; pps * pps],
+                patches_generation: vec![0; pps * pps],
                 patches_pending,
+                eye: Vector2::new(0., 0.),
+                patches_step: vec![1; pps * pps],
+                patch_indices: Vec::new(),
             }
         }
 
-        /// Tries to update the vertices with new available terrain patches.
+        /// Marks a patch which was put into the terrain storage, so it is
+        /// (re)added by the next `update`.
+        pub fn patch_changed(&mut self, patch_pos: data::terrain::PatchPosition) {
+            if !self.patches_pending.contains(&patch_pos) {
+                self.patches_pending.push(patch_pos);
+            }
+        }
+
+        /// Tries to update the vertices with the pending terrain patches.
+        ///
+        /// Patches not available in the storage are dropped from the pending
+        /// patches, they are marked again by `patch_changed` once they
+        /// arrive.
         ///
         /// Returns an error if there was one. Otherwise, if and only if some
         /// new vertices are added `Ok(true)` is returned, else
@@ -77,72 +428,1373 @@ pub mod terrain_land {
             let mut res: Result<(), Error> = Ok(());
 
             let region_id = self.region_id.clone();
+            let handles: Vec<_> = self.patches_pending
+                .iter()
+                .map(|pos| (region_id, *pos))
+                .collect();
+            // Read before the patches, see `TerrainStorage::generation`.
+            let generations: Vec<usize> = handles.iter().map(|h| storage.generation(h)).collect();
+            let results = storage.get_patches(&handles);
+
             let mut patches = Vec::new();
-            self.patches_pending
-                .retain(|&pos| match storage.get_patch(&(region_id, pos)) {
+            let mut still_pending = Vec::new();
+            let fetched = handles.into_iter().zip(generations.into_iter());
+            for ((handle, generation), result) in fetched.zip(results.into_iter()) {
+                match result {
                     Ok(patch) => {
-                        patches.push(patch);
-                        false
+                        // Skip patches which did not change since they were added.
+                        let index = self.patch_index(&handle.1);
+                        let changed = !self.patches_loaded[index]
+                            || generation != self.patches_generation[index];
+                        if changed {
+                            self.patches_generation[index] = generation;
+                            patches.push(patch);
+                        }
                     }
-                    Err(terrain::StorageError::NotFound) => true,
+                    // Corrupt patches were dropped, they arrive again like missing ones.
+                    Err(terrain::StorageError::NotFound)
+                    | Err(terrain::StorageError::Corrupt(_)) => {}
                     Err(terrain::StorageError::Cache(e)) => {
                         res = Err(e.into());
-                        true
+                        still_pending.push(handle.1);
                     }
-                });
+                }
+            }
+            self.patches_pending = still_pending;
 
             for patch in patches.iter() {
-                self.add_vertices(patch);
+                self.add_patch(patch);
+            }
+            if patches.len() > 0 {
+                self.rebuild_indices();
             }
 
             res?;
             Ok(patches.len() > 0)
         }
 
+        /// Chooses the levels of detail of the patches for a new position of
+        /// the eye, relative to the region.
+        ///
+        /// Returns whether they changed, in which case the indices were
+        /// rebuilt.
+        pub fn update_lod(&mut self, eye: Vector2<f32>) -> bool {
+            self.eye = eye;
+            let pps = self.patches_per_side;
+            let mut steps = Vec::with_capacity(pps * pps);
+            for patch_x in 0..pps {
+                for patch_y in 0..pps {
+                    steps.push(self.step_at(patch_x as i64, patch_y as i64));
+                }
+            }
+            if steps == self.patches_step {
+                return false;
+            }
+            self.patches_step = steps;
+            self.rebuild_indices();
+            true
+        }
+
+        /// The number of triangles currently drawn.
+        pub fn triangle_count(&self) -> usize {
+            self.indices.len() / 3
+        }
+
+        /// The number of triangles the loaded patches would have at full
+        /// resolution.
+        pub fn full_triangle_count(&self) -> usize {
+            let loaded = self.patches_loaded.iter().filter(|l| **l).count();
+            loaded * self.patch_size * self.patch_size * 2
+        }
+
         pub fn vertices(&self) -> &[Vertex] {
             &self.vertices[..]
         }
 
-        /// For each grid cell two triangles, i.e. 6 vertices, are inserted.
-        fn add_vertices(&mut self, patch: &data::terrain::TerrainPatch) {
-            let size = patch.size();
-            let offset = Vector2::new(
-                patch.position()[0] as usize * size,
-                patch.position()[1] as usize * size,
-            );
+        pub fn indices(&self) -> &[u32] {
+            &self.indices[..]
+        }
+
+        pub fn patch_indices(&self) -> &[PatchIndices] {
+            &self.patch_indices[..]
+        }
+
+        fn patch_index(&self, patch_pos: &data::terrain::PatchPosition) -> usize {
+            patch_pos[0] as usize * self.patches_per_side + patch_pos[1] as usize
+        }
+
+        fn vertex_index(&self, x: usize, y: usize) -> usize {
+            x * self.grid_side + y
+        }
+
+        fn set_height(&mut self, x: usize, y: usize, height: f32) {
+            let index = self.vertex_index(x, y);
+            self.vertices[index].position[2] = height;
+            self.vertices_valid[index] = true;
+        }
+
+        fn height(&self, x: usize, y: usize) -> f32 {
+            self.vertices[self.vertex_index(x, y)].position[2]
+        }
+
+        /// Recomputes the normals and the blend weights of the grid points in
+        /// the (inclusive) ranges, which are clamped to the grid.
+        ///
+        /// Neighbours outside of the grid or with unknown height are replaced
+        /// by the point itself.
+        fn update_shading(&mut self, x_min: usize, x_max: usize, y_min: usize, y_max: usize) {
+            let last = self.grid_side - 1;
+            for x in x_min..(x_max.min(last) + 1) {
+                for y in y_min..(y_max.min(last) + 1) {
+                    if !self.vertices_valid[self.vertex_index(x, y)] {
+                        continue;
+                    }
 
-            for x1 in 0..(size - 1) {
-                for y1 in 0..(size - 1) {
-                    let mut add_vertex = |x_rel: usize, y_rel: usize| {
-                        let x_abs = x_rel + offset[0] as usize;
-                        let y_abs = y_rel + offset[1] as usize;
-                        self.vertices[self.vertices_offset] = Vertex {
-                            position: [
-                                x_abs as f32,
-                                y_abs as f32,
-                                patch.land_heightmap()[(x_rel, y_rel)],
-                            ],
+                    let normal = {
+                        let valid = |x: usize, y: usize| {
+                            self.vertices_valid[self.vertex_index(x, y)]
                         };
-                        self.vertices_offset += 1;
+                        let x0 = if x > 0 && valid(x - 1, y) { x - 1 } else { x };
+                        let x1 = if x < last && valid(x + 1, y) { x + 1 } else { x };
+                        let y0 = if y > 0 && valid(x, y - 1) { y - 1 } else { y };
+                        let y1 = if y < last && valid(x, y + 1) { y + 1 } else { y };
+                        grid_normal(
+                            self.height(x0, y),
+                            self.height(x1, y),
+                            self.height(x, y0),
+                            self.height(x, y1),
+                            (x1 - x0) as f32,
+                            (y1 - y0) as f32,
+                        )
+                    };
+                    let rel = Vector2::new(x as f32, y as f32) / self.side_meters;
+                    let global = self.origin + Vector2::new(x as f32, y as f32);
+                    let blend = blend_weights(
+                        &self.composition,
+                        self.height(x, y),
+                        global,
+                        rel,
+                        normal[0] * normal[0] + normal[1] * normal[1],
+                    );
+
+                    let index = self.vertex_index(x, y);
+                    self.vertices[index].normal = normal;
+                    self.vertices[index].blend = blend;
+                }
+            }
+        }
+
+        /// Patches outside of the region are never considered loaded.
+        fn is_loaded(&self, patch_x: usize, patch_y: usize) -> bool {
+            let pps = self.patches_per_side;
+            patch_x < pps && patch_y < pps && self.patches_loaded[patch_x * pps + patch_y]
+        }
+
+        /// Writes the heights of a patch into the vertex grid.
+        ///
+        /// The first row and column of neighbouring patches which have not
+        /// arrived yet (or which are outside of the region) are filled with
+        /// the edge heights of this patch, so the cells along the edge can
+        /// already be drawn. Once the neighbour arrives it overwrites them.
+        fn add_patch(&mut self, patch: &data::terrain::TerrainPatch) {
+            let size = self.patch_size;
+            let heightmap = patch.land_heightmap();
+            let patch_x = patch.position()[0] as usize;
+            let patch_y = patch.position()[1] as usize;
+            let offset_x = patch_x * size;
+            let offset_y = patch_y * size;
+
+            for x in 0..size {
+                for y in 0..size {
+                    self.set_height(offset_x + x, offset_y + y, heightmap[(x, y)]);
+                }
+            }
+            let index = patch_x * self.patches_per_side + patch_y;
+            self.patches_loaded[index] = true;
+
+            let free_x = !self.is_loaded(patch_x + 1, patch_y);
+            let free_y = !self.is_loaded(patch_x, patch_y + 1);
+            let free_xy = !self.is_loaded(patch_x + 1, patch_y + 1);
+
+            if free_x {
+                for y in 0..size {
+                    self.set_height(offset_x + size, offset_y + y, heightmap[(size - 1, y)]);
+                }
+            }
+            if free_y {
+                for x in 0..size {
+                    self.set_height(offset_x + x, offset_y + size, heightmap[(x, size - 1)]);
+                }
+            }
+            // If one of the neighbours is loaded it has already filled the corner.
+            if free_x && free_y && free_xy {
+                let height = heightmap[(size - 1, size - 1)];
+                self.set_height(offset_x + size, offset_y + size, height);
+            }
+
+            // The normals of the neighbours' edges depend on this patch too.
+            self.update_shading(
+                offset_x.saturating_sub(1),
+                offset_x + size + 1,
+                offset_y.saturating_sub(1),
+                offset_y + size + 1,
+            );
+        }
+
+        /// The step of a patch, which may be outside of the region.
+        ///
+        /// It only depends on the position of the patch relative to the eye,
+        /// so neighbouring regions agree on the steps along their borders.
+        fn step_at(&self, patch_x: i64, patch_y: i64) -> usize {
+            let size = self.patch_size as f32;
+            let center = Vector2::new((patch_x as f32 + 0.5) * size, (patch_y as f32 + 0.5) * size);
+            lod_step((center - self.eye).norm(), self.patch_size)
+        }
+
+        fn rebuild_indices(&mut self) {
+            let pps = self.patches_per_side;
+
+            let size = self.patch_size as f32;
+            let mut indices = Vec::new();
+            let mut patch_indices = Vec::new();
+            for patch_x in 0..pps {
+                for patch_y in 0..pps {
+                    if !self.is_loaded(patch_x, patch_y) {
+                        continue;
+                    }
+
+                    let start = indices.len();
+                    self.triangulate_patch(patch_x, patch_y, &mut indices);
+
+                    let (min_height, max_height) = self.drawn_height_bounds(patch_x, patch_y);
+                    let (x, y) = (patch_x as f32 * size, patch_y as f32 * size);
+                    patch_indices.push(PatchIndices {
+                        bounds: BoundingBox {
+                            min: Vector3::new(x, y, min_height),
+                            max: Vector3::new(x + size, y + size, max_height),
+                        },
+                        range: start..indices.len(),
+                    });
+                }
+            }
+            self.indices = indices;
+            self.patch_indices = patch_indices;
+        }
+
+        /// Lowest and highest vertex the triangles of a patch can reach,
+        /// including the first row and column of the neighbouring patches
+        /// it is stitched to.
+        fn drawn_height_bounds(&self, patch_x: usize, patch_y: usize) -> (f32, f32) {
+            let size = self.patch_size;
+            let (x0, y0) = (patch_x * size, patch_y * size);
+            let mut bounds = (::std::f32::INFINITY, ::std::f32::NEG_INFINITY);
+            for x in x0..x0 + size + 1 {
+                for y in y0..y0 + size + 1 {
+                    let height = self.vertices[self.vertex_index(x, y)].position[2];
+                    bounds = (bounds.0.min(height), bounds.1.max(height));
+                }
+            }
+            bounds
+        }
+
+        /// Appends the triangles of a loaded patch to `indices`.
+        ///
+        /// The inner part of the patch is a regular grid with the step of
+        /// the patch. It is connected to each edge by a strip of triangles,
+        /// which along edges to patches with a larger step uses only the
+        /// grid points of the neighbour. Therefore there are no T-junctions,
+        /// which would show up as cracks, between the levels of detail.
+        fn triangulate_patch(&self, patch_x: usize, patch_y: usize, indices: &mut Vec<u32>) {
+            let size = self.patch_size as i64;
+            let step = self.patches_step[patch_x * self.patches_per_side + patch_y] as i64;
+            let (px, py) = (patch_x as i64, patch_y as i64);
+            let (x0, y0) = (px * size, py * size);
+            let (x1, y1) = (x0 + size, y0 + size);
+
+            let mut triangle = |a: (i64, i64), b: (i64, i64), c: (i64, i64)| {
+                for p in [a, b, c].iter() {
+                    indices.push(self.vertex_index(p.0 as usize, p.1 as usize) as u32);
+                }
+            };
+
+            if step == size {
+                triangle((x0, y0), (x1, y0), (x0, y1));
+                triangle((x1, y1), (x0, y1), (x1, y0));
+                return;
+            }
+
+            // The inner grid.
+            let cells = size / step;
+            for i in 1..(cells - 1) {
+                for j in 1..(cells - 1) {
+                    let (xa, ya) = (x0 + i * step, y0 + j * step);
+                    let (xb, yb) = (xa + step, ya + step);
+                    triangle((xa, ya), (xb, ya), (xa, yb));
+                    triangle((xb, yb), (xa, yb), (xb, ya));
+                }
+            }
+
+            // The strips along the edges, counter-clockwise: starting corner,
+            // direction along the edge, direction into the patch and the
+            // neighbour across the edge.
+            let sides = [
+                ((x0, y0), (1, 0), (0, 1), (px, py - 1)),
+                ((x1, y0), (0, 1), (-1, 0), (px + 1, py)),
+                ((x1, y1), (-1, 0), (0, -1), (px, py + 1)),
+                ((x0, y1), (0, -1), (1, 0), (px - 1, py)),
+            ];
+            for &(corner, along, inward, neighbour) in sides.iter() {
+                let edge_step = step.max(self.step_at(neighbour.0, neighbour.1) as i64);
+                let outer = |k: i64| {
+                    let d = k * edge_step;
+                    (corner.0 + along.0 * d, corner.1 + along.1 * d)
+                };
+                let inner = |k: i64| {
+                    let d = step + k * step;
+                    (
+                        corner.0 + along.0 * d + inward.0 * step,
+                        corner.1 + along.1 * d + inward.1 * step,
+                    )
+                };
+
+                // Zip the two chains of points together, always advancing on
+                // the chain whose next point is closer to the start.
+                let outer_segments = size / edge_step;
+                let inner_segments = cells - 2;
+                let (mut i, mut j) = (0, 0);
+                while i < outer_segments || j < inner_segments {
+                    let advance_outer = if i == outer_segments {
+                        false
+                    } else if j == inner_segments {
+                        true
+                    } else {
+                        (i + 1) * edge_step <= step + (j + 1) * step
                     };
+                    if advance_outer {
+                        triangle(outer(i), outer(i + 1), inner(j));
+                        i += 1;
+                    } else {
+                        triangle(outer(i), inner(j + 1), inner(j));
+                        j += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use data::region::RegionDimensions;
+        use data::terrain::TerrainPatch;
+        use types::Uuid;
+
+        fn assert_normal_eq(actual: [f32; 3], expected: [f32; 3]) {
+            for i in 0..3 {
+                assert!(
+                    (actual[i] - expected[i]).abs() < 1e-5,
+                    "{:?} != {:?}",
+                    actual,
+                    expected
+                );
+            }
+        }
+
+        fn render_state(patches_per_side: u16, patch_size: u8) -> RenderState {
+            let region_id = ids::RegionIds::new().get_or_insert(&Uuid::nil());
+            let dims = RegionDimensions {
+                side_meters: patches_per_side as u32 * patch_size as u32,
+                patches_per_side,
+                patch_size_axis: patch_size,
+            };
+            RenderState::new(&Region::new(Uuid::nil(), region_id, dims, Vector2::new(1000, 1000)))
+        }
+
+        /// Creates a patch with the height of each point given by a function
+        /// of its position in the region.
+        fn patch<F>(pos: (u8, u8), size: usize, height: F) -> TerrainPatch
+        where
+            F: Fn(usize, usize) -> f32,
+        {
+            let offset = (pos.0 as usize * size, pos.1 as usize * size);
+            let heightmap =
+                DMatrix::from_fn(size, size, |x, y| height(offset.0 + x, offset.1 + y));
+            TerrainPatch::new(Uuid::nil(), size, Vector2::new(pos.0, pos.1), heightmap)
+        }
+
+        #[test]
+        fn blend_weights_follow_elevation() {
+            let composition = TerrainComposition::default();
+            let at = |height: f32| {
+                let global = Vector2::new(256_123., 256_456.);
+                blend_weights(&composition, height, global, Vector2::new(0.5, 0.5), 1.)
+            };
+            // Far outside of the blended range the noise does not matter.
+            assert_eq!(at(-100.), [1., 0., 0., 0.]);
+            assert_eq!(at(1000.), [0., 0., 0., 1.]);
+
+            let weights = at(40.);
+            let sum: f32 = weights.iter().sum();
+            assert!((sum - 1.).abs() < 1e-5);
+            assert!(weights.iter().filter(|w| **w > 0.).count() <= 2);
+        }
+
+        /// The triangles of one patch as triples of grid positions.
+        fn patch_triangles(state: &RenderState, pos: (usize, usize)) -> Vec<[(usize, usize); 3]> {
+            let mut indices = Vec::new();
+            state.triangulate_patch(pos.0, pos.1, &mut indices);
+            let point = |i: u32| (i as usize / state.grid_side, i as usize % state.grid_side);
+            indices
+                .chunks(3)
+                .map(|t| [point(t[0]), point(t[1]), point(t[2])])
+                .collect()
+        }
+
+        fn area(t: &[(usize, usize); 3]) -> f32 {
+            let (ax, ay) = (t[1].0 as f32 - t[0].0 as f32, t[1].1 as f32 - t[0].1 as f32);
+            let (bx, by) = (t[2].0 as f32 - t[0].0 as f32, t[2].1 as f32 - t[0].1 as f32);
+            (ax * by - ay * bx) / 2.
+        }
+
+        #[test]
+        fn lod_triangles_cover_patches() {
+            let mut state = render_state(4, 16);
+            // Eye positions giving all combinations of neighbouring steps.
+            for &eye in [(0., 0.), (-40., 10.), (-100., 30.), (-300., -200.)].iter() {
+                state.update_lod(Vector2::new(eye.0, eye.1));
+                for x in 0..4 {
+                    for y in 0..4 {
+                        let triangles = patch_triangles(&state, (x, y));
+                        // Counter-clockwise and without overlaps.
+                        assert!(triangles.iter().all(|t| area(t) > 0.));
+                        let total: f32 = triangles.iter().map(area).sum();
+                        assert_eq!(total, 256.);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn lod_edges_have_no_t_junctions() {
+            let mut state = render_state(2, 16);
+            state.update_lod(Vector2::new(-40., 8.));
+            assert!(state.patches_step[0] < state.patches_step[2]);
+
+            // The points on the shared edge used by both patches.
+            let edge_points = |pos| {
+                let mut points: Vec<_> = patch_triangles(&state, pos)
+                    .iter()
+                    .flat_map(|t| t.to_vec())
+                    .filter(|p| p.0 == 16)
+                    .collect();
+                points.sort();
+                points.dedup();
+                points
+            };
+            assert_eq!(edge_points((0, 0)), edge_points((1, 0)));
+        }
+
+        #[test]
+        fn patch_bounds_include_stitched_edges() {
+            let mut state = render_state(2, 16);
+            // A cliff right behind the edge of the first patch.
+            let height = |x: usize, _| if x >= 16 { 100. } else { 10. };
+            state.add_patch(&patch((0, 0), 16, height));
+            state.add_patch(&patch((1, 0), 16, height));
+            state.rebuild_indices();
+            let bounds = &state.patch_indices()[0].bounds;
+            assert_eq!((bounds.min.z, bounds.max.z), (10., 100.));
+        }
+
+        #[test]
+        fn lod_reduces_triangles() {
+            let mut state = render_state(4, 16);
+            for x in 0..4 {
+                for y in 0..4 {
+                    state.add_patch(&patch((x, y), 16, |_, _| 0.));
+                }
+            }
+            state.update_lod(Vector2::new(-1000., -1000.));
+            assert_eq!(state.triangle_count(), 16 * 2);
+            assert_eq!(state.full_triangle_count(), 16 * 16 * 16 * 2);
+        }
+
+        #[test]
+        fn normal_of_flat_terrain() {
+            assert_normal_eq(grid_normal(5., 5., 5., 5., 2., 2.), [0., 0., 1.]);
+        }
+
+        #[test]
+        fn normal_of_slope() {
+            // Rising by 1 per meter in x direction.
+            let n = 1. / 2f32.sqrt();
+            assert_normal_eq(grid_normal(0., 2., 1., 1., 2., 2.), [-n, 0., n]);
+            // Falling by 1 per meter in y direction, at the edge of the grid.
+            assert_normal_eq(grid_normal(1., 1., 1., 0., 1., 1.), [0., n, n]);
+        }
+
+        #[test]
+        fn normals_are_unit_length() {
+            let n = grid_normal(-3., 7., 2., -11., 2., 1.);
+            let len = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+            assert!((len - 1.).abs() < 1e-5);
+        }
+
+        #[test]
+        fn normals_are_smooth_across_patch_edges() {
+            let slope = |x: usize, _: usize| x as f32 * 0.5;
+            let mut state = render_state(2, 4);
+            state.add_patch(&patch((0, 0), 4, &slope));
+            state.add_patch(&patch((1, 0), 4, &slope));
+
+            // Points on both sides of the edge between the patches lie on
+            // the same plane, so they have the same normal.
+            let expected = grid_normal(0., 1., 0., 0., 2., 2.);
+            for &x in [2, 3, 4, 5].iter() {
+                let index = state.vertex_index(x, 1);
+                assert_normal_eq(state.vertices[index].normal, expected);
+            }
+        }
+
+        #[test]
+        fn normals_are_updated_when_neighbour_arrives() {
+            let slope = |x: usize, _: usize| x as f32;
+            let mut state = render_state(2, 4);
+            state.add_patch(&patch((0, 0), 4, &slope));
+
+            // Without neighbour the edge is extended flat.
+            let index = state.vertex_index(4, 1);
+            assert_normal_eq(state.vertices[index].normal, grid_normal(3., 3., 3., 3., 2., 2.));
+
+            state.add_patch(&patch((1, 0), 4, &slope));
+            let index = state.vertex_index(3, 1);
+            assert_normal_eq(state.vertices[index].normal, grid_normal(2., 4., 3., 3., 2., 2.));
+        }
+    }
+}
+
+pub mod water {
+    use data::region::Region;
+    use std::collections::HashMap;
+    use types::Vector2;
+
+    /// Distance in meters to which the water is extended beyond the
+    /// connected regions.
+    const HORIZON_DISTANCE: f32 = 16384.;
+
+    #[derive(Copy, Clone)]
+    pub struct Vertex {
+        position: [f32; 3],
+    }
+
+    implement_vertex!(Vertex, position);
+
+    /// Appends the two triangles of a horizontal rectangle.
+    fn push_quad(vertices: &mut Vec<Vertex>, min: Vector2<f32>, max: Vector2<f32>, height: f32) {
+        let corner = |x: f32, y: f32| Vertex {
+            position: [x, y, height],
+        };
+        vertices.push(corner(min.x, min.y));
+        vertices.push(corner(max.x, min.y));
+        vertices.push(corner(min.x, max.y));
+
+        vertices.push(corner(max.x, max.y));
+        vertices.push(corner(min.x, max.y));
+        vertices.push(corner(max.x, min.y));
+    }
+
+    /// Builds the triangles of the water surface, in the coordinates of the
+    /// current region.
+    ///
+    /// Each grid cell covered by a connected region gets a plane at the
+    /// region's water height. Unconnected cells within the bounds of the
+    /// connected regions, and everything around them up to the horizon, are
+    /// covered at the water height of the current region, so no two planes
+    /// overlap.
+    pub fn build_surface(current: &Region, regions: &[Region]) -> Vec<Vertex> {
+        let mut heights = HashMap::new();
+        for region in regions {
+            for cell in region.grid_cells_from(current) {
+                heights.insert(cell, region.water_height());
+            }
+        }
+        for cell in current.grid_cells_from(current) {
+            heights.insert(cell, current.water_height());
+        }
+
+        let min_x = heights.keys().map(|c| c.x).min().unwrap();
+        let max_x = heights.keys().map(|c| c.x).max().unwrap() + 1;
+        let min_y = heights.keys().map(|c| c.y).min().unwrap();
+        let max_y = heights.keys().map(|c| c.y).max().unwrap() + 1;
+
+        let mut vertices = Vec::new();
+        for x in min_x..max_x {
+            for y in min_y..max_y {
+                let height = match heights.get(&Vector2::new(x, y)) {
+                    Some(height) => *height,
+                    None => current.water_height(),
+                };
+                let min = Vector2::new(x as f32 * 256., y as f32 * 256.);
+                push_quad(&mut vertices, min, min + Vector2::new(256., 256.), height);
+            }
+        }
+
+        // The surrounding ring up to the horizon.
+        let height = current.water_height();
+        let (x0, x1) = (min_x as f32 * 256., max_x as f32 * 256.);
+        let (y0, y1) = (min_y as f32 * 256., max_y as f32 * 256.);
+        let h = HORIZON_DISTANCE;
+        push_quad(&mut vertices, Vector2::new(x0 - h, y0 - h), Vector2::new(x0, y1 + h), height);
+        push_quad(&mut vertices, Vector2::new(x1, y0 - h), Vector2::new(x1 + h, y1 + h), height);
+        push_quad(&mut vertices, Vector2::new(x0, y0 - h), Vector2::new(x1, y0), height);
+        push_quad(&mut vertices, Vector2::new(x0, y1), Vector2::new(x1, y1 + h), height);
+        vertices
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use data::ids::RegionIds;
+        use data::region::RegionDimensions;
+        use data::test_uuid;
+
+        fn region(region_ids: &RegionIds, n: u8, x: u32, y: u32, water_height: f32) -> Region {
+            let uuid = test_uuid(n);
+            let id = region_ids.get_or_insert(&uuid);
+            let dims = RegionDimensions::from_size(256, 256).unwrap();
+            let mut region = Region::new(uuid, id, dims, Vector2::new(x, y));
+            region.set_water_height(water_height);
+            region
+        }
+
+        /// The heights of the quads, in the order they were built.
+        fn quad_heights(vertices: &[Vertex]) -> Vec<f32> {
+            vertices.chunks(6).map(|quad| quad[0].position[2]).collect()
+        }
+
+        #[test]
+        fn surface_reaches_the_horizon() {
+            let region_ids = RegionIds::new();
+            let current = region(&region_ids, 1, 1000, 1000, 20.);
+            let vertices = build_surface(&current, &[current.clone()]);
+            // The region and the four quads around it.
+            assert_eq!(quad_heights(&vertices), vec![20.; 5]);
+            let xs = vertices.iter().map(|v| v.position[0]);
+            let min_x = xs.clone().fold(::std::f32::INFINITY, f32::min);
+            let max_x = xs.fold(::std::f32::NEG_INFINITY, f32::max);
+            assert_eq!((min_x, max_x), (-HORIZON_DISTANCE, 256. + HORIZON_DISTANCE));
+        }
+
+        #[test]
+        fn regions_have_their_own_water_height() {
+            let region_ids = RegionIds::new();
+            let current = region(&region_ids, 1, 1000, 1000, 20.);
+            let diagonal = region(&region_ids, 2, 1001, 1001, 25.);
+            let vertices = build_surface(&current, &[current.clone(), diagonal]);
+            // The cells of the bounds, column by column, then the ring. The
+            // cells without a region have the water of the current one.
+            let heights = quad_heights(&vertices);
+            assert_eq!(heights[..4].to_vec(), vec![20., 20., 20., 25.]);
+            assert_eq!(heights[4..].to_vec(), vec![20.; 4]);
+            assert_eq!(vertices[3 * 6].position[..2].to_vec(), vec![256., 256.]);
+        }
+    }
+}
 
-                    let x2 = x1 + 1;
-                    let y2 = y1 + 1;
+pub mod weather {
+    use data::weather::RegionWeather;
+    use types::{Vector2, Vector3};
 
-                    add_vertex(x1, y1);
-                    add_vertex(x2, y1);
-                    add_vertex(x1, y2);
+    /// Height in meters above the ground or water at which the wind arrows
+    /// are drawn.
+    pub const ARROW_ELEVATION: f32 = 3.;
 
-                    add_vertex(x2, y2);
-                    add_vertex(x1, y2);
-                    add_vertex(x2, y1);
+    /// Length in meters of the arrow for a wind of one meter per second.
+    const ARROW_SCALE: f32 = 2.;
+
+    /// Wind speed in meters per second drawn in the strongest colour.
+    const STRONG_WIND: f32 = 20.;
+
+    /// Altitude in meters at which the cloud density is drawn.
+    pub const CLOUD_ALTITUDE: f32 = 180.;
+
+    #[derive(Copy, Clone)]
+    pub struct Vertex {
+        position: [f32; 3],
+        color: [f32; 4],
+    }
+
+    implement_vertex!(Vertex, position, color);
+
+    /// Colour of an arrow, from blue for calm to red for strong winds.
+    fn wind_color(speed: f32) -> [f32; 4] {
+        let t = (speed / STRONG_WIND).min(1.);
+        [t, 0.3 * (1. - t), 1. - t, 1.]
+    }
+
+    /// Appends the three lines of an arrow pointing in the direction of the
+    /// wind.
+    pub fn push_arrow(lines: &mut Vec<Vertex>, base: Vector3<f32>, wind: Vector2<f32>) {
+        let color = wind_color(wind.norm());
+        let vertex = |p: Vector3<f32>| Vertex {
+            position: [p.x, p.y, p.z],
+            color,
+        };
+        let shaft = Vector3::new(wind.x, wind.y, 0.) * ARROW_SCALE;
+        let tip = base + shaft;
+        let back = -shaft * 0.25;
+        let side = Vector3::new(-back.y, back.x, 0.) * 0.5;
+
+        lines.push(vertex(base));
+        lines.push(vertex(tip));
+        lines.push(vertex(tip));
+        lines.push(vertex(tip + back + side));
+        lines.push(vertex(tip));
+        lines.push(vertex(tip + back - side));
+    }
+
+    /// Builds the lines of the wind arrows of a region, in the coordinates of
+    /// the current region. `ground` returns the height of the ground or water
+    /// at a point of the region.
+    pub fn wind_arrows<F>(
+        weather: &RegionWeather,
+        side: f32,
+        offset: Vector2<f32>,
+        ground: F,
+    ) -> Vec<Vertex>
+    where
+        F: Fn(f32, f32) -> f32,
+    {
+        let mut lines = Vec::new();
+        if let Some(wind) = weather.wind() {
+            for (point, velocity) in wind.samples(side) {
+                let height = ground(point.x, point.y) + ARROW_ELEVATION;
+                let base = Vector3::new(point.x + offset.x, point.y + offset.y, height);
+                push_arrow(&mut lines, base, velocity);
+            }
+        }
+        lines
+    }
+
+    /// Builds the triangles of the cloud density of a region, in the
+    /// coordinates of the current region. Denser clouds are more opaque.
+    pub fn cloud_cells(weather: &RegionWeather, side: f32, offset: Vector2<f32>) -> Vec<Vertex> {
+        let mut triangles = Vec::new();
+        if let Some(clouds) = weather.clouds() {
+            for (min, max, density) in clouds.cells(side) {
+                let color = [1., 1., 1., 0.6 * density.max(0.).min(1.)];
+                let corner = |x: f32, y: f32| Vertex {
+                    position: [x + offset.x, y + offset.y, CLOUD_ALTITUDE],
+                    color,
+                };
+                triangles.push(corner(min.x, min.y));
+                triangles.push(corner(max.x, min.y));
+                triangles.push(corner(min.x, max.y));
+
+                triangles.push(corner(max.x, max.y));
+                triangles.push(corner(min.x, max.y));
+                triangles.push(corner(max.x, min.y));
+            }
+        }
+        triangles
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn arrow_points_downwind() {
+            let mut lines = Vec::new();
+            push_arrow(&mut lines, Vector3::new(10., 20., 30.), Vector2::new(3., -4.));
+            assert_eq!(lines.len(), 6);
+            assert_eq!(lines[0].position, [10., 20., 30.]);
+            let tip = [10. + 3. * ARROW_SCALE, 20. - 4. * ARROW_SCALE, 30.];
+            assert_eq!(lines[1].position, tip);
+            // Both barbs end behind the tip.
+            for barb in &[lines[3], lines[5]] {
+                let along = (barb.position[0] - tip[0]) * 3. - (barb.position[1] - tip[1]) * 4.;
+                assert!(along < 0.);
+            }
+        }
+
+        #[test]
+        fn calm_and_strong_wind_colors() {
+            assert_eq!(wind_color(0.), [0., 0.3, 1., 1.]);
+            assert_eq!(wind_color(2. * STRONG_WIND), [1., 0., 0., 1.]);
+        }
+    }
+}
+
+pub mod minimap {
+    use types::Vector2;
+
+    /// Side length of the minimap in pixels.
+    const SIZE: f32 = 200.;
+
+    /// Distance of the minimap from the upper right corner of the window, in
+    /// pixels.
+    const MARGIN: f32 = 10.;
+
+    /// Length of the avatar marker in pixels.
+    const MARKER_SIZE: f32 = 10.;
+
+    #[derive(Copy, Clone)]
+    pub struct Vertex {
+        position: [f32; 2],
+        tex_coords: [f32; 2],
+    }
+
+    implement_vertex!(Vertex, position, tex_coords);
+
+    /// The lower left and upper right corner of the minimap, in normalized
+    /// device coordinates, for a window of `size` pixels.
+    pub fn area(size: (u32, u32)) -> (Vector2<f32>, Vector2<f32>) {
+        let (width, height) = (size.0 as f32, size.1 as f32);
+        let max = Vector2::new(1. - 2. * MARGIN / width, 1. - 2. * MARGIN / height);
+        let min = max - Vector2::new(2. * SIZE / width, 2. * SIZE / height);
+        (min, max)
+    }
+
+    /// The triangle strip showing the map tile. Its rows start in the north,
+    /// so the texture is upside down.
+    pub fn map_quad(area: &(Vector2<f32>, Vector2<f32>)) -> Vec<Vertex> {
+        let (min, max) = *area;
+        let vertex = |x: f32, y: f32, u: f32, v: f32| Vertex {
+            position: [x, y],
+            tex_coords: [u, v],
+        };
+        vec![
+            vertex(min.x, min.y, 0., 1.),
+            vertex(max.x, min.y, 1., 1.),
+            vertex(min.x, max.y, 0., 0.),
+            vertex(max.x, max.y, 1., 0.),
+        ]
+    }
+
+    /// The triangle marking the avatar at a position in a region with sides
+    /// of `side` meters, pointing in the direction it is heading.
+    pub fn marker(
+        area: &(Vector2<f32>, Vector2<f32>),
+        size: (u32, u32),
+        rel_pos: Vector2<f32>,
+        side: f32,
+        heading: Vector2<f32>,
+    ) -> Vec<Vertex> {
+        let (min, max) = *area;
+        let fraction = |v: f32| (v / side).max(0.).min(1.);
+        let center = Vector2::new(
+            min.x + (max.x - min.x) * fraction(rel_pos.x),
+            min.y + (max.y - min.y) * fraction(rel_pos.y),
+        );
+
+        // From pixels to normalized device coordinates.
+        let scale = Vector2::new(2. / size.0 as f32, 2. / size.1 as f32);
+        let point = |along: f32, across: f32| {
+            let offset = heading * along + Vector2::new(-heading.y, heading.x) * across;
+            let p = center + offset.component_mul(&scale) * MARKER_SIZE;
+            Vertex {
+                position: [p.x, p.y],
+                tex_coords: [0., 0.],
+            }
+        };
+        vec![point(0.6, 0.), point(-0.4, 0.4), point(-0.4, -0.4)]
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn area_is_in_the_upper_right_corner() {
+            let (min, max) = area((800, 600));
+            assert_eq!(max, Vector2::new(1. - 20. / 800., 1. - 20. / 600.));
+            assert!((max.x - min.x - 400. / 800.).abs() < 1e-6);
+            assert!((max.y - min.y - 400. / 600.).abs() < 1e-6);
+        }
+
+        #[test]
+        fn marker_is_at_the_avatar_position() {
+            let size = (800, 600);
+            let area = area(size);
+            let marker = marker(&area, size, Vector2::new(64., 192.), 256., Vector2::new(0., 1.));
+            let expected = Vector2::new(
+                area.0.x + (area.1.x - area.0.x) * 0.25,
+                area.0.y + (area.1.y - area.0.y) * 0.75,
+            );
+            // Heading north, the tip is right above the position.
+            let tip = marker[0].position;
+            assert!((tip[0] - expected.x).abs() < 1e-6);
+            assert!(tip[1] > expected.y);
+            // The base is centered below it.
+            let base_x = (marker[1].position[0] + marker[2].position[0]) / 2.;
+            assert!((base_x - expected.x).abs() < 1e-6);
+            assert!(marker[1].position[1] < expected.y);
+        }
+    }
+}
+
+/// The map tile of the current region shown as minimap.
+struct MinimapView {
+    region_id: ids::RegionId,
+    side: f32,
+    built_at: Instant,
+    /// Whether the terrain of the region changed since it was rendered.
+    outdated: bool,
+    /// The map tile, `None` if rendering it failed.
+    texture: Option<glium::texture::SrgbTexture2d>,
+    overlay: Option<MinimapOverlay>,
+}
+
+/// The vertices the minimap is drawn with, for a window size.
+struct MinimapOverlay {
+    size: (u32, u32),
+    quad: glium::VertexBuffer<minimap::Vertex>,
+    /// Rewritten every frame, as the avatar moves.
+    marker: glium::VertexBuffer<minimap::Vertex>,
+}
+
+impl MinimapView {
+    fn new(
+        display: &glium::Display,
+        storage: &Storage,
+        region: &Region,
+        failures: &mut FailureReport,
+    ) -> Self {
+        let texture = match data::map_tile::render_region(&storage.terrain, region) {
+            Ok(tile) => {
+                failures.succeeded();
+                let dimensions = (tile.width() as u32, tile.height() as u32);
+                let image =
+                    glium::texture::RawImage2d::from_raw_rgb(tile.data().to_vec(), dimensions);
+                Some(glium::texture::SrgbTexture2d::new(display, image).unwrap())
+            }
+            Err(e) => {
+                failures.failed(format!("Rendering the minimap failed: {}", e));
+                None
+            }
+        };
+        MinimapView {
+            region_id: region.id().clone(),
+            side: region.dimensions().side_meters as f32,
+            built_at: Instant::now(),
+            outdated: false,
+            texture,
+            overlay: None,
+        }
+    }
+
+    /// Whether the minimap has to be rendered again, because the current
+    /// region changed or (at most every few seconds) its terrain did or
+    /// rendering it failed.
+    fn needs_update(&self, region: &Region) -> bool {
+        let interval = Duration::from_millis(MINIMAP_REBUILD_INTERVAL_MS);
+        let retry = self.outdated || self.texture.is_none();
+        self.region_id != *region.id() || (retry && self.built_at.elapsed() >= interval)
+    }
+
+    /// Builds the vertices for the current size of the window, if it
+    /// changed.
+    fn update_overlay(&mut self, display: &glium::Display) {
+        let size = match display.gl_window().get_inner_size() {
+            Some(size) => size,
+            None => return,
+        };
+        if let Some(ref overlay) = self.overlay {
+            if overlay.size == size {
+                return;
+            }
+        }
+        let area = minimap::area(size);
+        let marker = minimap::marker(&area, size, Vector2::new(0., 0.), 1., Vector2::new(0., 1.));
+        self.overlay = Some(MinimapOverlay {
+            size,
+            quad: glium::VertexBuffer::new(display, &minimap::map_quad(&area)).unwrap(),
+            marker: glium::VertexBuffer::dynamic(display, &marker).unwrap(),
+        });
+    }
+}
+
+/// Minimal interval in which the minimap is rendered again while the terrain
+/// of the current region changes.
+const MINIMAP_REBUILD_INTERVAL_MS: u64 = 2000;
+
+/// Interval in which the weather view is rebuilt while it is enabled, the
+/// simulators send new layers only every few seconds anyway.
+const WEATHER_REBUILD_INTERVAL_MS: u64 = 1000;
+
+/// The wind arrows and cloud density drawn for debugging.
+struct WeatherView {
+    built_at: Instant,
+    arrows: glium::VertexBuffer<weather::Vertex>,
+    clouds: glium::VertexBuffer<weather::Vertex>,
+}
+
+impl WeatherView {
+    fn new(
+        display: &glium::Display,
+        storage: &Storage,
+        current: &Region,
+        regions: &[Region],
+    ) -> Self {
+        let mut arrows = Vec::new();
+        let mut clouds = Vec::new();
+        for region in regions {
+            let layers = match storage.weather.get(region.id()) {
+                Some(layers) => layers,
+                None => continue,
+            };
+            let side = region.dimensions().side_meters as f32;
+            let offset = region.offset_from(current);
+            let ground = |x: f32, y: f32| {
+                let land = storage.terrain.height_at(region, x, y).unwrap_or(0.);
+                land.max(region.water_height())
+            };
+            arrows.extend(weather::wind_arrows(&layers, side, offset, ground));
+            clouds.extend(weather::cloud_cells(&layers, side, offset));
+        }
+
+        WeatherView {
+            built_at: Instant::now(),
+            arrows: glium::VertexBuffer::new(display, &arrows).unwrap(),
+            clouds: glium::VertexBuffer::new(display, &clouds).unwrap(),
+        }
+    }
+
+    fn is_outdated(&self) -> bool {
+        self.built_at.elapsed() >= Duration::from_millis(WEATHER_REBUILD_INTERVAL_MS)
+    }
+}
+
+/// The current region and the connected regions, with their water heights.
+type WaterKey = ((ids::RegionId, f32), Vec<(ids::RegionId, f32)>);
+
+/// The water surface around the current region.
+struct WaterView {
+    /// The regions the surface was built for.
+    built_for: WaterKey,
+    vertices: glium::VertexBuffer<water::Vertex>,
+}
+
+impl WaterView {
+    fn new(display: &glium::Display, current: &Region, regions: &[Region]) -> Self {
+        let vertices = water::build_surface(current, regions);
+        WaterView {
+            built_for: Self::key(current, regions),
+            vertices: glium::VertexBuffer::new(display, &vertices).unwrap(),
+        }
+    }
+
+    fn key(current: &Region, regions: &[Region]) -> WaterKey {
+        let key = |region: &Region| (region.id().clone(), region.water_height());
+        let mut keys: Vec<_> = regions.iter().map(&key).collect();
+        keys.sort_by(|a, b| a.0.cmp(&b.0));
+        (key(current), keys)
+    }
+
+    /// Rebuilds the surface if the regions or their water heights changed.
+    fn update(&mut self, display: &glium::Display, current: &Region, regions: &[Region]) {
+        if self.built_for != Self::key(current, regions) {
+            *self = WaterView::new(display, current, regions);
+        }
+    }
+}
+
+/// The GPU side resources for drawing one connected region.
+struct RegionView {
+    region: Region,
+    land: terrain_land::RenderState,
+    land_vertices: glium::VertexBuffer<terrain_land::Vertex>,
+    land_indices: Option<glium::IndexBuffer<u32>>,
+
+    /// The detail textures of the terrain composition, once loaded.
+    detail_textures: [Option<glium::texture::SrgbTexture2d>; 4],
+    terrain_failures: FailureReport,
+}
+
+impl RegionView {
+    fn new(display: &glium::Display, region: Region, logger: Logger) -> Self {
+        let land = terrain_land::RenderState::new(&region);
+        let land_vertices =
+            glium::VertexBuffer::empty_dynamic(display, land.vertices().len()).unwrap();
+
+        RegionView {
+            region,
+            land,
+            land_vertices,
+            land_indices: None,
+            detail_textures: [None, None, None, None],
+            terrain_failures: FailureReport::new(logger),
+        }
+    }
+
+    /// Uploads the detail textures which were fetched since the last call,
+    /// the missing ones are requested from the texture storage.
+    fn load_detail_textures(&mut self, display: &glium::Display, textures: &TextureStorage) {
+        let ids = self.region.composition().detail_textures;
+        for (layer, id) in ids.iter().enumerate() {
+            if self.detail_textures[layer].is_some() {
+                continue;
+            }
+            if let Some(image) = textures.get_or_request(id) {
+                let raw = glium::texture::RawImage2d::from_raw_rgba_reversed(
+                    &image.rgba,
+                    (image.width, image.height),
+                );
+                self.detail_textures[layer] =
+                    Some(glium::texture::SrgbTexture2d::new(display, raw).unwrap());
+            }
+        }
+    }
+
+    /// Uploads newly arrived terrain and textures to the GPU, and changes the
+    /// levels of detail for the position of the eye relative to the region.
+    fn update(&mut self, display: &glium::Display, storage: &Storage, eye: Vector2<f32>) {
+        self.load_detail_textures(display, &storage.textures);
+        let terrain = &storage.terrain;
+        let lod_changed = self.land.update_lod(eye);
+        let terrain_changed = match self.land.update(Arc::clone(terrain)) {
+            Ok(changed) => {
+                self.terrain_failures.succeeded();
+                changed
+            }
+            Err(e) => {
+                // The patches which could be read were added nonetheless.
+                let message = format!("Loading the terrain of {} failed: {}", self.region.id(), e);
+                self.terrain_failures.failed(message);
+                true
+            }
+        };
+        if terrain_changed {
+            self.land_vertices.write(self.land.vertices());
+        }
+        if lod_changed || terrain_changed {
+            self.land_indices = Some(
+                glium::IndexBuffer::new(display, PrimitiveType::TrianglesList, self.land.indices())
+                    .unwrap(),
+            );
+        }
+    }
+}
+
+/// Logs failures of the render loop. A failure which keeps repeating, e.g.
+/// every frame, is only logged again once it changed.
+struct FailureReport {
+    logger: Logger,
+    last: Option<String>,
+}
+
+impl FailureReport {
+    fn new(logger: Logger) -> Self {
+        FailureReport { logger, last: None }
+    }
+
+    fn failed(&mut self, message: String) {
+        if self.last.as_ref() != Some(&message) {
+            warn!(self.logger, "{}", message);
+            self.last = Some(message);
+        }
+    }
+
+    fn succeeded(&mut self) {
+        self.last = None;
+    }
+}
+
+/// Returns the region the client avatar is currently in, if it is connected.
+fn current_region(storage: &Storage) -> Option<Region> {
+    let region_id = storage.client_avatar.read().current_region().clone()?;
+    storage.region.get(&region_id).ok()?.clone_region()
+}
+
+/// Radii in meters the terraforming brush can be switched between.
+const BRUSH_RADII: [f32; 4] = [1., 2., 4., 8.];
+
+/// Distance in meters in front of the avatar at which the brush is applied
+/// if the cursor does not point at the terrain.
+const BRUSH_DISTANCE: f32 = 8.;
+
+/// How long each key press (or key repeat) applies the brush.
+const STROKE_SECONDS: f32 = 0.25;
+
+/// The terraforming tool, used with the keyboard: 1 to 6 select the brush,
+/// [ and ] change its radius, and holding T applies it to the terrain under
+/// the cursor.
+struct TerraformTool {
+    brush: Brush,
+    radius_index: usize,
+    /// The height flattened to while T is held.
+    flatten_height: Option<f32>,
+    applying: bool,
+    preview_failures: FailureReport,
+}
+
+impl TerraformTool {
+    fn new(logger: Logger) -> Self {
+        TerraformTool {
+            brush: Brush::Raise,
+            radius_index: 2,
+            flatten_height: None,
+            applying: false,
+            preview_failures: FailureReport::new(logger),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("{:?} brush, {} m", self.brush, BRUSH_RADII[self.radius_index])
+    }
+
+    /// Handles the keys of the tool, returning whether the key was one of
+    /// them.
+    fn handle_key(
+        &mut self,
+        storage: &Storage,
+        cursor_hit: Option<&RayHit>,
+        key: glutin::VirtualKeyCode,
+        pressed: bool,
+    ) -> bool {
+        use glium::glutin::VirtualKeyCode as Key;
+
+        let brush = match key {
+            Key::Key1 => Some(Brush::Raise),
+            Key::Key2 => Some(Brush::Lower),
+            Key::Key3 => Some(Brush::Flatten),
+            Key::Key4 => Some(Brush::Smooth),
+            Key::Key5 => Some(Brush::Noise),
+            Key::Key6 => Some(Brush::Revert),
+            _ => None,
+        };
+        match (key, brush) {
+            (_, Some(brush)) => {
+                if pressed {
+                    self.brush = brush;
+                }
+            }
+            (Key::LBracket, _) => {
+                if pressed && self.radius_index > 0 {
+                    self.radius_index -= 1;
                 }
             }
+            (Key::RBracket, _) => {
+                if pressed && self.radius_index + 1 < BRUSH_RADII.len() {
+                    self.radius_index += 1;
+                }
+            }
+            (Key::T, _) => {
+                // Key repeats continue the stroke.
+                if pressed {
+                    if !self.applying {
+                        self.flatten_height = None;
+                    }
+                    self.applying = true;
+                    self.apply(storage, cursor_hit);
+                } else {
+                    self.applying = false;
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Previews a stroke at the terrain under the cursor, or in front of the
+    /// avatar, and queues it to be sent to the simulator.
+    fn apply(&mut self, storage: &Storage, cursor_hit: Option<&RayHit>) {
+        let (region, center) = match cursor_hit {
+            Some(hit) => {
+                let region = storage
+                    .region
+                    .get(&hit.region_id)
+                    .ok()
+                    .and_then(|connection| connection.clone_region());
+                match region {
+                    Some(region) => (region, Vector2::new(hit.rel_pos.x, hit.rel_pos.y)),
+                    None => return,
+                }
+            }
+            None => {
+                let region = match current_region(storage) {
+                    Some(region) => region,
+                    None => return,
+                };
+                let avatar = storage.client_avatar.read();
+                let position = avatar.location().rel_pos;
+                let center =
+                    Vector2::new(position.x, position.y) + avatar.heading() * BRUSH_DISTANCE;
+                (region, center)
+            }
+        };
+        let side = region.dimensions().side_meters as f32;
+        if center.x < 0. || center.y < 0. || center.x >= side || center.y >= side {
+            return;
+        }
+        let height = match self.flatten_height {
+            Some(height) => height,
+            None => match storage.terrain.height_at(&region, center.x, center.y) {
+                Ok(height) => height,
+                // Nothing to terraform before the terrain arrived.
+                Err(_) => return,
+            },
+        };
+        self.flatten_height = Some(height);
+
+        let stroke = Stroke {
+            brush: self.brush,
+            center,
+            radius: BRUSH_RADII[self.radius_index],
+            seconds: STROKE_SECONDS,
+            height,
+        };
+        match data::terraform::preview(&storage.terrain, &region, &stroke) {
+            Ok(_) => self.preview_failures.succeeded(),
+            Err(e) => {
+                let message = format!("Previewing terraforming failed: {}", e);
+                self.preview_failures.failed(message);
+            }
         }
+        storage.strokes.push(region.id().clone(), stroke);
     }
 }
 
-pub fn render_world(storage: Storage) {
+/// Returns the terrain under a point of the window, which is `size` pixels
+/// large.
+fn pick_terrain(
+    storage: &Storage,
+    current: &Region,
+    point: (f64, f64),
+    size: (u32, u32),
+    max_distance: f32,
+) -> Option<RayHit> {
+    let ray = storage.client_avatar.read().screen_ray(point, size)?;
+    storage.terrain.cast_ray(current, &ray, max_distance).ok()?
+}
+
+/// Saves the map tile of the current region, logging the outcome.
+fn save_map_tile(storage: &Storage, logger: &Logger) {
+    let region = match current_region(storage) {
+        Some(region) => region,
+        None => return,
+    };
+    match data::map_tile::save_region(&storage.terrain, &region) {
+        Ok(path) => info!(logger, "Saved map tile of region {} to {:?}.", region.uuid(), path),
+        Err(e) => warn!(logger, "Saving map tile of region {} failed: {}", region.uuid(), e),
+    }
+}
+
+/// Exports the terrain of the current region, logging the outcome.
+fn export_terrain(storage: &Storage, logger: &Logger) {
+    let region = match current_region(storage) {
+        Some(region) => region,
+        None => return,
+    };
+    let dir = data::config::Paths {}.terrain_export();
+    match data::heightmap::export_region(&storage.terrain, &region, &dir) {
+        Ok(()) => info!(logger, "Exported terrain of region {} to {:?}.", region.uuid(), dir),
+        Err(e) => warn!(logger, "Exporting terrain of region {} failed: {}", region.uuid(), e),
+    }
+}
+
+pub fn render_world(storage: Storage, logger: Logger) {
     // Setup display.
     // TODO: Maybe this does not belong into the render world method?
     let mut events_loop = glutin::EventsLoop::new();
@@ -157,29 +1809,47 @@ pub fn render_world(storage: Storage) {
             fragment: include_str!("../../shader/terrain_land.frag"),
         },
     ).unwrap();
+    let water_program = program!(&display,
+        140 => {
+            vertex: include_str!("../../shader/water.vert"),
+            fragment: include_str!("../../shader/water.frag"),
+        },
+    ).unwrap();
+    let weather_program = program!(&display,
+        140 => {
+            vertex: include_str!("../../shader/weather.vert"),
+            fragment: include_str!("../../shader/weather.frag"),
+        },
+    ).unwrap();
+    let minimap_program = program!(&display,
+        140 => {
+            vertex: include_str!("../../shader/minimap.vert"),
+            fragment: include_str!("../../shader/minimap.frag"),
+        },
+    ).unwrap();
+
+    // Bound in place of detail textures which are not loaded yet.
+    let placeholder_texture = glium::texture::SrgbTexture2d::empty(&display, 1, 1).unwrap();
+
+    // Subscribe before any region view is created, so no patch is missed.
+    let terrain_updates = storage.terrain.subscribe();
 
     // Wait for region connection. (TODO loading screen.)
-    while storage.client_avatar.read().current_region().is_none() {
+    while current_region(&storage).is_none() {
         thread::sleep(Duration::from_millis(50));
     }
 
-    let region_id = storage
-        .client_avatar
-        .read()
-        .current_region()
-        .clone()
-        .unwrap();
-    let region_conn = storage.region.get(&region_id).unwrap();
-    let region = region_conn.clone_region().unwrap();
-
-    //let pps = region.dimensions().patches_per_side as usize;
-
-    let mut render_state = terrain_land::RenderState::new(region_id, region.dimensions());
-    let v_buffer =
-        glium::VertexBuffer::empty_dynamic(&display, render_state.vertices().len()).unwrap();
+    // The connected regions, drawn at their offset from the current region.
+    let mut views: HashMap<ids::RegionId, RegionView> = HashMap::new();
+    let mut water_view: Option<WaterView> = None;
+    // Toggled with F11.
+    let mut weather_view: Option<WeatherView> = None;
+    let mut show_weather = false;
+    let mut terraform_tool = TerraformTool::new(logger.clone());
+    let mut minimap_view: Option<MinimapView> = None;
+    let mut minimap_failures = FailureReport::new(logger.clone());
 
     // let mut camera = camera::CameraState::new();
-    let index_buffer = glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList);
     let params = glium::DrawParameters {
         depth: glium::Depth {
             test: glium::DepthTest::IfLess,
@@ -188,49 +1858,346 @@ pub fn render_world(storage: Storage) {
         },
         ..Default::default()
     };
+    // The water is translucent, and must not hide the terrain below it. It
+    // reaches to the horizon, far beyond the far plane, so instead of being
+    // clipped its depth is clamped to the far plane.
+    let water_params = glium::DrawParameters {
+        depth: glium::Depth {
+            test: glium::DepthTest::IfLessOrEqual,
+            write: false,
+            clamp: glium::draw_parameters::DepthClamp::Clamp,
+            ..Default::default()
+        },
+        blend: glium::Blend::alpha_blending(),
+        ..Default::default()
+    };
 
-    let redraw = |avatar: &Arc<RwLock<ClientAvatar>>| {
+    let draw_distance = storage.terrain.config().draw_distance;
+    let redraw = |avatar: &Arc<RwLock<ClientAvatar>>,
+                  current: &Region,
+                  views: &HashMap<ids::RegionId, RegionView>,
+                  water_view: &WaterView,
+                  weather_view: Option<&WeatherView>,
+                  minimap_view: Option<&MinimapView>| {
         // Compute he uniforms.
-        let uniforms = uniform! {
-            persp_matrix: avatar.read().get_persp_matrix().as_ref().clone(),
-            view_matrix: avatar.read().get_view_matrix().as_ref().clone(),
-        };
+        let persp = avatar.read().get_persp_matrix();
+        let view = avatar.read().get_view_matrix();
+        let frustum = culling::Frustum::from_matrix(&(persp * view));
+        let persp_matrix = persp.as_ref().clone();
+        let view_matrix = view.as_ref().clone();
+        let camera_position = avatar.read().eye_position();
+        let sun_direction = Vector3::new(0.5f32, 0.3, 0.8).normalize();
 
         // Draw a frame.
         let mut target = display.draw();
         target.clear_color_and_depth((0.0, 0.0, 0.0, 0.0), 1.0);
+        let mut patches_drawn = 0;
+        for view in views.values() {
+            if let Some(ref land_indices) = view.land_indices {
+                let offset = view.region.offset_from(current);
+                // The textures are tiled over the terrain.
+                let texture = |layer: usize| {
+                    let texture = match view.detail_textures[layer] {
+                        Some(ref texture) => texture,
+                        None => &placeholder_texture,
+                    };
+                    texture
+                        .sampled()
+                        .wrap_function(glium::uniforms::SamplerWrapFunction::Repeat)
+                };
+                let loaded = |layer: usize| match view.detail_textures[layer] {
+                    Some(_) => 1.0f32,
+                    None => 0.,
+                };
+                let colors = terrain_land::DETAIL_COLORS;
+                let uniforms = uniform! {
+                    persp_matrix: persp_matrix,
+                    view_matrix: view_matrix,
+                    region_offset: [offset[0], offset[1], 0.0f32],
+                    sun_direction: [sun_direction.x, sun_direction.y, sun_direction.z],
+                    water_height: view.region.water_height(),
+                    detail_texture0: texture(0),
+                    detail_texture1: texture(1),
+                    detail_texture2: texture(2),
+                    detail_texture3: texture(3),
+                    detail_loaded: [loaded(0), loaded(1), loaded(2), loaded(3)],
+                    detail_color0: colors[0],
+                    detail_color1: colors[1],
+                    detail_color2: colors[2],
+                    detail_color3: colors[3],
+                };
+
+                // Draw the patches which are in view and within the draw distance.
+                let offset = Vector3::new(offset[0], offset[1], 0.);
+                for patch in view.land.patch_indices() {
+                    let bounds = patch.bounds.translated(&offset);
+                    if bounds.distance_to(&camera_position) > draw_distance
+                        || !frustum.intersects(&bounds)
+                    {
+                        continue;
+                    }
+                    let indices = land_indices.slice(patch.range.clone()).unwrap();
+                    target
+                        .draw(&view.land_vertices, indices, &program, &uniforms, &params)
+                        .unwrap();
+                    patches_drawn += 1;
+                }
+            }
+        }
+
+        // The water is drawn last, over the terrain.
+        let uniforms = uniform! {
+            persp_matrix: persp_matrix,
+            view_matrix: view_matrix,
+            camera_position: [camera_position.x, camera_position.y, camera_position.z],
+        };
         target
-            .draw(&v_buffer, &index_buffer, &program, &uniforms, &params)
+            .draw(
+                &water_view.vertices,
+                glium::index::NoIndices(PrimitiveType::TrianglesList),
+                &water_program,
+                &uniforms,
+                &water_params,
+            )
             .unwrap();
+
+        if let Some(weather_view) = weather_view {
+            let uniforms = uniform! {
+                persp_matrix: persp_matrix,
+                view_matrix: view_matrix,
+            };
+            target
+                .draw(
+                    &weather_view.arrows,
+                    glium::index::NoIndices(PrimitiveType::LinesList),
+                    &weather_program,
+                    &uniforms,
+                    &params,
+                )
+                .unwrap();
+            target
+                .draw(
+                    &weather_view.clouds,
+                    glium::index::NoIndices(PrimitiveType::TrianglesList),
+                    &weather_program,
+                    &uniforms,
+                    &water_params,
+                )
+                .unwrap();
+        }
+
+        // The minimap is drawn over everything.
+        let minimap = minimap_view.and_then(|view| match (&view.texture, &view.overlay) {
+            (&Some(ref texture), &Some(ref overlay)) => Some((view.side, texture, overlay)),
+            _ => None,
+        });
+        if let Some((side, texture, overlay)) = minimap {
+            let area = minimap::area(overlay.size);
+            let (rel_pos, heading) = {
+                let avatar = avatar.read();
+                (avatar.location().rel_pos, avatar.heading())
+            };
+            let rel_pos = Vector2::new(rel_pos.x, rel_pos.y);
+            let marker = minimap::marker(&area, overlay.size, rel_pos, side, heading);
+            overlay.marker.write(&marker);
+            let buffers = [
+                (&overlay.quad, PrimitiveType::TriangleStrip, 1.0f32),
+                (&overlay.marker, PrimitiveType::TrianglesList, 0.),
+            ];
+            for &(vertices, primitive, textured) in buffers.iter() {
+                let uniforms = uniform! {
+                    map: texture,
+                    textured: textured,
+                    color: [0.9f32, 0.1, 0.1, 1.0],
+                };
+                target
+                    .draw(
+                        vertices,
+                        glium::index::NoIndices(primitive),
+                        &minimap_program,
+                        &uniforms,
+                        &Default::default(),
+                    )
+                    .unwrap();
+            }
+        }
         target.finish().unwrap();
-    };
 
-    // Draw the triangle to the screen.
-    redraw(&storage.client_avatar);
+        patches_drawn
+    };
 
     // Main loop.
     let mut accumulator = Duration::new(0, 0);
     let mut previous_clock = Instant::now();
+    let mut last_report = Instant::now();
+    let mut patches_drawn = 0;
+    let mut statistics = String::new();
+    let mut title = String::new();
+    // Position of the mouse cursor in the window, if it is inside.
+    let mut cursor: Option<(f64, f64)> = None;
+    // The terrain under the cursor, which is only picked again when the
+    // cursor moved or the brush is applied.
+    let mut cursor_hit = None;
+    let mut cursor_moved = false;
+    let pick = |region: Option<&Region>, cursor: Option<(f64, f64)>| {
+        match (cursor, region, display.gl_window().get_inner_size()) {
+            (Some(cursor), Some(region), Some(size)) => {
+                pick_terrain(&storage, region, cursor, size, draw_distance)
+            }
+            _ => None,
+        }
+    };
     loop {
+        // Keep the region views in sync with the connected regions.
+        let connected = storage.region.connected();
+        views.retain(|id, _| connected.iter().any(|region| region.id() == id));
+        for region in connected.iter() {
+            if !views.contains_key(region.id()) {
+                let view = RegionView::new(&display, region.clone(), logger.clone());
+                views.insert(region.id().clone(), view);
+            }
+        }
+
         // Update as needed.
-        if render_state.update(Arc::clone(&storage.terrain)).unwrap() {
-            v_buffer.write(render_state.vertices())
+        while let Ok((region_id, patch_pos)) = terrain_updates.try_recv() {
+            // New views load all their patches anyway.
+            if let Some(view) = views.get_mut(&region_id) {
+                view.land.patch_changed(patch_pos);
+            }
+            if let Some(ref mut minimap_view) = minimap_view {
+                if minimap_view.region_id == region_id {
+                    minimap_view.outdated = true;
+                }
+            }
+        }
+        let region = current_region(&storage);
+        if let Some(ref region) = region {
+            let eye = storage.client_avatar.read().eye_position();
+            for view in views.values_mut() {
+                let eye = Vector2::new(eye.x, eye.y) - view.region.offset_from(region);
+                view.update(&display, &storage, eye);
+            }
+        }
+
+        if cursor_moved {
+            cursor_hit = pick(region.as_ref(), cursor);
+            cursor_moved = false;
+        }
+
+        // Report the savings of the levels of detail, the brush and the
+        // position under the cursor.
+        if last_report.elapsed() >= Duration::from_secs(1) {
+            last_report = Instant::now();
+            let drawn: usize = views.values().map(|v| v.land.triangle_count()).sum();
+            let full: usize = views.values().map(|v| v.land.full_triangle_count()).sum();
+            statistics = format!(
+                "Terrain: {} patches drawn, {} triangles loaded ({} at full detail)",
+                patches_drawn, drawn, full
+            );
+        }
+        let cursor_text = match cursor_hit {
+            Some(ref hit) => format!(
+                "Cursor: ({:.1}, {:.1}, {:.1}) in region {}",
+                hit.rel_pos.x, hit.rel_pos.y, hit.rel_pos.z, hit.region_id
+            ),
+            None => "Cursor: no terrain".to_string(),
+        };
+        let new_title = format!("{} | {} | {}", statistics, terraform_tool.describe(), cursor_text);
+        if new_title != title {
+            display.gl_window().set_title(&new_title);
+            title = new_title;
         }
 
         // Draw the frame.
         // camera.update();
-        redraw(&storage.client_avatar);
+        if let Some(ref region) = region {
+            if water_view.is_none() {
+                water_view = Some(WaterView::new(&display, region, &connected));
+            }
+            let rebuild_weather = match weather_view {
+                Some(ref weather_view) => weather_view.is_outdated(),
+                None => show_weather,
+            };
+            if rebuild_weather {
+                weather_view = Some(WeatherView::new(&display, &storage, region, &connected));
+            }
+            let rebuild_minimap = match minimap_view {
+                Some(ref minimap_view) => minimap_view.needs_update(region),
+                None => true,
+            };
+            if rebuild_minimap {
+                // The vertices only depend on the size of the window.
+                let overlay = minimap_view.take().and_then(|view| view.overlay);
+                let mut view = MinimapView::new(&display, &storage, region, &mut minimap_failures);
+                view.overlay = overlay;
+                minimap_view = Some(view);
+            }
+            if let Some(ref mut minimap_view) = minimap_view {
+                minimap_view.update_overlay(&display);
+            }
+            if let Some(ref mut water_view) = water_view {
+                water_view.update(&display, region, &connected);
+                patches_drawn = redraw(
+                    &storage.client_avatar,
+                    region,
+                    &views,
+                    water_view,
+                    weather_view.as_ref(),
+                    minimap_view.as_ref(),
+                );
+            }
+        }
 
         // Handle events.
         let mut exit = false;
         events_loop.poll_events(|event| match event {
             glutin::Event::WindowEvent { event, .. } => match event {
                 glutin::WindowEvent::Closed => exit = true,
+                glutin::WindowEvent::CursorMoved { position, .. } => {
+                    cursor = Some(position);
+                    cursor_moved = true;
+                }
+                glutin::WindowEvent::CursorLeft { .. } => {
+                    cursor = None;
+                    cursor_hit = None;
+                }
                 glutin::WindowEvent::KeyboardInput { input, .. } => {
                     let pressed = input.state == glutin::ElementState::Pressed;
                     match input.virtual_keycode {
                         Some(glutin::VirtualKeyCode::Escape) => {exit = true;}
-                        Some(key) => {storage.client_avatar.write().handle_key(key, pressed);}
+                        Some(glutin::VirtualKeyCode::F10) => {
+                            if pressed {
+                                save_map_tile(&storage, &logger);
+                            }
+                        }
+                        Some(glutin::VirtualKeyCode::F11) => {
+                            if pressed {
+                                show_weather = !show_weather;
+                                if !show_weather {
+                                    weather_view = None;
+                                }
+                            }
+                        }
+                        Some(glutin::VirtualKeyCode::F12) => {
+                            if pressed {
+                                export_terrain(&storage, &logger);
+                            }
+                        }
+                        Some(key) => {
+                            // The avatar might have moved since the cursor did.
+                            if key == glutin::VirtualKeyCode::T && pressed {
+                                cursor_hit = pick(region.as_ref(), cursor);
+                            }
+                            let handled = terraform_tool.handle_key(
+                                &storage,
+                                cursor_hit.as_ref(),
+                                key,
+                                pressed,
+                            );
+                            if !handled {
+                                storage.client_avatar.write().handle_key(key, pressed);
+                            }
+                        }
                         _ => {}
                     }
                 }
@@ -249,11 +2216,16 @@ pub fn render_world(storage: Storage) {
         previous_clock = now;
 
         let fixed_time_step = Duration::new(0, 16666667);
+        let dt = fixed_time_step.subsec_nanos() as f32 * 1e-9;
         while accumulator >= fixed_time_step {
             accumulator -= fixed_time_step;
 
             // Update world state.
-            storage.client_avatar.write().update();
+            if let Some(ref region) = region {
+                storage.client_avatar.write().update(dt, |x, y| {
+                    storage.terrain.height_at(region, x, y).ok()
+                });
+            }
         }
 
         thread::sleep(fixed_time_step - accumulator);